@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Write};
+use std::sync::Arc;
+use datasource::AlertRule;
+use process::{Consumer, ConsumerConfig};
+use output::{Cell, Table, Report, ReportOptions, TimeFormat};
+
+/// The exit status when an alert rule is breached
+pub const BREACHED_EXIT_STATUS: i32 = 1;
+/// The exit status when an alert rule can't be checked, e.g. because it names a missing table
+pub const INVALID_EXIT_STATUS: i32 = 2;
+
+/// A run of consecutive timeslices in which an alert rule's threshold was crossed
+#[derive(Debug,Clone,PartialEq)]
+pub struct Breach {
+    pub rule: String,
+    /// the table's keys other than the timeslice, e.g. `request_handler=php-script`, if it has any
+    pub series: String,
+    pub first: i64,
+    pub last: i64,
+    pub slices: usize,
+    /// the value furthest beyond the threshold
+    pub worst: f64,
+}
+
+fn breached(rule: &AlertRule, value: f64) -> bool {
+    rule.above.map_or(false, |above| value > above) || rule.below.map_or(false, |below| value < below)
+}
+
+/// How far the value is beyond whichever of the rule's thresholds it crossed
+fn excess(rule: &AlertRule, value: f64) -> f64 {
+    match (rule.above, rule.below) {
+        (Some(above), _) if value > above => value - above,
+        (_, Some(below)) if value < below => below - value,
+        _ => 0.0,
+    }
+}
+
+/// Whether `a` is further beyond the rule's thresholds than `b`
+fn worse(rule: &AlertRule, a: f64, b: f64) -> bool {
+    excess(rule, a) > excess(rule, b)
+}
+
+/// The rule's threshold, e.g. `server_error_rate > 0.02 for 3 timeslices`
+pub fn describe(rule: &AlertRule) -> String {
+    let mut conditions = Vec::new();
+    if let Some(above) = rule.above {
+        conditions.push(format!("{} > {}", rule.column, above));
+    }
+    if let Some(below) = rule.below {
+        conditions.push(format!("{} < {}", rule.column, below));
+    }
+    let mut text = format!("{} {}", rule.table, conditions.join(" or "));
+    if rule.consecutive.unwrap_or(1) > 1 {
+        text.push_str(&format!(" for {} timeslices", rule.consecutive.unwrap()));
+    }
+    text
+}
+
+fn end_run(run: Option<Breach>, consecutive: usize, breaches: &mut Vec<Breach>) {
+    if let Some(run) = run {
+        if run.slices >= consecutive {
+            breaches.push(run);
+        }
+    }
+}
+
+/// Find the runs of timeslices in which the rule's column crossed its threshold, for at least
+/// the rule's number of consecutive timeslices.  Tables with keys besides the timeslice are
+/// checked separately for each combination of the other keys.  A missing timeslice ends a run,
+/// as does an empty value.
+pub fn check(rule: &AlertRule, tables: &[Table], slice_seconds: i64) -> Result<Vec<Breach>, Error> {
+    if rule.above.is_none() && rule.below.is_none() {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("alert {:?} needs an above or below threshold", rule.name)));
+    }
+    let table = tables.iter().find(|t| t.name == rule.table )
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("alert {:?}: no table named {:?}", rule.name, rule.table)))?;
+    let ts_index = table.keys.iter().position(|k| k == "timeslice" )
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("alert {:?}: table {:?} has no timeslices", rule.name, rule.table)))?;
+    let column = table.columns.iter().position(|c| c == &rule.column )
+        .map(|i| i + table.keys.len() )
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("alert {:?}: table {:?} has no column {:?}", rule.name, rule.table, rule.column)))?;
+    let consecutive = rule.consecutive.unwrap_or(1);
+
+    // the rows for each combination of the other keys, in the order first seen
+    let times = TimeFormat::epoch();
+    let mut index = HashMap::new();
+    let mut series: Vec<(String, Vec<&Vec<Cell>>)> = Vec::new();
+    for row in table.rows.iter() {
+        let name = table.keys.iter().zip(row.iter()).enumerate()
+            .filter(|&(i, _)| i != ts_index )
+            .map(|(_, (k, c))| format!("{}={}", k, c.text(&times)) )
+            .collect::<Vec<String>>()
+            .join(" ");
+        let i = *index.entry(name.clone()).or_insert_with(|| {
+            series.push((name, Vec::new()));
+            series.len() - 1
+        });
+        series[i].1.push(row);
+    }
+
+    let mut breaches = Vec::new();
+    for (name, rows) in series {
+        let mut run: Option<Breach> = None;
+        for row in rows {
+            let ts = match row[ts_index] {
+                Cell::Time(ts) => ts,
+                _ => continue,
+            };
+            let value = match row[column].number() {
+                Some(v) if breached(rule, v) => Some(v),
+                _ => None,
+            };
+            let continues = run.as_ref().map_or(false, |r| r.last + slice_seconds == ts );
+            match value {
+                Some(v) if continues => {
+                    let r = run.as_mut().unwrap();
+                    r.last = ts;
+                    r.slices += 1;
+                    if worse(rule, v, r.worst) {
+                        r.worst = v;
+                    }
+                },
+                Some(v) => {
+                    end_run(run.take(), consecutive, &mut breaches);
+                    run = Some(Breach {
+                        rule: rule.name.clone(),
+                        series: name.clone(),
+                        first: ts,
+                        last: ts,
+                        slices: 1,
+                        worst: v,
+                    });
+                },
+                None => end_run(run.take(), consecutive, &mut breaches),
+            }
+        }
+        end_run(run, consecutive, &mut breaches);
+    }
+    Ok(breaches)
+}
+
+fn tables(consumer: &Consumer, options: &ReportOptions) -> Vec<Table> {
+    consumer.reports(options).into_iter().map(|report| match report {
+        Report::Table(t) => t,
+        Report::Histograms(h) => h.to_table(),
+    }).collect()
+}
+
+/// Check, before any results are in, that each rule has a threshold and names a table with
+/// timeslices that the run will produce, and one of its columns.  The columns of pivoted
+/// tables, and the tables for the classifier's named captures, depend on the data, so are
+/// accepted whatever they're called.
+pub fn validate(rules: &[AlertRule], config: Arc<ConsumerConfig>, options: &ReportOptions) -> Result<(), Error> {
+    let captures = config.classifier.capture_names();
+    let tables = tables(&Consumer::new(config), options);
+    for rule in rules {
+        let invalid = |message: String| Err(Error::new(ErrorKind::InvalidInput, format!("alert {:?}: {}", rule.name, message)));
+        if rule.above.is_none() && rule.below.is_none() {
+            return invalid("needs an above or below threshold".to_string());
+        }
+        match tables.iter().find(|t| t.name == rule.table ) {
+            Some(t) if !t.keys.iter().any(|k| k == "timeslice" ) => return invalid(format!("table {:?} has no timeslices", rule.table)),
+            Some(t) if t.pivot.is_none() && !t.columns.contains(&rule.column) => return invalid(format!("table {:?} has no column {:?}", rule.table, rule.column)),
+            Some(_) => {},
+            None if captures.iter().any(|c| format!("by_{}_timeslice", c) == rule.table ) => {},
+            None => return invalid(format!("no table named {:?}", rule.table)),
+        }
+    }
+    Ok(())
+}
+
+/// Check every rule against the tables the consumer would write out.  The rules should already
+/// have passed `validate()`, so a table missing now is one that only appears given some data,
+/// and a column missing from a pivoted table is a value that was never seen; neither breaches.
+pub fn check_all(rules: &[AlertRule], consumer: &Consumer, options: &ReportOptions) -> Result<Vec<Breach>, Error> {
+    let tables = tables(consumer, options);
+    let mut breaches = Vec::new();
+    for rule in rules {
+        let present = tables.iter().find(|t| t.name == rule.table )
+            .map_or(false, |t| t.pivot.is_none() || t.columns.contains(&rule.column) );
+        if present {
+            breaches.extend(check(rule, &tables, consumer.slice_seconds())?);
+        }
+    }
+    Ok(breaches)
+}
+
+/// A line for each breach, under the rule it breached
+pub fn write_report(rules: &[AlertRule], breaches: &[Breach], times: &TimeFormat, out: &mut Write) -> Result<(), Error> {
+    for rule in rules {
+        let breached = breaches.iter().filter(|b| b.rule == rule.name ).collect::<Vec<&Breach>>();
+        if breached.is_empty() {
+            continue;
+        }
+        writeln!(out, "ALERT {}: {}", rule.name, describe(rule))?;
+        for b in breached {
+            let series = if b.series.is_empty() { String::new() } else { format!("{}: ", b.series) };
+            writeln!(out, "  {}{} to {} ({} timeslices), worst {}", series, times.format(b.first), times.format(b.last), b.slices, b.worst)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use classify::Classifier;
+
+    fn rule(consecutive: Option<usize>) -> AlertRule {
+        AlertRule {
+            name: "errors".to_string(),
+            table: "error_rate_by_timeslice".to_string(),
+            column: "server_error_rate".to_string(),
+            above: Some(0.02),
+            below: None,
+            consecutive: consecutive,
+        }
+    }
+
+    fn table(rates: &[(i64, Cell)]) -> Table {
+        Table {
+            name: "error_rate_by_timeslice".to_string(),
+            keys: vec!["timeslice".to_string()],
+            columns: vec!["server_error_rate".to_string()],
+            pivot: None,
+            rows: rates.iter().map(|&(ts, ref rate)| vec![Cell::Time(ts), rate.clone()] ).collect(),
+        }
+    }
+
+    #[test]
+    fn consecutive_slices() {
+        let tables = vec![table(&[
+            (0, Cell::Float(0.05)),
+            (60, Cell::Float(0.03)),
+            (120, Cell::Float(0.01)),
+            (180, Cell::Float(0.04)),
+            (240, Cell::Float(0.06)),
+            (300, Cell::Float(0.03)),
+            // a gap ends the run
+            (420, Cell::Float(0.03)),
+        ])];
+        let breaches = check(&rule(Some(3)), &tables, 60).unwrap();
+        assert_eq!(vec![Breach {
+                       rule: "errors".to_string(),
+                       series: String::new(),
+                       first: 180,
+                       last: 300,
+                       slices: 3,
+                       worst: 0.06,
+                   }],
+                   breaches);
+        assert_eq!(3, check(&rule(None), &tables, 60).unwrap().len());
+    }
+
+    #[test]
+    fn above_and_below() {
+        let mut band = rule(None);
+        band.above = Some(0.5);
+        band.below = Some(0.1);
+        let tables = vec![table(&[(0, Cell::Float(0.55)), (60, Cell::Float(0.0)), (120, Cell::Float(0.3))])];
+        let breaches = check(&band, &tables, 60).unwrap();
+        assert_eq!(1, breaches.len());
+        assert_eq!(2, breaches[0].slices);
+        // 0.0 is further below 0.1 than 0.55 is above 0.5
+        assert_eq!(0.0, breaches[0].worst);
+    }
+
+    #[test]
+    fn validation() {
+        let config = Arc::new(ConsumerConfig {
+            classifier: Classifier::new(&[("Segment", r"-Frag(?P<fragment>\d+)")], "Other").unwrap(),
+            slice_seconds: 60,
+            query: None,
+            top: None,
+            unique: None,
+            breakdown: None,
+        });
+        let options = ReportOptions {
+            zero_fill: false,
+            slice_end: false,
+            percentiles: vec![99.0],
+            buckets: None,
+            status_classes: false,
+            anomalies: None,
+        };
+        let on = |table: &str, column: &str| {
+            let mut r = rule(None);
+            r.table = table.to_string();
+            r.column = column.to_string();
+            r
+        };
+        assert!(validate(&[rule(None)], config.clone(), &options).is_ok());
+        // statuses and capture values only become columns once they're seen
+        assert!(validate(&[on("by_status_timeslice", "503")], config.clone(), &options).is_ok());
+        assert!(validate(&[on("by_fragment_timeslice", "7")], config.clone(), &options).is_ok());
+        assert!(validate(&[on("servicetime_percentiles_by_timeslice", "p95")], config.clone(), &options).is_err());
+        assert!(validate(&[on("nonsense", "count")], config.clone(), &options).is_err());
+        // nothing was seen, so there's no breach rather than an error
+        let empty = Consumer::new(config);
+        assert!(check_all(&[on("by_status_timeslice", "503")], &empty, &options).unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_rules() {
+        let tables = vec![table(&[(0, Cell::Empty)])];
+        assert!(check(&rule(None), &tables, 60).unwrap().is_empty());
+        let mut missing = rule(None);
+        missing.column = "nonsense".to_string();
+        assert!(check(&missing, &tables, 60).is_err());
+        missing = rule(None);
+        missing.above = None;
+        assert!(check(&missing, &tables, 60).is_err());
+    }
+}
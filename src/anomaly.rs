@@ -0,0 +1,217 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use output::{Cell, Table};
+
+/// What each timeslice's value is compared with
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Baseline {
+    /// the timeslices just before it
+    Rolling,
+    /// the same time of day on the days before
+    Daily,
+}
+
+/// Settings for `--anomalies`
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct AnomalyConfig {
+    pub baseline: Baseline,
+    /// how many earlier values to compare with; timeslices for `Rolling`, days for `Daily`
+    pub window: usize,
+    /// the modified z-score beyond which a value is unusual
+    pub threshold: f64,
+    /// the least change from the baseline, as a fraction of it, that can be unusual, so that
+    /// values which barely vary don't make every small change stand out
+    pub min_change: f64,
+}
+
+pub const DEFAULT_ROLLING_WINDOW: usize = 12;
+pub const DEFAULT_DAILY_WINDOW: usize = 7;
+/// A modified z-score above 3.5 is the usual cut-off for an outlier (Iglewicz and Hoaglin)
+pub const DEFAULT_THRESHOLD: f64 = 3.5;
+pub const DEFAULT_MIN_CHANGE: f64 = 0.1;
+/// No value is judged against fewer earlier values than this
+const MIN_REFERENCES: usize = 3;
+const DAY_SECONDS: i64 = 86400;
+
+impl AnomalyConfig {
+    /// Parse the `--anomalies` baseline (`rolling` or `daily`), `--anomaly-window`,
+    /// `--anomaly-threshold` and `--anomaly-min-change`
+    pub fn parse(baseline: &str, window: Option<&str>, threshold: Option<&str>, min_change: Option<&str>) -> Result<AnomalyConfig, String> {
+        let baseline = match baseline {
+            "rolling" => Baseline::Rolling,
+            "daily" => Baseline::Daily,
+            other => return Err(format!("unknown baseline {:?}, expected rolling or daily", other)),
+        };
+        let window = match window {
+            Some(w) => w.parse::<usize>().map_err(|e| format!("bad window {:?}: {}", w, e))?,
+            None if baseline == Baseline::Rolling => DEFAULT_ROLLING_WINDOW,
+            None => DEFAULT_DAILY_WINDOW,
+        };
+        if window < MIN_REFERENCES {
+            return Err(format!("the window must be at least {}", MIN_REFERENCES));
+        }
+        let threshold = match threshold {
+            Some(t) => t.parse::<f64>().map_err(|e| format!("bad threshold {:?}: {}", t, e))?,
+            None => DEFAULT_THRESHOLD,
+        };
+        let min_change = match min_change {
+            Some(m) => m.parse::<f64>().map_err(|e| format!("bad minimum change {:?}: {}", m, e))?,
+            None => DEFAULT_MIN_CHANGE,
+        };
+        if !(min_change >= 0.0) {
+            return Err("the minimum change must not be negative".to_string());
+        }
+        Ok(AnomalyConfig {
+            baseline: baseline,
+            window: window,
+            threshold: threshold,
+            min_change: min_change,
+        })
+    }
+}
+
+/// A timeslice whose value was far from those it was compared with
+#[derive(Debug,Clone,PartialEq)]
+pub struct Anomaly {
+    pub timeslice: i64,
+    pub metric: String,
+    pub value: f64,
+    /// the median of the earlier values
+    pub baseline: f64,
+    /// the median absolute deviation of the earlier values
+    pub mad: f64,
+    /// the modified z-score, or `None` when the earlier values were all the same, so any change
+    /// of at least the minimum is unusual
+    pub score: Option<f64>,
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal) );
+    let mid = values.len() / 2;
+    if values.len() % 2 == 0 { (values[mid - 1] + values[mid]) / 2.0 } else { values[mid] }
+}
+
+/// Compare a value with the earlier ones, giving `Some` if it's unusual
+fn judge(config: &AnomalyConfig, value: f64, mut references: Vec<f64>) -> Option<(f64, f64, Option<f64>)> {
+    if references.len() < MIN_REFERENCES {
+        return None;
+    }
+    let baseline = median(&mut references);
+    let mut deviations = references.iter().map(|r| (r - baseline).abs() ).collect::<Vec<f64>>();
+    let mad = median(&mut deviations);
+    if (value - baseline).abs() < config.min_change * baseline.abs() {
+        None
+    } else if mad > 0.0 {
+        let score = 0.6745 * (value - baseline) / mad;
+        if score.abs() > config.threshold { Some((baseline, mad, Some(score))) } else { None }
+    } else if value != baseline {
+        Some((baseline, mad, None))
+    } else {
+        None
+    }
+}
+
+/// Find the unusual values in a series of `(timeslice, value)`, in order of timeslice
+pub fn detect(config: &AnomalyConfig, metric: &str, series: &[(i64, f64)]) -> Vec<Anomaly> {
+    let by_timeslice = series.iter().cloned().collect::<HashMap<i64, f64>>();
+    series.iter().enumerate().filter_map(|(i, &(ts, value))| {
+        let references = match config.baseline {
+            Baseline::Rolling => series[i.saturating_sub(config.window)..i].iter().map(|&(_, v)| v ).collect(),
+            Baseline::Daily => (1..config.window as i64 + 1).filter_map(|days| by_timeslice.get(&(ts - days * DAY_SECONDS)).cloned() ).collect(),
+        };
+        judge(config, value, references).map(|(baseline, mad, score)| Anomaly {
+            timeslice: ts,
+            metric: metric.to_string(),
+            value: value,
+            baseline: baseline,
+            mad: mad,
+            score: score,
+        })
+    }).collect()
+}
+
+/// The non-empty, finite values of a column of a table keyed only by timeslice
+pub fn column_series(table: &Table, column: &str) -> Vec<(i64, f64)> {
+    let index = match table.columns.iter().position(|c| c == column ) {
+        Some(i) => i + table.keys.len(),
+        None => return Vec::new(),
+    };
+    table.rows.iter().filter_map(|row| match (&row[0], row[index].number()) {
+        (&Cell::Time(ts), Some(v)) if v.is_finite() => Some((ts, v)),
+        _ => None,
+    }).collect()
+}
+
+pub fn table(anomalies: &[Anomaly]) -> Table {
+    let mut anomalies = anomalies.iter().collect::<Vec<&Anomaly>>();
+    anomalies.sort_by(|a, b| (a.timeslice, &a.metric).cmp(&(b.timeslice, &b.metric)) );
+    let rows = anomalies.into_iter().map(|a| vec![
+        Cell::Time(a.timeslice),
+        Cell::Str(a.metric.clone()),
+        Cell::Float(a.value),
+        Cell::Float(a.baseline),
+        Cell::Float(a.mad),
+        a.score.map(Cell::Float).unwrap_or(Cell::Empty),
+    ]).collect();
+    Table {
+        name: "anomalies".to_string(),
+        keys: vec!["timeslice".to_string(), "metric".to_string()],
+        columns: vec!["value", "baseline", "mad", "score"].into_iter().map(|c| c.to_string() ).collect(),
+        pivot: None,
+        rows: rows,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling_spike() {
+        let config = AnomalyConfig::parse("rolling", Some("6"), None, None).unwrap();
+        let values = [100.0, 104.0, 98.0, 101.0, 97.0, 103.0, 250.0, 99.0];
+        let series = values.iter().enumerate().map(|(i, v)| (i as i64 * 60, *v) ).collect::<Vec<(i64, f64)>>();
+        let anomalies = detect(&config, "requests", &series);
+        assert_eq!(1, anomalies.len());
+        assert_eq!(360, anomalies[0].timeslice);
+        assert_eq!(100.5, anomalies[0].baseline);
+    }
+
+    #[test]
+    fn daily_baseline() {
+        let config = AnomalyConfig::parse("daily", None, None, None).unwrap();
+        // a rate that's high at the same time every day is normal; one that was always zero isn't
+        let mut series = Vec::new();
+        for day in 0..5 {
+            series.push((day * DAY_SECONDS, if day == 4 { 0.05 } else { 0.0 }));
+            series.push((day * DAY_SECONDS + 3600, 0.2));
+        }
+        let anomalies = detect(&config, "server_error_rate", &series);
+        assert_eq!(1, anomalies.len());
+        assert_eq!(4 * DAY_SECONDS, anomalies[0].timeslice);
+        assert_eq!(None, anomalies[0].score);
+        assert!(AnomalyConfig::parse("weekly", None, None, None).is_err());
+    }
+
+    #[test]
+    fn minimum_change() {
+        // a count that never varied only stands out when it changes by a tenth or more
+        let config = AnomalyConfig::parse("rolling", Some("4"), None, None).unwrap();
+        let series = vec![(0, 100.0), (60, 100.0), (120, 100.0), (180, 100.0), (240, 105.0), (300, 120.0)];
+        let anomalies = detect(&config, "requests", &series);
+        assert_eq!(vec![300], anomalies.iter().map(|a| a.timeslice ).collect::<Vec<i64>>());
+        assert!(AnomalyConfig::parse("rolling", None, None, Some("-1")).is_err());
+    }
+
+    #[test]
+    fn not_a_number() {
+        let table = Table {
+            name: "error_rate_by_timeslice".to_string(),
+            keys: vec!["timeslice".to_string()],
+            columns: vec!["server_error_rate".to_string()],
+            pivot: None,
+            rows: vec![vec![Cell::Time(0), Cell::Float(0.5)], vec![Cell::Time(60), Cell::Float(::std::f64::NAN)], vec![Cell::Time(120), Cell::Empty]],
+        };
+        assert_eq!(vec![(0, 0.5)], column_series(&table, "server_error_rate"));
+    }
+}
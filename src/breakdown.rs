@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Error;
+use hdrsample::Histogram;
+use parse_access_log::Record;
+use query::RecordField;
+use output::{Cell, Table};
+use snapshot::{SnapshotWriter, SnapshotReader};
+use process::{SliceResult, rescale};
+
+/// The fields to give counts, statuses and service times for, e.g. `request_handler`
+#[derive(Debug,Clone)]
+pub struct BreakdownConfig {
+    pub fields: Vec<RecordField>,
+}
+
+impl BreakdownConfig {
+    /// Parse a comma-separated list of `--breakdown` fields
+    pub fn parse(fields: &str) -> Result<BreakdownConfig, String> {
+        let fields = fields.split(',')
+            .map(|name| RecordField::parse(name.trim()).ok_or_else(|| format!("unknown field {:?}", name) ) )
+            .collect::<Result<Vec<RecordField>, String>>()?;
+        Ok(BreakdownConfig {
+            fields: fields,
+        })
+    }
+}
+
+impl fmt::Display for BreakdownConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fields = self.fields.iter().map(|field| field.name() ).collect::<Vec<&str>>();
+        write!(f, "--breakdown {}", fields.join(","))
+    }
+}
+
+/// The label used for requests with an empty value, such as those handled by no handler
+const NO_VALUE: &'static str = "-";
+
+#[derive(Debug,Clone,Hash,Eq,PartialEq)]
+struct KeyValueTimeslice {
+    timeslice: i64,
+    value: String,
+}
+
+#[derive(Debug,Clone,Hash,Eq,PartialEq)]
+struct KeyValueStatusTimeslice {
+    timeslice: i64,
+    value: String,
+    http_status: String,
+}
+
+/// Everything counted for one of the breakdown fields
+struct Dimension {
+    /// the service times of requests with each value; each histogram's count is the number
+    /// of requests
+    servicetime: HashMap<KeyValueTimeslice, Histogram<u64>>,
+    by_status: HashMap<KeyValueStatusTimeslice, u64>,
+    values: HashSet<String>,
+}
+
+impl Dimension {
+    fn new() -> Dimension {
+        Dimension {
+            servicetime: HashMap::new(),
+            by_status: HashMap::new(),
+            values: HashSet::new(),
+        }
+    }
+
+    fn record(&mut self, value: &str, r: &Record, slice: i64) {
+        let key = KeyValueTimeslice {
+            timeslice: slice,
+            value: value.to_string(),
+        };
+        let _ = self.servicetime.entry(key).or_insert_with(|| Histogram::new(1).unwrap()).record(r.response_time_micros);
+        let key = KeyValueStatusTimeslice {
+            timeslice: slice,
+            value: value.to_string(),
+            http_status: r.response_status.clone(),
+        };
+        *self.by_status.entry(key).or_insert(0) += 1;
+        if !self.values.contains(value) {
+            self.values.insert(value.to_string());
+        }
+    }
+
+    fn absorb(&mut self, other: &Dimension, slice_seconds: i64, keep: &Fn(i64) -> bool) {
+        for (k, hist) in other.servicetime.iter().filter(|&(k, _)| keep(k.timeslice) ) {
+            if !self.values.contains(&k.value) {
+                self.values.insert(k.value.clone());
+            }
+            let key = KeyValueTimeslice {
+                timeslice: rescale(k.timeslice, slice_seconds),
+                value: k.value.clone(),
+            };
+            let _ = self.servicetime.entry(key).or_insert_with(|| Histogram::new(1).unwrap()).add(hist);
+        }
+        for (k, v) in other.by_status.iter().filter(|&(k, _)| keep(k.timeslice) ) {
+            let key = KeyValueStatusTimeslice {
+                timeslice: rescale(k.timeslice, slice_seconds),
+                value: k.value.clone(),
+                http_status: k.http_status.clone(),
+            };
+            *self.by_status.entry(key).or_insert(0) += *v;
+        }
+    }
+
+    fn write_snapshot(&self, w: &mut SnapshotWriter) -> Result<(), Error> {
+        w.write_u64(self.servicetime.len() as u64)?;
+        for (k, hist) in self.servicetime.iter() {
+            w.write_i64(k.timeslice)?;
+            w.write_str(&k.value)?;
+            w.write_histogram(hist)?;
+        }
+        w.write_u64(self.by_status.len() as u64)?;
+        for (k, v) in self.by_status.iter() {
+            w.write_i64(k.timeslice)?;
+            w.write_str(&k.value)?;
+            w.write_str(&k.http_status)?;
+            w.write_u64(*v)?;
+        }
+        Ok(())
+    }
+
+    fn read_snapshot(r: &mut SnapshotReader) -> Result<Dimension, Error> {
+        let mut d = Dimension::new();
+        for _ in 0..r.read_len()? {
+            let key = KeyValueTimeslice {
+                timeslice: r.read_i64()?,
+                value: r.read_string()?,
+            };
+            d.values.insert(key.value.clone());
+            d.servicetime.insert(key, r.read_histogram()?);
+        }
+        for _ in 0..r.read_len()? {
+            let key = KeyValueStatusTimeslice {
+                timeslice: r.read_i64()?,
+                value: r.read_string()?,
+                http_status: r.read_string()?,
+            };
+            d.by_status.insert(key, r.read_u64()?);
+        }
+        Ok(d)
+    }
+
+    /// The number of requests with each value in each of the given timeslices
+    fn count_table(&self, name: &str, timeslices: &[i64]) -> Table {
+        let mut cols = self.values.iter().collect::<Vec<&String>>();
+        cols.sort();
+        let rows = timeslices.iter().map(|ts| {
+            let mut row = vec![Cell::Time(*ts)];
+            for c in &cols {
+                let key = KeyValueTimeslice {
+                    timeslice: *ts,
+                    value: (*c).clone(),
+                };
+                row.push(Cell::Int(self.servicetime.get(&key).map(|h| h.count() ).unwrap_or(0) as i64));
+            }
+            row
+        }).collect();
+        Table {
+            name: format!("by_{}_timeslice", name),
+            keys: vec!["timeslice".to_string()],
+            columns: cols.into_iter().cloned().collect(),
+            pivot: Some(name.to_string()),
+            rows: rows,
+        }
+    }
+
+    /// The number of requests with each value and status in each timeslice
+    fn status_table(&self, name: &str) -> Table {
+        let mut keys = self.by_status.keys().collect::<Vec<&KeyValueStatusTimeslice>>();
+        keys.sort_by(|a, b| (a.timeslice, &a.value, &a.http_status).cmp(&(b.timeslice, &b.value, &b.http_status)) );
+        let rows = keys.into_iter().map(|k| {
+            vec![Cell::Time(k.timeslice), Cell::Str(k.value.clone()), Cell::Str(k.http_status.clone()), Cell::Int(self.by_status[k] as i64)]
+        }).collect();
+        Table {
+            name: format!("by_{}_status_timeslice", name),
+            keys: vec!["timeslice".to_string(), name.to_string(), "http_status".to_string()],
+            columns: vec!["count".to_string()],
+            pivot: None,
+            rows: rows,
+        }
+    }
+
+    /// The chosen percentiles of the service time for each value in each timeslice, along with
+    /// the maximum, mean and count
+    fn servicetime_table(&self, name: &str, percentiles: &[f64]) -> Table {
+        let mut columns = percentiles.iter().map(|p| format!("p{}", p) ).collect::<Vec<String>>();
+        columns.push("max".to_string());
+        columns.push("mean".to_string());
+        columns.push("count".to_string());
+        let mut keys = self.servicetime.keys().collect::<Vec<&KeyValueTimeslice>>();
+        keys.sort_by(|a, b| (a.timeslice, &a.value).cmp(&(b.timeslice, &b.value)) );
+        let rows = keys.into_iter().map(|k| {
+            let hist = &self.servicetime[k];
+            let mut row = vec![Cell::Time(k.timeslice), Cell::Str(k.value.clone())];
+            row.extend(percentiles.iter().map(|p| Cell::Int(hist.value_at_percentile(*p) as i64) ));
+            row.push(Cell::Int(hist.max() as i64));
+            row.push(Cell::Float(hist.mean()));
+            row.push(Cell::Int(hist.count() as i64));
+            row
+        }).collect();
+        Table {
+            name: format!("servicetime_percentiles_by_{}_timeslice", name),
+            keys: vec!["timeslice".to_string(), name.to_string()],
+            columns: columns,
+            pivot: None,
+            rows: rows,
+        }
+    }
+}
+
+/// Counts, statuses and service times for each value of each of the `--breakdown` fields,
+/// per timeslice
+pub struct BreakdownResult {
+    /// in the same order as the config's fields
+    dimensions: Vec<Dimension>,
+}
+
+impl BreakdownResult {
+    pub fn new() -> BreakdownResult {
+        BreakdownResult {
+            dimensions: Vec::new(),
+        }
+    }
+
+    /// Make sure there's a dimension for each of the config's fields
+    fn fill(&mut self, config: &BreakdownConfig) {
+        while self.dimensions.len() < config.fields.len() {
+            self.dimensions.push(Dimension::new());
+        }
+    }
+
+    pub fn record(&mut self, config: &BreakdownConfig, r: &Record, slice: i64) {
+        self.fill(config);
+        for (field, dimension) in config.fields.iter().zip(self.dimensions.iter_mut()) {
+            let value = field.value(r).to_string();
+            dimension.record(if value.is_empty() { NO_VALUE } else { &value[..] }, r, slice);
+        }
+    }
+
+    /// For each field, tables of the requests per value in each of the given timeslices, of
+    /// requests per value and status, and of service time percentiles per value
+    pub fn tables(&self, config: &BreakdownConfig, timeslices: &[i64], percentiles: &[f64]) -> Vec<Table> {
+        let mut tables = Vec::new();
+        for (field, dimension) in config.fields.iter().zip(self.dimensions.iter()) {
+            tables.push(dimension.count_table(field.name(), timeslices));
+            tables.push(dimension.status_table(field.name()));
+            tables.push(dimension.servicetime_table(field.name(), percentiles));
+        }
+        tables
+    }
+}
+
+impl SliceResult for BreakdownResult {
+    type Config = BreakdownConfig;
+
+    fn absorb(&mut self, config: &BreakdownConfig, other: &BreakdownResult, slice_seconds: i64, keep: &Fn(i64) -> bool) {
+        self.fill(config);
+        for (dimension, other) in self.dimensions.iter_mut().zip(other.dimensions.iter()) {
+            dimension.absorb(other, slice_seconds, keep);
+        }
+    }
+
+    fn write_snapshot(&self, w: &mut SnapshotWriter) -> Result<(), Error> {
+        w.write_u64(self.dimensions.len() as u64)?;
+        for dimension in self.dimensions.iter() {
+            dimension.write_snapshot(w)?;
+        }
+        Ok(())
+    }
+
+    fn read_snapshot(r: &mut SnapshotReader) -> Result<BreakdownResult, Error> {
+        let mut result = BreakdownResult::new();
+        for _ in 0..r.read_len()? {
+            result.dimensions.push(Dimension::read_snapshot(r)?);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merged_breakdowns() {
+        let config = BreakdownConfig::parse("request_handler, request_local_port").unwrap();
+        let mut a = BreakdownResult::new();
+        a.record(&config, &Record { request_handler: "php-script".to_string(), request_local_port: 443, response_status: "200".to_string(), response_time_micros: 1000, ..Record::default() }, 0);
+        a.record(&config, &Record { request_local_port: 80, response_status: "301".to_string(), response_time_micros: 10, ..Record::default() }, 0);
+        let mut b = BreakdownResult::new();
+        b.record(&config, &Record { request_handler: "php-script".to_string(), request_local_port: 443, response_status: "500".to_string(), response_time_micros: 3000, ..Record::default() }, 60);
+        let mut total = BreakdownResult::new();
+        total.absorb(&config, &a, 120, &|_| true );
+        total.absorb(&config, &b, 120, &|_| true );
+        let tables = total.tables(&config, &[0], &[50.0]);
+        assert_eq!(6, tables.len());
+        assert_eq!("by_request_handler_timeslice", tables[0].name);
+        assert_eq!(vec!["-", "php-script"], tables[0].columns);
+        assert_eq!(vec![vec![Cell::Time(0), Cell::Int(1), Cell::Int(2)]], tables[0].rows);
+        assert_eq!(vec![
+                       vec![Cell::Time(0), Cell::Str("-".to_string()), Cell::Str("301".to_string()), Cell::Int(1)],
+                       vec![Cell::Time(0), Cell::Str("php-script".to_string()), Cell::Str("200".to_string()), Cell::Int(1)],
+                       vec![Cell::Time(0), Cell::Str("php-script".to_string()), Cell::Str("500".to_string()), Cell::Int(1)],
+                   ],
+                   tables[1].rows);
+        assert_eq!("servicetime_percentiles_by_request_local_port_timeslice", tables[5].name);
+        // values are sorted as text
+        assert_eq!(vec![Cell::Time(0), Cell::Str("443".to_string())], &tables[5].rows[0][..2]);
+        assert_eq!(Cell::Int(2), tables[5].rows[0][5]);
+    }
+}
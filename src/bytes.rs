@@ -0,0 +1,254 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::io::Error;
+use hdrsample::Histogram;
+use parse_access_log::Record;
+use output::{Cell, Table, percentile_table};
+use snapshot::{SnapshotWriter, SnapshotReader, invalid_data};
+use process::{SliceResult, rescale};
+
+/// The bytes sent in response to a number of requests
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+struct ByteStats {
+    requests: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl ByteStats {
+    fn add(&mut self, bytes: u64) {
+        self.requests += 1;
+        self.sum += bytes;
+        self.max = cmp::max(self.max, bytes);
+    }
+
+    fn merge(&mut self, other: &ByteStats) {
+        self.requests += other.requests;
+        self.sum += other.sum;
+        self.max = cmp::max(self.max, other.max);
+    }
+
+    /// The row values for a table of these stats, given the width of the timeslice
+    fn cells(&self, slice_seconds: i64) -> Vec<Cell> {
+        vec![
+            Cell::Int(self.requests as i64),
+            Cell::Int(self.sum as i64),
+            if self.requests > 0 { Cell::Float(self.sum as f64 / self.requests as f64) } else { Cell::Empty },
+            Cell::Int(self.max as i64),
+            Cell::Float(self.sum as f64 / slice_seconds as f64),
+        ]
+    }
+}
+
+/// What the bytes served in each timeslice are broken down by
+#[derive(Debug,Clone,Copy,Hash,Eq,PartialEq)]
+enum Breakdown {
+    /// no breakdown; all requests together
+    All,
+    Uritype,
+    Status,
+    CacheStatus,
+}
+
+/// Each breakdown, with its table name and the name of the key it breaks down by
+const BREAKDOWNS: &'static [(Breakdown, &'static str, &'static str)] = &[
+    (Breakdown::All, "bytes_by_timeslice", ""),
+    (Breakdown::Uritype, "bytes_by_uritype_timeslice", "uritype"),
+    (Breakdown::Status, "bytes_by_status_timeslice", "http_status"),
+    (Breakdown::CacheStatus, "bytes_by_cache_status_timeslice", "response_cache_status"),
+];
+
+#[derive(Debug,Clone,Hash,Eq,PartialEq)]
+struct KeyBytesTimeslice {
+    timeslice: i64,
+    breakdown: Breakdown,
+    value: String,
+}
+
+/// Bytes served per timeslice, along with the distribution of per-request transfer rates
+pub struct BytesResult {
+    stats: HashMap<KeyBytesTimeslice, ByteStats>,
+    /// bytes per second for each request, from `response_bytes / response_time_micros`
+    rate_by_timeslice: HashMap<i64, Histogram<u64>>,
+}
+
+impl BytesResult {
+    pub fn new() -> BytesResult {
+        BytesResult {
+            stats: HashMap::new(),
+            rate_by_timeslice: HashMap::new(),
+        }
+    }
+
+    /// Add a record, where `slice` and `uritype` are the values the consumer has already worked
+    /// out for it.  A response with no `response_bytes` (logged as `-`) counts as empty.
+    pub fn record(&mut self, r: &Record, slice: i64, uritype: &str) {
+        let bytes = r.response_bytes.unwrap_or(0) as u64;
+        for &(breakdown, _, _) in BREAKDOWNS {
+            let value = match breakdown {
+                Breakdown::All => "",
+                Breakdown::Uritype => uritype,
+                Breakdown::Status => &r.response_status,
+                Breakdown::CacheStatus => &r.response_cache_status,
+            };
+            let key = KeyBytesTimeslice {
+                timeslice: slice,
+                breakdown: breakdown,
+                value: value.to_string(),
+            };
+            self.stats.entry(key).or_insert_with(ByteStats::default).add(bytes);
+        }
+        // a rate can't be worked out for instant or empty responses
+        if bytes > 0 && r.response_time_micros > 0 {
+            let rate = bytes * 1000000 / r.response_time_micros;
+            let _ = self.rate_by_timeslice.entry(slice).or_insert_with(|| Histogram::new(2).unwrap()).record(rate);
+        }
+    }
+
+    /// A table of the bytes served in each of the given timeslices, and one for each breakdown,
+    /// giving the request count, total, mean and maximum bytes, and throughput in bytes per
+    /// second.  Only the overall table is zero-filled, as for the query table.
+    pub fn tables(&self, timeslices: &[i64], slice_seconds: i64) -> Vec<Table> {
+        let columns = vec!["requests", "bytes", "mean_bytes", "max_bytes", "bytes_per_second"].into_iter()
+            .map(|c| c.to_string() )
+            .collect::<Vec<String>>();
+        let mut tables = Vec::new();
+        for &(breakdown, name, key_name) in BREAKDOWNS {
+            let (keys, rows) = if breakdown == Breakdown::All {
+                let rows = timeslices.iter().map(|ts| {
+                    let key = KeyBytesTimeslice {
+                        timeslice: *ts,
+                        breakdown: breakdown,
+                        value: String::new(),
+                    };
+                    let mut row = vec![Cell::Time(*ts)];
+                    row.extend(self.stats.get(&key).cloned().unwrap_or_default().cells(slice_seconds));
+                    row
+                }).collect();
+                (vec!["timeslice".to_string()], rows)
+            } else {
+                let mut keys = self.stats.keys().filter(|k| k.breakdown == breakdown ).collect::<Vec<&KeyBytesTimeslice>>();
+                keys.sort_by(|a, b| (a.timeslice, &a.value).cmp(&(b.timeslice, &b.value)) );
+                let rows = keys.into_iter().map(|k| {
+                    let mut row = vec![Cell::Time(k.timeslice), Cell::Str(k.value.clone())];
+                    row.extend(self.stats[k].cells(slice_seconds));
+                    row
+                }).collect();
+                (vec!["timeslice".to_string(), key_name.to_string()], rows)
+            };
+            tables.push(Table {
+                name: name.to_string(),
+                keys: keys,
+                columns: columns.clone(),
+                pivot: None,
+                rows: rows,
+            });
+        }
+        tables
+    }
+
+    /// The chosen percentiles of the per-request transfer rate, in bytes per second, in each of
+    /// the given timeslices
+    pub fn rate_table(&self, timeslices: &[i64], percentiles: &[f64]) -> Table {
+        let slices = timeslices.iter()
+            .map(|ts| (*ts, self.rate_by_timeslice.get(ts)) )
+            .collect::<Vec<(i64, Option<&Histogram<u64>>)>>();
+        percentile_table("transfer_rate_percentiles_by_timeslice", &slices, percentiles)
+    }
+}
+
+impl SliceResult for BytesResult {
+    type Config = ();
+
+    fn absorb(&mut self, _: &(), other: &BytesResult, slice_seconds: i64, keep: &Fn(i64) -> bool) {
+        for (k, v) in other.stats.iter().filter(|&(k, _)| keep(k.timeslice) ) {
+            let key = KeyBytesTimeslice {
+                timeslice: rescale(k.timeslice, slice_seconds),
+                breakdown: k.breakdown,
+                value: k.value.clone(),
+            };
+            self.stats.entry(key).or_insert_with(ByteStats::default).merge(v);
+        }
+        for (ts, hist) in other.rate_by_timeslice.iter().filter(|&(ts, _)| keep(*ts) ) {
+            let slice = rescale(ts, slice_seconds);
+            let _ = self.rate_by_timeslice.entry(slice).or_insert_with(|| Histogram::new(2).unwrap()).add(hist);
+        }
+    }
+
+    fn write_snapshot(&self, w: &mut SnapshotWriter) -> Result<(), Error> {
+        w.write_u64(self.stats.len() as u64)?;
+        for (k, v) in self.stats.iter() {
+            w.write_i64(k.timeslice)?;
+            w.write_u64(BREAKDOWNS.iter().position(|&(b, _, _)| b == k.breakdown ).unwrap() as u64)?;
+            w.write_str(&k.value)?;
+            w.write_u64(v.requests)?;
+            w.write_u64(v.sum)?;
+            w.write_u64(v.max)?;
+        }
+        w.write_u64(self.rate_by_timeslice.len() as u64)?;
+        for (ts, hist) in self.rate_by_timeslice.iter() {
+            w.write_i64(*ts)?;
+            w.write_histogram(hist)?;
+        }
+        Ok(())
+    }
+
+    fn read_snapshot(r: &mut SnapshotReader) -> Result<BytesResult, Error> {
+        let mut result = BytesResult::new();
+        for _ in 0..r.read_len()? {
+            let timeslice = r.read_i64()?;
+            let breakdown = match BREAKDOWNS.get(r.read_len()?) {
+                Some(&(b, _, _)) => b,
+                None => return Err(invalid_data("unknown bytes breakdown")),
+            };
+            let key = KeyBytesTimeslice {
+                timeslice: timeslice,
+                breakdown: breakdown,
+                value: r.read_string()?,
+            };
+            let stats = ByteStats {
+                requests: r.read_u64()?,
+                sum: r.read_u64()?,
+                max: r.read_u64()?,
+            };
+            result.stats.insert(key, stats);
+        }
+        for _ in 0..r.read_len()? {
+            let ts = r.read_i64()?;
+            result.rate_by_timeslice.insert(ts, r.read_histogram()?);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_and_rollup() {
+        let mut a = BytesResult::new();
+        a.record(&Record { response_status: "200".to_string(), response_bytes: Some(1000), response_time_micros: 500000, ..Record::default() }, 0, "Video");
+        a.record(&Record { response_status: "304".to_string(), response_time_micros: 100, ..Record::default() }, 60, "Video");
+        let mut b = BytesResult::new();
+        b.record(&Record { response_status: "200".to_string(), response_bytes: Some(3000), response_time_micros: 1000000, ..Record::default() }, 60, "Admin");
+        let mut total = BytesResult::new();
+        total.absorb(&(), &a, 120, &|_| true );
+        total.absorb(&(), &b, 120, &|_| true );
+        let tables = total.tables(&[0], 120);
+        assert_eq!(vec![vec![Cell::Time(0), Cell::Int(3), Cell::Int(4000), Cell::Float(4000.0 / 3.0), Cell::Int(3000), Cell::Float(4000.0 / 120.0)]],
+                   tables[0].rows);
+        assert_eq!(vec![
+                       vec![Cell::Time(0), Cell::Str("200".to_string()), Cell::Int(2), Cell::Int(4000), Cell::Float(2000.0), Cell::Int(3000), Cell::Float(4000.0 / 120.0)],
+                       vec![Cell::Time(0), Cell::Str("304".to_string()), Cell::Int(1), Cell::Int(0), Cell::Float(0.0), Cell::Int(0), Cell::Float(0.0)],
+                   ],
+                   tables[2].rows);
+        // 2000 and 3000 bytes a second; the 304 has no rate
+        let rates = total.rate_table(&[0], &[50.0]);
+        assert_eq!(Cell::Int(2), rates.rows[0][4]);
+        match rates.rows[0][3] {
+            Cell::Float(mean) => assert!((mean - 2500.0).abs() < 25.0),
+            ref other => panic!("unexpected mean {:?}", other),
+        }
+    }
+}
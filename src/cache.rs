@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Error;
+use hdrsample::Histogram;
+use parse_access_log::Record;
+use output::{Cell, Table};
+use snapshot::{SnapshotWriter, SnapshotReader};
+use process::{SliceResult, rescale};
+
+/// Whether a response came from the cache, for the cache statuses that say so one way or the
+/// other
+#[derive(Debug,Clone,Copy,Hash,Eq,PartialEq)]
+pub enum CacheOutcome {
+    Hit,
+    Miss,
+}
+
+/// Interpret a cache status as logged by httpd's mod_cache (`cache hit`, `cache miss`,
+/// `cache revalidate`, `cache invalidate`), nginx's `$upstream_cache_status` (`HIT`, `MISS`,
+/// `EXPIRED`, `STALE`, `UPDATING`, `REVALIDATED`, `BYPASS`) or CloudFront (`Hit`, `RefreshHit`,
+/// `Miss`, `Error` etc).  A stale or revalidated response was still served from the cache, so
+/// counts as a hit.  Anything else, such as a bypass or an error, is neither.
+pub fn cache_outcome(status: &str) -> Option<CacheOutcome> {
+    match &status.to_lowercase()[..] {
+        "hit" | "cache hit" | "refreshhit" | "stale" | "updating" | "revalidated" | "cache revalidate" => Some(CacheOutcome::Hit),
+        "miss" | "cache miss" | "expired" | "cache invalidate" => Some(CacheOutcome::Miss),
+        _ => None,
+    }
+}
+
+/// The label used for requests logged without a cache status
+const NO_STATUS: &'static str = "-";
+
+#[derive(Debug,Clone,Hash,Eq,PartialEq)]
+struct KeyCacheStatusTimeslice {
+    timeslice: i64,
+    cache_status: String,
+}
+
+#[derive(Debug,Clone,Hash,Eq,PartialEq)]
+struct KeyUritypeTimeslice {
+    timeslice: i64,
+    uritype: String,
+}
+
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+struct HitsMisses {
+    hits: u64,
+    misses: u64,
+}
+
+impl HitsMisses {
+    fn add(&mut self, outcome: CacheOutcome) {
+        match outcome {
+            CacheOutcome::Hit => self.hits += 1,
+            CacheOutcome::Miss => self.misses += 1,
+        }
+    }
+
+    fn merge(&mut self, other: &HitsMisses) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+    }
+
+    /// The hits, misses and hit ratio, which is empty if there were neither
+    fn cells(&self) -> Vec<Cell> {
+        let total = self.hits + self.misses;
+        vec![
+            Cell::Int(self.hits as i64),
+            Cell::Int(self.misses as i64),
+            if total > 0 { Cell::Float(self.hits as f64 / total as f64) } else { Cell::Empty },
+        ]
+    }
+}
+
+/// Service time histograms for the requests with one outcome
+pub struct OutcomeHistograms {
+    pub by_timeslice: HashMap<i64, Histogram<u64>>,
+    /// all the timeslices combined
+    pub total: Histogram<u64>,
+}
+
+impl OutcomeHistograms {
+    fn new() -> OutcomeHistograms {
+        OutcomeHistograms {
+            by_timeslice: HashMap::new(),
+            total: Histogram::new(1).unwrap(),
+        }
+    }
+
+    fn record(&mut self, slice: i64, response_time_micros: u64) {
+        let _ = self.by_timeslice.entry(slice).or_insert_with(|| Histogram::new(1).unwrap()).record(response_time_micros);
+        let _ = self.total.record(response_time_micros);
+    }
+
+    fn absorb(&mut self, other: &OutcomeHistograms, slice_seconds: i64, keep: &Fn(i64) -> bool) {
+        for (ts, hist) in other.by_timeslice.iter().filter(|&(ts, _)| keep(*ts) ) {
+            let slice = rescale(ts, slice_seconds);
+            let _ = self.by_timeslice.entry(slice).or_insert_with(|| Histogram::new(1).unwrap()).add(hist);
+            let _ = self.total.add(hist);
+        }
+    }
+
+    fn write_snapshot(&self, w: &mut SnapshotWriter) -> Result<(), Error> {
+        w.write_u64(self.by_timeslice.len() as u64)?;
+        for (ts, hist) in self.by_timeslice.iter() {
+            w.write_i64(*ts)?;
+            w.write_histogram(hist)?;
+        }
+        Ok(())
+    }
+
+    fn read_snapshot(r: &mut SnapshotReader) -> Result<OutcomeHistograms, Error> {
+        let mut result = OutcomeHistograms::new();
+        for _ in 0..r.read_len()? {
+            let ts = r.read_i64()?;
+            let hist = r.read_histogram()?;
+            let _ = result.total.add(&hist);
+            result.by_timeslice.insert(ts, hist);
+        }
+        Ok(result)
+    }
+}
+
+/// Requests per cache status, hit ratios and service times for hits and misses, per timeslice
+pub struct CacheResult {
+    by_status_timeslice: HashMap<KeyCacheStatusTimeslice, u64>,
+    statuses: HashSet<String>,
+    by_uritype_timeslice: HashMap<KeyUritypeTimeslice, HitsMisses>,
+    pub hit_servicetimes: OutcomeHistograms,
+    pub miss_servicetimes: OutcomeHistograms,
+}
+
+impl CacheResult {
+    pub fn new() -> CacheResult {
+        CacheResult {
+            by_status_timeslice: HashMap::new(),
+            statuses: HashSet::new(),
+            by_uritype_timeslice: HashMap::new(),
+            hit_servicetimes: OutcomeHistograms::new(),
+            miss_servicetimes: OutcomeHistograms::new(),
+        }
+    }
+
+    /// Add a record, where `slice` and `uritype` are the values the consumer has already worked
+    /// out for it
+    pub fn record(&mut self, r: &Record, slice: i64, uritype: &str) {
+        let status = if r.response_cache_status.is_empty() { NO_STATUS } else { &r.response_cache_status[..] };
+        let key = KeyCacheStatusTimeslice {
+            timeslice: slice,
+            cache_status: status.to_string(),
+        };
+        *self.by_status_timeslice.entry(key).or_insert(0) += 1;
+        if !self.statuses.contains(status) {
+            self.statuses.insert(status.to_string());
+        }
+        if let Some(outcome) = cache_outcome(status) {
+            let key = KeyUritypeTimeslice {
+                timeslice: slice,
+                uritype: uritype.to_string(),
+            };
+            self.by_uritype_timeslice.entry(key).or_insert_with(HitsMisses::default).add(outcome);
+            match outcome {
+                CacheOutcome::Hit => self.hit_servicetimes.record(slice, r.response_time_micros),
+                CacheOutcome::Miss => self.miss_servicetimes.record(slice, r.response_time_micros),
+            }
+        }
+    }
+
+    /// Whether any request had a cache status logged
+    pub fn has_statuses(&self) -> bool {
+        self.statuses.iter().any(|s| s != NO_STATUS )
+    }
+
+    /// The number of requests with each cache status in each of the given timeslices
+    pub fn status_table(&self, timeslices: &[i64]) -> Table {
+        let mut cols = self.statuses.iter().collect::<Vec<&String>>();
+        cols.sort();
+        let rows = timeslices.iter().map(|ts| {
+            let mut row = vec![Cell::Time(*ts)];
+            for c in &cols {
+                let key = KeyCacheStatusTimeslice {
+                    timeslice: *ts,
+                    cache_status: (*c).clone(),
+                };
+                row.push(Cell::Int(*self.by_status_timeslice.get(&key).unwrap_or(&0) as i64));
+            }
+            row
+        }).collect();
+        Table {
+            name: "by_cache_status_timeslice".to_string(),
+            keys: vec!["timeslice".to_string()],
+            columns: cols.into_iter().cloned().collect(),
+            pivot: Some("response_cache_status".to_string()),
+            rows: rows,
+        }
+    }
+
+    /// The hits, misses and hit ratio in each of the given timeslices
+    pub fn hit_ratio_table(&self, timeslices: &[i64]) -> Table {
+        let mut by_timeslice = HashMap::new();
+        for (k, v) in self.by_uritype_timeslice.iter() {
+            by_timeslice.entry(k.timeslice).or_insert_with(HitsMisses::default).merge(v);
+        }
+        let rows = timeslices.iter().map(|ts| {
+            let mut row = vec![Cell::Time(*ts)];
+            row.extend(by_timeslice.get(ts).cloned().unwrap_or_default().cells());
+            row
+        }).collect();
+        Table {
+            name: "cache_hit_ratio_by_timeslice".to_string(),
+            keys: vec!["timeslice".to_string()],
+            columns: vec!["hits".to_string(), "misses".to_string(), "hit_ratio".to_string()],
+            pivot: None,
+            rows: rows,
+        }
+    }
+
+    /// The hits, misses and hit ratio for each URI type in each timeslice.  As for the query
+    /// table, timeslices aren't zero-filled.
+    pub fn uritype_hit_ratio_table(&self) -> Table {
+        let mut keys = self.by_uritype_timeslice.keys().collect::<Vec<&KeyUritypeTimeslice>>();
+        keys.sort_by(|a, b| (a.timeslice, &a.uritype).cmp(&(b.timeslice, &b.uritype)) );
+        let rows = keys.into_iter().map(|k| {
+            let mut row = vec![Cell::Time(k.timeslice), Cell::Str(k.uritype.clone())];
+            row.extend(self.by_uritype_timeslice[k].cells());
+            row
+        }).collect();
+        Table {
+            name: "cache_hit_ratio_by_uritype_timeslice".to_string(),
+            keys: vec!["timeslice".to_string(), "uritype".to_string()],
+            columns: vec!["hits".to_string(), "misses".to_string(), "hit_ratio".to_string()],
+            pivot: None,
+            rows: rows,
+        }
+    }
+}
+
+impl SliceResult for CacheResult {
+    type Config = ();
+
+    fn absorb(&mut self, _: &(), other: &CacheResult, slice_seconds: i64, keep: &Fn(i64) -> bool) {
+        for (k, v) in other.by_status_timeslice.iter().filter(|&(k, _)| keep(k.timeslice) ) {
+            if !self.statuses.contains(&k.cache_status) {
+                self.statuses.insert(k.cache_status.clone());
+            }
+            let key = KeyCacheStatusTimeslice {
+                timeslice: rescale(k.timeslice, slice_seconds),
+                cache_status: k.cache_status.clone(),
+            };
+            *self.by_status_timeslice.entry(key).or_insert(0) += *v;
+        }
+        for (k, v) in other.by_uritype_timeslice.iter().filter(|&(k, _)| keep(k.timeslice) ) {
+            let key = KeyUritypeTimeslice {
+                timeslice: rescale(k.timeslice, slice_seconds),
+                uritype: k.uritype.clone(),
+            };
+            self.by_uritype_timeslice.entry(key).or_insert_with(HitsMisses::default).merge(v);
+        }
+        self.hit_servicetimes.absorb(&other.hit_servicetimes, slice_seconds, keep);
+        self.miss_servicetimes.absorb(&other.miss_servicetimes, slice_seconds, keep);
+    }
+
+    fn write_snapshot(&self, w: &mut SnapshotWriter) -> Result<(), Error> {
+        w.write_u64(self.by_status_timeslice.len() as u64)?;
+        for (k, v) in self.by_status_timeslice.iter() {
+            w.write_i64(k.timeslice)?;
+            w.write_str(&k.cache_status)?;
+            w.write_u64(*v)?;
+        }
+        w.write_u64(self.by_uritype_timeslice.len() as u64)?;
+        for (k, v) in self.by_uritype_timeslice.iter() {
+            w.write_i64(k.timeslice)?;
+            w.write_str(&k.uritype)?;
+            w.write_u64(v.hits)?;
+            w.write_u64(v.misses)?;
+        }
+        self.hit_servicetimes.write_snapshot(w)?;
+        self.miss_servicetimes.write_snapshot(w)
+    }
+
+    fn read_snapshot(r: &mut SnapshotReader) -> Result<CacheResult, Error> {
+        let mut result = CacheResult::new();
+        for _ in 0..r.read_len()? {
+            let key = KeyCacheStatusTimeslice {
+                timeslice: r.read_i64()?,
+                cache_status: r.read_string()?,
+            };
+            result.statuses.insert(key.cache_status.clone());
+            result.by_status_timeslice.insert(key, r.read_u64()?);
+        }
+        for _ in 0..r.read_len()? {
+            let key = KeyUritypeTimeslice {
+                timeslice: r.read_i64()?,
+                uritype: r.read_string()?,
+            };
+            let counts = HitsMisses {
+                hits: r.read_u64()?,
+                misses: r.read_u64()?,
+            };
+            result.by_uritype_timeslice.insert(key, counts);
+        }
+        result.hit_servicetimes = OutcomeHistograms::read_snapshot(r)?;
+        result.miss_servicetimes = OutcomeHistograms::read_snapshot(r)?;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outcomes() {
+        assert_eq!(Some(CacheOutcome::Hit), cache_outcome("HIT"));
+        assert_eq!(Some(CacheOutcome::Hit), cache_outcome("RefreshHit"));
+        assert_eq!(Some(CacheOutcome::Hit), cache_outcome("cache revalidate"));
+        assert_eq!(Some(CacheOutcome::Miss), cache_outcome("EXPIRED"));
+        assert_eq!(Some(CacheOutcome::Miss), cache_outcome("cache miss"));
+        assert_eq!(None, cache_outcome("BYPASS"));
+        assert_eq!(None, cache_outcome(""));
+    }
+
+    #[test]
+    fn hit_ratios() {
+        let mut a = CacheResult::new();
+        a.record(&Record { response_cache_status: "HIT".to_string(), response_time_micros: 100, ..Record::default() }, 0, "Video");
+        a.record(&Record { response_cache_status: "HIT".to_string(), response_time_micros: 200, ..Record::default() }, 0, "Video");
+        a.record(&Record { response_cache_status: "MISS".to_string(), response_time_micros: 90000, ..Record::default() }, 60, "Video");
+        a.record(&Record { response_time_micros: 100, ..Record::default() }, 60, "Admin");
+        let mut total = CacheResult::new();
+        total.absorb(&(), &a, 120, &|_| true );
+        assert_eq!(vec![vec![Cell::Time(0), Cell::Int(1), Cell::Int(2), Cell::Int(1)]],
+                   total.status_table(&[0]).rows);
+        assert_eq!(vec!["-", "HIT", "MISS"], total.status_table(&[0]).columns);
+        assert_eq!(vec![vec![Cell::Time(0), Cell::Int(2), Cell::Int(1), Cell::Float(2.0 / 3.0)]],
+                   total.hit_ratio_table(&[0]).rows);
+        // requests without a cache status aren't part of any ratio
+        assert_eq!(vec![vec![Cell::Time(0), Cell::Str("Video".to_string()), Cell::Int(2), Cell::Int(1), Cell::Float(2.0 / 3.0)]],
+                   total.uritype_hit_ratio_table().rows);
+        assert_eq!(2, total.hit_servicetimes.total.count());
+        assert_eq!(1, total.miss_servicetimes.by_timeslice[&0].count());
+    }
+
+    #[test]
+    fn no_statuses() {
+        let mut result = CacheResult::new();
+        result.record(&Record { response_time_micros: 100, ..Record::default() }, 0, "Video");
+        assert!(!result.has_statuses());
+        result.record(&Record { response_cache_status: "BYPASS".to_string(), ..Record::default() }, 0, "Video");
+        assert!(result.has_statuses());
+    }
+}
@@ -0,0 +1,100 @@
+use std::io;
+use std::io::Read;
+use std::cmp::min;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+pub type ChunkSender = SyncSender<Result<Vec<u8>, io::Error>>;
+
+/// Adapts a sequence of data chunks, sent from another thread, into a `Read` implementation.
+///
+/// Lets blocking consumers (`GzDecoder`, the line parser) process a response body while it is
+/// still being received by the event loop.  The channel is bounded, so a slow reader will apply
+/// back-pressure to the sender rather than having the whole body buffer up in memory.
+pub struct ChunkReader {
+    recv: Receiver<Result<Vec<u8>, io::Error>>,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+/// Create a connected sender / reader pair, allowing at most `bound` chunks to be queued.
+/// Dropping the sender marks the end of the data.
+pub fn channel(bound: usize) -> (ChunkSender, ChunkReader) {
+    let (send, recv) = sync_channel(bound);
+    let reader = ChunkReader {
+        recv: recv,
+        current: Vec::new(),
+        pos: 0,
+    };
+    (send, reader)
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.current.len() {
+            match self.recv.recv() {
+                Ok(Ok(chunk)) => {
+                    self.current = chunk;
+                    self.pos = 0;
+                },
+                Ok(Err(e)) => return Err(e),
+                // sender has gone away, so there is no more data
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = min(buf.len(), self.current.len() - self.pos);
+        buf[..len].copy_from_slice(&self.current[self.pos..self.pos+len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn reads_across_chunks() {
+        let (send, mut reader) = channel(4);
+        send.send(Ok(b"hello ".to_vec())).unwrap();
+        send.send(Ok(Vec::new())).unwrap();
+        send.send(Ok(b"world".to_vec())).unwrap();
+        drop(send);
+        let mut buf = [0; 4];
+        assert_eq!(4, reader.read(&mut buf).unwrap());
+        assert_eq!(b"hell", &buf);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!("o world", rest);
+        // the sender has gone, so the data has ended
+        assert_eq!(0, reader.read(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn error_from_sender() {
+        let (send, mut reader) = channel(4);
+        send.send(Ok(b"partial".to_vec())).unwrap();
+        send.send(Err(io::Error::new(io::ErrorKind::Other, "connection reset"))).unwrap();
+        let mut data = Vec::new();
+        let e = reader.read_to_end(&mut data).unwrap_err();
+        assert_eq!("connection reset", e.to_string());
+        assert_eq!(b"partial", &data[..]);
+    }
+
+    #[test]
+    fn bounded() {
+        let (send, mut reader) = channel(1);
+        send.send(Ok(vec![1])).unwrap();
+        // the channel is full until the reader takes a chunk
+        assert!(send.try_send(Ok(vec![2])).is_err());
+        let sender = thread::spawn(move || {
+            for i in 2..10 {
+                send.send(Ok(vec![i])).unwrap();
+            }
+        });
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        sender.join().unwrap();
+        assert_eq!((1..10).collect::<Vec<u8>>(), data);
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 network, such as `10.0.0.0/8` or `2001:db8::/32`
+#[derive(Debug,Clone,PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Parse a network in CIDR notation.  A bare address is treated as a network containing only
+    /// that address.
+    pub fn parse(text: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match text.find('/') {
+            Some(i) => (&text[..i], Some(&text[i+1..])),
+            None => (text, None),
+        };
+        let addr = IpAddr::from_str(addr).map_err(|e| format!("invalid address in {:?}: {}", text, e))?;
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix {
+            Some(p) => p.parse::<u8>().map_err(|_| format!("invalid prefix length in {:?}", text))?,
+            None => max,
+        };
+        if prefix_len > max {
+            return Err(format!("prefix length in {:?} is larger than {}", text, max));
+        }
+        Ok(Cidr {
+            addr: addr,
+            prefix_len: prefix_len,
+        })
+    }
+
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (&self.addr, addr) {
+            (&IpAddr::V4(ref net), &IpAddr::V4(ref a)) => prefix_matches(&net.octets(), &a.octets(), self.prefix_len),
+            (&IpAddr::V6(ref net), &IpAddr::V6(ref a)) => prefix_matches(&net.octets(), &a.octets(), self.prefix_len),
+            _ => false,
+        }
+    }
+
+    /// Is the given text an address within this network?  Text that is not an IP address (such
+    /// as a hostname, or `-`) is never contained.
+    pub fn contains_str(&self, addr: &str) -> bool {
+        IpAddr::from_str(addr).map(|a| self.contains(&a) ).unwrap_or(false)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+fn prefix_matches(net: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+    let whole = (prefix_len / 8) as usize;
+    if net[..whole] != addr[..whole] {
+        return false;
+    }
+    let bits = prefix_len % 8;
+    if bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - bits);
+    net[whole] & mask == addr[whole] & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v4() {
+        let net = Cidr::parse("10.1.0.0/17").unwrap();
+        assert!(net.contains_str("10.1.0.1"));
+        assert!(net.contains_str("10.1.127.255"));
+        assert!(!net.contains_str("10.1.128.0"));
+        assert!(!net.contains_str("example.com"));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains_str("192.0.2.1"));
+        assert!(Cidr::parse("192.0.2.1").unwrap().contains_str("192.0.2.1"));
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn v6() {
+        let net = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(net.contains_str("2001:db8:1::1"));
+        assert!(!net.contains_str("2001:db9::1"));
+        assert!(!net.contains_str("10.0.0.1"));
+    }
+}
@@ -0,0 +1,174 @@
+use regex::{Regex, RegexSet};
+use regex;
+
+/// The label given to URIs that no rule matches, unless configured otherwise
+pub const DEFAULT_UNMATCHED_LABEL: &'static str = "UnknownOther";
+
+/// The rules used when a datasource does not name a classifier of its own
+const DEFAULT_RULES: &'static [(&'static str, &'static str)] = &[
+    ("HdsBootstrap", r"\.bootstrap"),
+    ("HlsSegment", r"/[^/]+.ts"),
+    ("HdsSegment", r"-Seg1-Frag(\d+)"),
+    ("HlsMediaManifest", r"(?:audio=|video=)[^/]+\.m3u8"),
+    ("HlsMasterManifest", r"\.m3u8"),
+    ("HdsF4mManifest", r"\.f4m"),
+    ("DashInitialisationSegment", r"\.dash"),
+    ("DashSegment", r"\.m4s"),
+    ("DashManifest", r"\.mpd"),
+    ("Admin", r"/test\.txt$|/Manifest?iss_client_manifest_version=22$|/archive-segment-length-seconds$|/state$|/statistics$|/servicePaths.txt$|/server-status$"),
+];
+
+#[derive(Debug,Clone)]
+struct Rule {
+    label: String,
+    re: Regex,
+    /// does the regex define any named capture groups?
+    named_captures: bool,
+}
+
+/// Assigns a label to each request URI using an ordered list of regular expressions, where the
+/// first matching expression wins.  Named capture groups within the expressions provide extra
+/// dimensions, e.g. `-Seg1-Frag(?P<fragment>\d+)`.
+#[derive(Debug,Clone)]
+pub struct Classifier {
+    set: RegexSet,
+    rules: Vec<Rule>,
+    unmatched: String,
+}
+
+/// The result of classifying a single URI
+pub struct Classification<'a> {
+    pub label: &'a str,
+    /// `(name, value)` for each named capture group that participated in the match
+    pub captures: Vec<(&'a str, String)>,
+}
+
+impl Classifier {
+    /// Create a classifier from a list of `(label, regex)` pairs, in priority order
+    pub fn new<S: AsRef<str>>(rules: &[(S, S)], unmatched: &str) -> Result<Classifier, regex::Error> {
+        let set = RegexSet::new(rules.iter().map(|&(_, ref re)| re.as_ref() ))?;
+        let mut compiled = Vec::new();
+        for &(ref label, ref re) in rules.iter() {
+            let re = Regex::new(re.as_ref())?;
+            let named_captures = re.capture_names().any(|n| n.is_some() );
+            compiled.push(Rule {
+                label: label.as_ref().to_string(),
+                re: re,
+                named_captures: named_captures,
+            });
+        }
+        Ok(Classifier {
+            set: set,
+            rules: compiled,
+            unmatched: unmatched.to_string(),
+        })
+    }
+
+    /// The built-in rules for HLS, HDS, DASH and admin requests
+    pub fn default_rules() -> Classifier {
+        Classifier::new(DEFAULT_RULES, DEFAULT_UNMATCHED_LABEL).unwrap()
+    }
+
+    /// Every label the classifier can give, in the order of its rules, followed by the label
+    /// for URIs no rule matches
+    pub fn labels(&self) -> Vec<&str> {
+        let mut labels: Vec<&str> = Vec::new();
+        for rule in self.rules.iter() {
+            if !labels.contains(&&rule.label[..]) {
+                labels.push(&rule.label);
+            }
+        }
+        if !labels.contains(&&self.unmatched[..]) {
+            labels.push(&self.unmatched);
+        }
+        labels
+    }
+
+    /// The names of the capture groups in all the rules, in order, without repeats
+    pub fn capture_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        for rule in self.rules.iter() {
+            for name in rule.re.capture_names() {
+                if let Some(name) = name {
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+        }
+        names
+    }
+
+    pub fn classify<'a>(&'a self, uri: &str) -> Classification<'a> {
+        match self.set.matches(uri).into_iter().next() {
+            Some(i) => {
+                let rule = &self.rules[i];
+                let mut captures = Vec::new();
+                if rule.named_captures {
+                    if let Some(caps) = rule.re.captures(uri) {
+                        for name in rule.re.capture_names() {
+                            if let Some(name) = name {
+                                if let Some(m) = caps.name(name) {
+                                    captures.push((name, m.as_str().to_string()));
+                                }
+                            }
+                        }
+                    }
+                }
+                Classification {
+                    label: &rule.label,
+                    captures: captures,
+                }
+            },
+            None => Classification {
+                label: &self.unmatched,
+                captures: Vec::new(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classifier() -> Classifier {
+        Classifier::new(&[
+            ("Segment", r"-Frag(?P<fragment>\d+)"),
+            ("Video", r"\.(?P<ext>mp4|ts)"),
+            ("Segment", r"/seg/"),
+        ], "Other").unwrap()
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let c = classifier();
+        assert_eq!("Segment", c.classify("/a-Frag12.ts").label);
+        assert_eq!("Video", c.classify("/a.ts").label);
+        assert_eq!("Segment", c.classify("/seg/a").label);
+    }
+
+    #[test]
+    fn named_captures() {
+        let c = classifier();
+        assert_eq!(vec![("fragment", "12".to_string())], c.classify("/a-Frag12.ts").captures);
+        assert_eq!(vec![("ext", "mp4".to_string())], c.classify("/a.mp4").captures);
+        assert!(c.classify("/seg/a").captures.is_empty());
+        assert_eq!(vec!["fragment", "ext"], c.capture_names());
+    }
+
+    #[test]
+    fn unmatched() {
+        let c = classifier();
+        let result = c.classify("/index.html");
+        assert_eq!("Other", result.label);
+        assert!(result.captures.is_empty());
+        assert_eq!(DEFAULT_UNMATCHED_LABEL, Classifier::default_rules().classify("/index.html").label);
+        assert_eq!(vec!["Segment", "Video", "Other"], c.labels());
+    }
+
+    #[test]
+    fn bad_regex() {
+        assert!(Classifier::new(&[("Broken", "(unclosed")], "Other").is_err());
+    }
+}
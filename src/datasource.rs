@@ -2,11 +2,66 @@ use toml;
 use std::io;
 use std::fs::File;
 use std::io::Read;
+use parse_access_log::{LogParser, ParseSettings};
+use parse_error::ErrorPolicy;
+use filter::Period;
+use classify::{Classifier, DEFAULT_UNMATCHED_LABEL};
 
-#[derive(Deserialize,Debug)]
+#[derive(Deserialize,Debug,Default)]
 pub struct Datasources {
     pub s3: Vec<S3Source>,
     pub file: Vec<FileSource>,
+    #[serde(default)]
+    pub classifier: Vec<ClassifierConfig>,
+    #[serde(default)]
+    pub alert: Vec<AlertRule>,
+}
+
+impl Datasources {
+    /// Build the named URI classifier, or the built-in one if no name is given
+    pub fn build_classifier(&self, name: &Option<String>) -> Result<Classifier, io::Error> {
+        let name = match *name {
+            Some(ref n) => n,
+            None => return Ok(Classifier::default_rules()),
+        };
+        let config = self.classifier.iter().find(|c| &c.name == name )
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("no classifier named {:?}", name)))?;
+        let rules = config.rule.iter().map(|r| (&r.label[..], &r.pattern[..]) ).collect::<Vec<_>>();
+        let unmatched = config.unmatched.as_ref().map(|u| &u[..] ).unwrap_or(DEFAULT_UNMATCHED_LABEL);
+        Classifier::new(&rules, unmatched)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("classifier {:?}: {}", name, e)))
+    }
+}
+
+/// An ordered list of rules giving a label to each request URI
+#[derive(Deserialize,Debug)]
+pub struct ClassifierConfig {
+    pub name: String,
+    /// label for URIs that match none of the rules
+    pub unmatched: Option<String>,
+    pub rule: Vec<ClassifierRule>,
+}
+
+#[derive(Deserialize,Debug)]
+pub struct ClassifierRule {
+    pub label: String,
+    pub pattern: String,
+}
+
+/// A threshold on one column of an output table, checked with `--alerts`
+#[derive(Deserialize,Debug)]
+pub struct AlertRule {
+    pub name: String,
+    /// the table to check, e.g. `"error_rate_by_timeslice"`
+    pub table: String,
+    /// the column to check, e.g. `"server_error_rate"`
+    pub column: String,
+    /// breached by values greater than this
+    pub above: Option<f64>,
+    /// breached by values less than this
+    pub below: Option<f64>,
+    /// only breached when this many timeslices in a row are over the threshold (default 1)
+    pub consecutive: Option<usize>,
 }
 
 #[derive(Deserialize,Debug)]
@@ -15,12 +70,82 @@ pub struct S3Source {
     pub region: String,
     pub bucket: String,
     pub pathexp: String,
+    /// The kind of log, `"httpd"` (the default), `"nginx"`, `"elb"`, `"alb"` or `"cloudfront"`
+    pub format: Option<String>,
+    /// Apache `LogFormat` / nginx `log_format` string describing the layout of each log line
+    pub logformat: Option<String>,
+    /// `"skip"` (the default) to ignore lines that can't be parsed, or `"fail"` to discard a
+    /// file's results at the first such line
+    pub on_error: Option<String>,
+    /// discard a file's results when it has more than this many lines that can't be parsed
+    pub max_errors: Option<u64>,
+    /// discard a file's results when more than this fraction of its lines can't be parsed
+    pub max_error_rate: Option<f64>,
+    /// name of the `[[classifier]]` used to label request URIs
+    pub classifier: Option<String>,
+    /// width of each timeslice, e.g. `"5m"`, unless overridden with `--slice`
+    pub slice: Option<String>,
+}
+
+impl S3Source {
+    pub fn parse_settings(&self) -> Result<ParseSettings, io::Error> {
+        Ok(ParseSettings {
+            parser: make_parser(&self.format, &self.logformat)?,
+            policy: make_error_policy(&self.on_error, self.max_errors, self.max_error_rate)?,
+            filter: None,
+            period: Period::default(),
+            skip_files_outside_period: false,
+        })
+    }
 }
 
 #[derive(Deserialize,Debug)]
 pub struct FileSource {
     pub name: String,
     pub pathexp: String,
+    /// The kind of log, `"httpd"` (the default), `"nginx"`, `"elb"`, `"alb"` or `"cloudfront"`
+    pub format: Option<String>,
+    /// Apache `LogFormat` / nginx `log_format` string describing the layout of each log line
+    pub logformat: Option<String>,
+    /// `"skip"` (the default) to ignore lines that can't be parsed, or `"fail"` to discard a
+    /// file's results at the first such line
+    pub on_error: Option<String>,
+    /// discard a file's results when it has more than this many lines that can't be parsed
+    pub max_errors: Option<u64>,
+    /// discard a file's results when more than this fraction of its lines can't be parsed
+    pub max_error_rate: Option<f64>,
+    /// name of the `[[classifier]]` used to label request URIs
+    pub classifier: Option<String>,
+    /// width of each timeslice, e.g. `"5m"`, unless overridden with `--slice`
+    pub slice: Option<String>,
+}
+
+impl FileSource {
+    pub fn parse_settings(&self) -> Result<ParseSettings, io::Error> {
+        Ok(ParseSettings {
+            parser: make_parser(&self.format, &self.logformat)?,
+            policy: make_error_policy(&self.on_error, self.max_errors, self.max_error_rate)?,
+            filter: None,
+            period: Period::default(),
+            skip_files_outside_period: false,
+        })
+    }
+}
+
+fn make_parser(format: &Option<String>, logformat: &Option<String>) -> Result<LogParser, io::Error> {
+    let format = format.as_ref().map(|f| &f[..] ).unwrap_or("httpd");
+    LogParser::new(format, logformat.as_ref().map(|f| &f[..] ))
+}
+
+fn make_error_policy(on_error: &Option<String>, max_errors: Option<u64>, max_error_rate: Option<f64>) -> Result<ErrorPolicy, io::Error> {
+    match (on_error.as_ref().map(|e| &e[..] ), max_errors, max_error_rate) {
+        (Some("fail"), None, None) => Ok(ErrorPolicy::Fail),
+        (None, None, None) | (Some("skip"), None, None) => Ok(ErrorPolicy::Skip),
+        (None, Some(max), None) | (Some("skip"), Some(max), None) => Ok(ErrorPolicy::MaxCount(max)),
+        (None, None, Some(rate)) | (Some("skip"), None, Some(rate)) => Ok(ErrorPolicy::MaxRate(rate)),
+        (Some(other), None, None) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("on_error must be \"skip\" or \"fail\", not {:?}", other))),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "only one of on_error = \"fail\", max_errors and max_error_rate may be given")),
+    }
 }
 
 pub fn get_datasources() -> Result<Datasources, io::Error> {
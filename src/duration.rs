@@ -0,0 +1,41 @@
+/// Parse a duration such as `10s`, `5m`, `1h` or `1d` into a number of seconds.  A number with
+/// no unit is taken to be seconds.
+pub fn parse_duration(text: &str) -> Result<i64, String> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_digit(10) ).unwrap_or(text.len());
+    let (num, unit) = text.split_at(split);
+    let num: i64 = num.parse().map_err(|_| format!("invalid duration {:?}", text))?;
+    let mult = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown unit {:?} in duration {:?} (expected s, m, h or d)", unit, text)),
+    };
+    if num == 0 {
+        return Err(format!("duration must not be zero: {:?}", text));
+    }
+    Ok(num * mult)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units() {
+        assert_eq!(Ok(10), parse_duration("10s"));
+        assert_eq!(Ok(60), parse_duration("1m"));
+        assert_eq!(Ok(3600), parse_duration("1h"));
+        assert_eq!(Ok(86400), parse_duration("1d"));
+        assert_eq!(Ok(300), parse_duration("300"));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5y").is_err());
+        assert!(parse_duration("0s").is_err());
+    }
+}
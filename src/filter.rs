@@ -0,0 +1,367 @@
+use std::cmp::Ordering;
+use regex::Regex;
+use time::strptime;
+use parse_access_log::Record;
+use query::{RecordField, Value};
+use cidr::Cidr;
+use time::Timespec;
+
+/// Formats accepted for times compared against `timestamp`, which are taken to be UTC
+const TIME_FORMATS: &'static [&'static str] = &[
+    "%Y-%m-%dT%H:%M:%SZ",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%d:%H:%M:%S",
+    "%Y-%m-%d",
+];
+
+/// A comparison between a field and a literal value
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn test(&self, ord: Ordering) -> bool {
+        match *self {
+            Op::Eq => ord == Ordering::Equal,
+            Op::Ne => ord != Ordering::Equal,
+            Op::Lt => ord == Ordering::Less,
+            Op::Le => ord != Ordering::Greater,
+            Op::Gt => ord == Ordering::Greater,
+            Op::Ge => ord != Ordering::Less,
+        }
+    }
+}
+
+/// A predicate over `Record` values, such as
+/// `request_host == "cdn.example.com" && response_status =~ "^5"`
+#[derive(Debug,Clone)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Compare(RecordField, Op, Value),
+    Matches(RecordField, Regex),
+    InNetwork(RecordField, Cidr),
+}
+
+impl Filter {
+    pub fn parse(text: &str) -> Result<Filter, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens: &tokens, pos: 0 };
+        let filter = parser.or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some(t) => Err(format!("unexpected {:?} in filter", t)),
+        }
+    }
+
+    pub fn matches(&self, r: &Record) -> bool {
+        match *self {
+            Filter::And(ref a, ref b) => a.matches(r) && b.matches(r),
+            Filter::Or(ref a, ref b) => a.matches(r) || b.matches(r),
+            Filter::Not(ref f) => !f.matches(r),
+            Filter::Compare(field, op, ref expected) => {
+                match (field.value(r), expected) {
+                    (Value::Num(n), &Value::Num(e)) => op.test(n.cmp(&e)),
+                    (Value::Str(ref s), &Value::Num(e)) => match s.parse::<i64>() {
+                        Ok(n) => op.test(n.cmp(&e)),
+                        Err(_) => op == Op::Ne,
+                    },
+                    (Value::Str(ref s), &Value::Str(ref e)) => op.test(s[..].cmp(&e[..])),
+                    // string literals for numeric fields are converted when the filter is parsed
+                    (Value::Num(_), &Value::Str(_)) => false,
+                }
+            },
+            Filter::Matches(field, ref re) => match field.text(r) {
+                Some(s) => re.is_match(s),
+                None => re.is_match(&field.value(r).to_string()),
+            },
+            Filter::InNetwork(field, ref net) => field.text(r).map(|s| net.contains_str(s) ).unwrap_or(false),
+        }
+    }
+}
+
+/// A range of time, such as the one given by `--period`, including `from` but excluding `to`
+#[derive(Debug,Clone,Default)]
+pub struct Period {
+    pub from: Option<Timespec>,
+    pub to: Option<Timespec>,
+}
+
+impl Period {
+    pub fn is_bounded(&self) -> bool {
+        self.from.is_some() || self.to.is_some()
+    }
+
+    pub fn contains(&self, t: &Timespec) -> bool {
+        !self.is_before(t) && !self.is_after(t)
+    }
+
+    /// Is the given time earlier than the start of the period?
+    pub fn is_before(&self, t: &Timespec) -> bool {
+        self.from.map(|from| *t < from ).unwrap_or(false)
+    }
+
+    /// Is the given time at or beyond the end of the period?
+    pub fn is_after(&self, t: &Timespec) -> bool {
+        self.to.map(|to| *t >= to ).unwrap_or(false)
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(i64),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+const OPERATORS: &'static [&'static str] = &["&&", "||", "==", "!=", "<=", ">=", "=~", "!~", "<", ">", "!"];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some(&(i, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' {
+            chars.next();
+            tokens.push(Token::LParen);
+        } else if c == ')' {
+            chars.next();
+            tokens.push(Token::RParen);
+        } else if c == '"' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c)) => s.push(c),
+                        None => return Err("unterminated string in filter".to_string()),
+                    },
+                    Some((_, c)) => s.push(c),
+                    None => return Err("unterminated string in filter".to_string()),
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else if c.is_digit(10) || c == '-' {
+            let mut end = i;
+            while let Some(&(j, c)) = chars.peek() {
+                if !(c.is_digit(10) || (c == '-' && j == i)) {
+                    break;
+                }
+                end = j + c.len_utf8();
+                chars.next();
+            }
+            let n = text[i..end].parse().map_err(|_| format!("invalid number {:?} in filter", &text[i..end]))?;
+            tokens.push(Token::Num(n));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = i;
+            while let Some(&(j, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_') {
+                    break;
+                }
+                end = j + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token::Ident(text[i..end].to_string()));
+        } else {
+            match OPERATORS.iter().find(|op| text[i..].starts_with(*op) ) {
+                Some(op) => {
+                    for _ in 0..op.len() {
+                        chars.next();
+                    }
+                    tokens.push(Token::Op(*op));
+                },
+                None => return Err(format!("unexpected {:?} in filter", c)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over the tokens, where `&&` binds more tightly than `||`
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let t = self.tokens.get(self.pos);
+        self.pos += 1;
+        t
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut left = self.and()?;
+        while self.peek() == Some(&Token::Op("||")) {
+            self.pos += 1;
+            let right = self.and()?;
+            left = Filter::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut left = self.unary()?;
+        while self.peek() == Some(&Token::Op("&&")) {
+            self.pos += 1;
+            let right = self.unary()?;
+            left = Filter::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Filter, String> {
+        match self.next() {
+            Some(&Token::Op("!")) => Ok(Filter::Not(Box::new(self.unary()?))),
+            Some(&Token::LParen) => {
+                let inner = self.or()?;
+                match self.next() {
+                    Some(&Token::RParen) => Ok(inner),
+                    _ => Err("missing ')' in filter".to_string()),
+                }
+            },
+            Some(&Token::Ident(ref name)) => self.comparison(name),
+            Some(t) => Err(format!("expected a field name, found {:?}", t)),
+            None => Err("unexpected end of filter".to_string()),
+        }
+    }
+
+    fn comparison(&mut self, name: &str) -> Result<Filter, String> {
+        let field = RecordField::parse(name).ok_or_else(|| format!("unknown field {:?} in filter", name))?;
+        let op = match self.next() {
+            Some(&Token::Op(op)) => op,
+            Some(&Token::Ident(ref i)) if i == "in" => "in",
+            Some(t) => return Err(format!("expected an operator after {}, found {:?}", name, t)),
+            None => return Err(format!("expected an operator after {}", name)),
+        };
+        let literal = match self.next() {
+            Some(&Token::Str(ref s)) => Value::Str(s.clone()),
+            Some(&Token::Num(n)) => Value::Num(n),
+            Some(t) => return Err(format!("expected a string or number after {} {}, found {:?}", name, op, t)),
+            None => return Err(format!("expected a string or number after {} {}", name, op)),
+        };
+        let text = match literal {
+            Value::Str(ref s) => Some(s.clone()),
+            Value::Num(_) => None,
+        };
+        match (op, text) {
+            ("=~", Some(re)) | ("!~", Some(re)) => {
+                let re = Regex::new(&re).map_err(|e| format!("bad regex in filter: {}", e))?;
+                let f = Filter::Matches(field, re);
+                Ok(if op == "!~" { Filter::Not(Box::new(f)) } else { f })
+            },
+            ("in", Some(net)) => Ok(Filter::InNetwork(field, Cidr::parse(&net)?)),
+            ("=~", None) | ("!~", None) | ("in", None) => Err(format!("{} {} needs a string", name, op)),
+            (op, _) => {
+                let op = match op {
+                    "==" => Op::Eq,
+                    "!=" => Op::Ne,
+                    "<" => Op::Lt,
+                    "<=" => Op::Le,
+                    ">" => Op::Gt,
+                    ">=" => Op::Ge,
+                    _ => return Err(format!("unexpected operator {:?} in filter", op)),
+                };
+                Ok(Filter::Compare(field, op, literal_for(field, literal)?))
+            },
+        }
+    }
+}
+
+/// Convert a literal to the type of value that the field holds, so that for example `timestamp`
+/// can be compared to a date, and `response_bytes` to `"1000"`
+fn literal_for(field: RecordField, literal: Value) -> Result<Value, String> {
+    match literal {
+        Value::Str(ref s) if field.is_numeric() => {
+            if let Ok(n) = s.parse() {
+                return Ok(Value::Num(n));
+            }
+            if field == RecordField::Timestamp {
+                for fmt in TIME_FORMATS {
+                    if let Ok(tm) = strptime(s, fmt) {
+                        return Ok(Value::Num(tm.to_timespec().sec));
+                    }
+                }
+                return Err(format!("can't understand {:?} as a time; try YYYY-MM-DDThh:mm:ssZ", s));
+            }
+            Err(format!("{} must be compared with a number, not {:?}", field.name(), s))
+        },
+        literal => Ok(literal),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Timespec;
+
+    fn record() -> Record {
+        let mut r = Record::default();
+        r.timestamp = Timespec::new(1488326400, 0); // 2017-03-01T00:00:00Z
+        r.remote_host = "10.1.2.3".to_string();
+        r.request_host = "cdn.example.com".to_string();
+        r.response_status = "503".to_string();
+        r.response_bytes = Some(1234);
+        r
+    }
+
+    fn check(text: &str) -> bool {
+        Filter::parse(text).unwrap().matches(&record())
+    }
+
+    #[test]
+    fn comparisons() {
+        assert!(check(r#"request_host == "cdn.example.com" && response_status =~ "^5""#));
+        assert!(!check(r#"request_host != "cdn.example.com""#));
+        assert!(check("response_status >= 500 && response_status < 600"));
+        assert!(check(r#"response_bytes > "1000""#));
+        assert!(check(r#"response_status !~ "^2" || response_bytes == 0"#));
+        assert!(!check(r#"!(response_status =~ "^5")"#));
+    }
+
+    #[test]
+    fn networks_and_times() {
+        assert!(check(r#"remote_host in "10.0.0.0/8""#));
+        assert!(!check(r#"remote_host in "192.168.0.0/16""#));
+        assert!(check(r#"timestamp >= "2017-03-01" && timestamp < "2017-03-01T01:00:00Z""#));
+        assert!(!check(r#"timestamp > "2017-03-01T00:00:00Z""#));
+    }
+
+    #[test]
+    fn period() {
+        let period = Period {
+            from: Some(Timespec::new(100, 0)),
+            to: Some(Timespec::new(200, 0)),
+        };
+        assert!(period.is_before(&Timespec::new(99, 999999999)));
+        assert!(period.contains(&Timespec::new(100, 0)));
+        assert!(period.contains(&Timespec::new(199, 0)));
+        assert!(period.is_after(&Timespec::new(200, 0)));
+        assert!(Period::default().contains(&Timespec::new(0, 0)));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Filter::parse("no_such_field == 1").is_err());
+        assert!(Filter::parse(r#"request_host == "x" &&"#).is_err());
+        assert!(Filter::parse(r#"(request_host == "x""#).is_err());
+        assert!(Filter::parse(r#"response_bytes > "lots""#).is_err());
+        assert!(Filter::parse(r#"request_host =~ "(""#).is_err());
+    }
+}
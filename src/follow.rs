@@ -0,0 +1,442 @@
+use std::fs;
+use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::i64;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(not(unix))]
+use std::time::UNIX_EPOCH;
+use time;
+use parse_access_log::{ParseSettings, process_lines};
+use parse_error::ErrorPolicy;
+use pathexpression::{PathExpression, PathMatchOptions};
+use process::Consumer;
+
+/// How long to wait for more data once the end of the file has been reached
+const POLL_INTERVAL_MILLIS: u64 = 500;
+/// How often to look for a newer file to follow, or for the current one having been replaced
+const ROTATION_CHECK_SECONDS: u64 = 5;
+/// How long after a timeslice ends before it's treated as complete, since servers may log a
+/// request some time after the timestamp it's logged with
+const GRACE_SECONDS: i64 = 30;
+/// The most to read from the file in one go
+const CHUNK_BYTES: u64 = 1024 * 1024;
+
+/// What identifies a file whatever its name, so that a file moved aside by log rotation is still
+/// recognised: the device and inode numbers, or the creation time where there are no inodes
+#[derive(Debug,Clone,Copy,Hash,Eq,PartialEq)]
+struct FileId(u64, u64);
+
+#[cfg(unix)]
+fn file_id(meta: &fs::Metadata) -> FileId {
+    use std::os::unix::fs::MetadataExt;
+    FileId(meta.dev(), meta.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(meta: &fs::Metadata) -> FileId {
+    let created = meta.created().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok() )
+        .unwrap_or(Duration::from_secs(0));
+    FileId(created.as_secs(), created.subsec_nanos() as u64)
+}
+
+/// A file currently matching the expression
+struct Found {
+    path: PathBuf,
+    id: FileId,
+    len: u64,
+    modified: SystemTime,
+}
+
+/// The uncompressed files matching the expression
+fn matching_files(exp: &PathExpression) -> Vec<Found> {
+    exp.list_local(PathMatchOptions::new())
+        .filter_map(|entry| entry.ok() )
+        .filter(|path| path.extension().and_then(|e| e.to_str() ) != Some("gz") )
+        .filter_map(|path| {
+            let meta = match fs::metadata(&path) {
+                Ok(meta) => meta,
+                Err(_) => return None,
+            };
+            meta.modified().ok().map(|modified| Found {
+                id: file_id(&meta),
+                len: meta.len(),
+                modified: modified,
+                path: path,
+            })
+        })
+        .collect()
+}
+
+/// The most recently modified of the files, taken to be the one the server is writing to.  Ties
+/// go to the last by name, so the choice doesn't depend on the order the files were listed in.
+fn newest<'f, I: Iterator<Item=&'f Found>>(files: I) -> Option<&'f Found> {
+    files.max_by(|a, b| (a.modified, &a.path).cmp(&(b.modified, &b.path)) )
+}
+
+/// The file currently being followed
+struct Tail {
+    path: PathBuf,
+    id: FileId,
+    file: File,
+    offset: u64,
+    /// the start of a line whose end hasn't been written yet
+    partial: Vec<u8>,
+}
+
+impl Tail {
+    /// Open the file to read from `offset`, or from its end if that's `None`
+    fn open(path: PathBuf, offset: Option<u64>) -> Result<Tail, Error> {
+        let mut file = File::open(&path)?;
+        let id = file_id(&file.metadata()?);
+        let offset = match offset {
+            Some(offset) => file.seek(SeekFrom::Start(offset))?,
+            None => file.seek(SeekFrom::End(0))?,
+        };
+        Ok(Tail {
+            path: path,
+            id: id,
+            file: file,
+            offset: offset,
+            partial: Vec::new(),
+        })
+    }
+
+    /// Process any complete lines that have been written since the last call, returning the
+    /// number of bytes read
+    fn read_lines(&mut self, settings: &ParseSettings, consumer: &mut Consumer) -> Result<u64, Error> {
+        let len = (&mut self.file).take(CHUNK_BYTES).read_to_end(&mut self.partial)? as u64;
+        self.offset += len;
+        if let Some(end) = self.partial.iter().rposition(|b| *b == b'\n' ) {
+            let rest = self.partial.split_off(end + 1);
+            self.process(settings, consumer)?;
+            self.partial = rest;
+        }
+        Ok(len)
+    }
+
+    /// Process everything left in the file, including a final line with no line ending
+    fn finish(&mut self, settings: &ParseSettings, consumer: &mut Consumer) -> Result<(), Error> {
+        while self.read_lines(settings, consumer)? > 0 {}
+        self.process(settings, consumer)?;
+        self.partial.clear();
+        Ok(())
+    }
+
+    fn process(&self, settings: &ParseSettings, consumer: &mut Consumer) -> Result<(), Error> {
+        if !self.partial.is_empty() {
+            process_lines(settings, &self.path.display().to_string(), &self.partial[..], consumer)?;
+        }
+        Ok(())
+    }
+
+    /// Whether our file is now shorter than what's been read from it, meaning it has been
+    /// truncated in place.  A different file having taken our file's name doesn't count.
+    fn is_truncated(&self) -> bool {
+        fs::metadata(&self.path).map(|m| file_id(&m) == self.id && m.len() < self.offset ).unwrap_or(false)
+    }
+
+    /// Go back to the start of a truncated file, dropping any incomplete line
+    fn rewind(&mut self) -> Result<(), Error> {
+        self.offset = self.file.seek(SeekFrom::Start(0))?;
+        self.partial.clear();
+        Ok(())
+    }
+}
+
+/// Chooses which of the files matching the expression to follow, and remembers how far each
+/// file already followed was read, so that none is ever read from its start twice
+struct Follower<'a> {
+    exp: &'a PathExpression,
+    tail: Option<Tail>,
+    /// the files that matched at the last check
+    seen: HashSet<(PathBuf, FileId)>,
+    /// the path and offset each file followed before the current one was left at
+    left: HashMap<FileId, (PathBuf, u64)>,
+}
+
+impl<'a> Follower<'a> {
+    /// Start following the most recently modified of the files matching, from its end
+    fn start(exp: &'a PathExpression) -> Result<Follower<'a>, Error> {
+        let files = matching_files(exp);
+        let tail = match newest(files.iter()) {
+            Some(found) => Some(Tail::open(found.path.clone(), None)?),
+            None => None,
+        };
+        Ok(Follower {
+            exp: exp,
+            tail: tail,
+            seen: files.into_iter().map(|f| (f.path, f.id) ).collect(),
+            left: HashMap::new(),
+        })
+    }
+
+    fn read_lines(&mut self, settings: &ParseSettings, consumer: &mut Consumer) -> Result<u64, Error> {
+        match self.tail {
+            Some(ref mut t) => t.read_lines(settings, consumer),
+            None => Ok(0),
+        }
+    }
+
+    /// Look for the logs having been rotated.  Only a file which has appeared since the last
+    /// check is taken to be the server's new log, read from its start, so that several files
+    /// being written at once don't take turns at being followed.  Lines written to a file after
+    /// it was left, e.g. by a server yet to reopen its log, are read from where it was left.
+    fn check(&mut self, settings: &ParseSettings, consumer: &mut Consumer) -> Result<(), Error> {
+        if let Some(ref mut t) = self.tail {
+            if t.is_truncated() {
+                println!("{} was truncated, reading from its start", t.path.display());
+                t.rewind()?;
+            }
+        }
+        let files = matching_files(self.exp);
+        let current = self.tail.as_ref().map(|t| t.id );
+        let next = newest(files.iter().filter(|f| {
+            Some(f.id) != current && !self.left.contains_key(&f.id) && !self.seen.contains(&(f.path.clone(), f.id))
+        })).map(|f| f.path.clone() );
+        if let Some(path) = next {
+            if let Some(mut t) = self.tail.take() {
+                t.finish(settings, consumer)?;
+                self.left.insert(t.id, (t.path, t.offset));
+            }
+            println!("following {}", path.display());
+            self.tail = Some(Tail::open(path, Some(0))?);
+        }
+        for found in files.iter() {
+            if let Some(&mut (ref mut path, ref mut offset)) = self.left.get_mut(&found.id) {
+                *path = found.path.clone();
+                if found.len > *offset {
+                    let mut t = Tail::open(found.path.clone(), Some(*offset))?;
+                    t.finish(settings, consumer)?;
+                    *offset = t.offset;
+                }
+            }
+        }
+        // once a file has gone its inode may be reused for a new one
+        self.left = self.left.drain().filter(|&(id, _)| files.iter().any(|f| f.id == id ) ).collect();
+        self.seen = files.into_iter().map(|f| (f.path, f.id) ).collect();
+        Ok(())
+    }
+}
+
+/// Give `flush` the timeslices completed by `until`, first dropping those which ended more than
+/// `retain_seconds` before then from the live consumer, so that it doesn't grow for ever
+fn flush_completed<F>(live: &Mutex<Consumer>, until: i64, retain_seconds: i64, flush: &mut F) -> Result<(), Error>
+    where F: FnMut(Consumer) -> Result<(), Error>
+{
+    let closed = {
+        let mut live = live.lock().unwrap();
+        let kept = live.between(until - retain_seconds, i64::MAX);
+        *live = kept;
+        live.between(i64::MIN, until)
+    };
+    flush(closed)
+}
+
+/// Follow the log file currently being written, starting from its end, and moving on to the
+/// next file when the logs are rotated.  Each time one or more timeslices of width
+/// `flush_seconds` complete, `flush` is given the results for the timeslices completed within
+/// the last `retain_seconds`.  Records are added to the `live` consumer, which others may read
+/// in the meantime.  Only returns if there's an error.
+pub fn follow<F>(exp: &PathExpression, settings: &ParseSettings, live: Arc<Mutex<Consumer>>, flush_seconds: i64, retain_seconds: i64, mut flush: F) -> Result<(), Error>
+    where F: FnMut(Consumer) -> Result<(), Error>
+{
+    // there's no whole file to give up on
+    let mut settings = settings.clone();
+    settings.policy = ErrorPolicy::Skip;
+    let mut follower = Follower::start(exp)?;
+    match follower.tail {
+        Some(ref t) => println!("following {}", t.path.display()),
+        None => println!("pattern did not match: {:?}, waiting for a matching file", exp),
+    }
+    let mut flushed = completed_until(flush_seconds);
+    let mut last_check = Instant::now();
+    loop {
+        let len = follower.read_lines(&settings, &mut live.lock().unwrap())?;
+        if last_check.elapsed() >= Duration::from_secs(ROTATION_CHECK_SECONDS) {
+            last_check = Instant::now();
+            follower.check(&settings, &mut live.lock().unwrap())?;
+        }
+        let until = completed_until(flush_seconds);
+        if until > flushed {
+            flush_completed(&live, until, retain_seconds, &mut flush)?;
+            flushed = until;
+        }
+        if len == 0 {
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MILLIS));
+        }
+    }
+}
+
+/// The end of the most recent `seconds`-wide timeslice which is complete
+pub fn completed_until(seconds: i64) -> i64 {
+    let now = time::get_time().sec - GRACE_SECONDS;
+    (now / seconds) * seconds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+    use std::path::Path;
+    use classify::Classifier;
+    use filter::Period;
+    use output::{Cell, ReportOptions};
+    use parse_access_log::{LogParser, Record};
+    use process::ConsumerConfig;
+    use time::Timespec;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("whatf-follow-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn settings() -> ParseSettings {
+        ParseSettings {
+            parser: LogParser::new("nginx", Some("[$time_local] $status")).unwrap(),
+            policy: ErrorPolicy::Skip,
+            filter: None,
+            period: Period::default(),
+            skip_files_outside_period: false,
+        }
+    }
+
+    fn consumer() -> Consumer {
+        Consumer::new(Arc::new(ConsumerConfig {
+            classifier: Classifier::default_rules(),
+            slice_seconds: 60,
+            query: None,
+            top: None,
+            unique: None,
+            breakdown: None,
+        }))
+    }
+
+    fn append(path: &Path, lines: usize) {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(path).unwrap();
+        for _ in 0..lines {
+            file.write_all(b"[03/Feb/2017:11:20:34 +0000] 200\n").unwrap();
+        }
+    }
+
+    fn options() -> ReportOptions {
+        ReportOptions {
+            zero_fill: false,
+            slice_end: false,
+            percentiles: Vec::new(),
+            buckets: None,
+            status_classes: false,
+            anomalies: None,
+        }
+    }
+
+    /// The number of requests in each timeslice
+    fn requests(consumer: &Consumer) -> Vec<(i64, i64)> {
+        consumer.status_table(&options()).rows.iter().map(|row| {
+            let count = row[1..].iter().map(|c| match *c {
+                Cell::Int(n) => n,
+                _ => 0,
+            }).sum();
+            match row[0] {
+                Cell::Time(ts) => (ts, count),
+                _ => panic!("no timeslice"),
+            }
+        }).collect()
+    }
+
+    fn total(consumer: &Consumer) -> i64 {
+        requests(consumer).iter().map(|&(_, n)| n ).sum()
+    }
+
+    #[test]
+    fn files_written_together() {
+        let dir = temp_dir("together");
+        append(&dir.join("a.log"), 1);
+        append(&dir.join("b.log"), 1);
+        let exp = PathExpression::parse(&format!("{}/{{file}}", dir.display())).unwrap();
+        let (settings, mut consumer) = (settings(), consumer());
+        let mut follower = Follower::start(&exp).unwrap();
+        let followed = follower.tail.as_ref().unwrap().path.clone();
+        for _ in 0..3 {
+            append(&dir.join("a.log"), 1);
+            append(&dir.join("b.log"), 1);
+            follower.read_lines(&settings, &mut consumer).unwrap();
+            follower.check(&settings, &mut consumer).unwrap();
+            assert_eq!(followed, follower.tail.as_ref().unwrap().path);
+        }
+        // neither file is new, so there's no switching between them and nothing is read twice
+        assert_eq!(3, total(&consumer));
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = temp_dir("rotation");
+        let (log, rotated) = (dir.join("access.log"), dir.join("access.log.1"));
+        append(&log, 2);
+        let exp = PathExpression::parse(&format!("{}/{{file}}", dir.display())).unwrap();
+        let (settings, mut consumer) = (settings(), consumer());
+        let mut follower = Follower::start(&exp).unwrap();
+        append(&log, 1);
+        follower.read_lines(&settings, &mut consumer).unwrap();
+        assert_eq!(1, total(&consumer));
+
+        // written after the rename, but before the server moved on to the new file
+        fs::rename(&log, &rotated).unwrap();
+        append(&rotated, 1);
+        append(&log, 2);
+        follower.check(&settings, &mut consumer).unwrap();
+        assert_eq!(log, follower.tail.as_ref().unwrap().path);
+        follower.read_lines(&settings, &mut consumer).unwrap();
+        assert_eq!(4, total(&consumer));
+
+        // the old file is picked up from where it was left, and the new one isn't read again
+        append(&rotated, 1);
+        follower.check(&settings, &mut consumer).unwrap();
+        follower.read_lines(&settings, &mut consumer).unwrap();
+        assert_eq!(log, follower.tail.as_ref().unwrap().path);
+        assert_eq!(5, total(&consumer));
+    }
+
+    #[test]
+    fn truncation() {
+        let dir = temp_dir("truncation");
+        let log = dir.join("access.log");
+        append(&log, 3);
+        let exp = PathExpression::parse(&format!("{}/{{file}}", dir.display())).unwrap();
+        let (settings, mut consumer) = (settings(), consumer());
+        let mut follower = Follower::start(&exp).unwrap();
+        append(&log, 1);
+        follower.read_lines(&settings, &mut consumer).unwrap();
+        File::create(&log).unwrap();
+        append(&log, 1);
+        follower.check(&settings, &mut consumer).unwrap();
+        follower.read_lines(&settings, &mut consumer).unwrap();
+        assert_eq!(2, total(&consumer));
+    }
+
+    #[test]
+    fn flush_prunes_old_timeslices() {
+        let mut live = consumer();
+        for ts in vec![0, 60, 120, 180] {
+            let mut r = Record::default();
+            r.timestamp = Timespec::new(ts, 0);
+            r.response_status = "200".to_string();
+            live.handle(r);
+        }
+        let live = Mutex::new(live);
+        let mut flushed = Vec::new();
+        flush_completed(&live, 180, 120, &mut |closed| {
+            flushed = requests(&closed);
+            Ok(())
+        }).unwrap();
+        assert_eq!(vec![(60, 1), (120, 1)], flushed);
+        assert_eq!(vec![(60, 1), (120, 1), (180, 1)], requests(&live.lock().unwrap()));
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::i64;
+use std::time::UNIX_EPOCH;
+use serde_json;
+use rusoto::s3;
+use filter::Period;
+use parse_error::FileReport;
+use process::{Consumer, ConsumerConfig};
+
+const INDEX_FILE: &'static str = "ledger.json";
+const PARTIALS_DIR: &'static str = "partials";
+
+/// A file which has already been processed
+#[derive(Serialize,Deserialize,Debug,Clone)]
+struct Entry {
+    /// local path, or `s3://bucket/key`
+    key: String,
+    /// identifies the version of the file that was processed, e.g. from its size and mtime
+    fingerprint: String,
+    /// name of the snapshot holding the results for this file, within the partials directory
+    snapshot: String,
+    lines: u64,
+    rejected: u64,
+    filtered: u64,
+    /// the first and last timeslices with results, if there were any
+    first_timeslice: Option<i64>,
+    last_timeslice: Option<i64>,
+}
+
+#[derive(Serialize,Deserialize,Debug)]
+struct Index {
+    /// describes the options in effect when the entries were recorded, since any change to
+    /// them would make the saved results wrong
+    settings: String,
+    entries: Vec<Entry>,
+}
+
+/// A record of the files that have been processed, along with the results from each, so that a
+/// rerun only needs to process files which are new or have changed since.  The results are kept
+/// for the whole of each file, whatever the `--period`, and cut down to the period as they're
+/// used, so that a period which moves from one run to the next doesn't make them useless.
+pub struct Ledger {
+    dir: PathBuf,
+    settings: String,
+    period: Period,
+    entries: Mutex<HashMap<String, Entry>>,
+    /// the files looked up or recorded during this run
+    seen: Mutex<HashSet<String>>,
+}
+
+/// The start and end of the period, as bounds for `Consumer::between()`
+fn period_bounds(period: &Period) -> (i64, i64) {
+    (period.from.map(|t| t.sec ).unwrap_or(i64::MIN), period.to.map(|t| t.sec ).unwrap_or(i64::MAX))
+}
+
+impl Ledger {
+    /// Open the ledger in the given directory, creating it if needed.  `settings` describes the
+    /// options affecting the results, apart from the `period` they're to be cut down to; if
+    /// they've changed since the ledger was last saved, its existing entries are ignored.
+    pub fn open(dir: &Path, settings: &str, period: &Period) -> Result<Arc<Ledger>, Error> {
+        fs::create_dir_all(dir.join(PARTIALS_DIR))?;
+        let index_path = dir.join(INDEX_FILE);
+        let mut entries = HashMap::new();
+        if index_path.exists() {
+            let index: Index = serde_json::from_reader(BufReader::new(File::open(&index_path)?))
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", index_path.display(), e)))?;
+            if index.settings == settings {
+                for entry in index.entries {
+                    entries.insert(entry.key.clone(), entry);
+                }
+            } else {
+                println!("Options have changed since the ledger in {} was written, so all files will be reprocessed", dir.display());
+            }
+        }
+        Ok(Arc::new(Ledger {
+            dir: dir.to_path_buf(),
+            settings: settings.to_string(),
+            period: period.clone(),
+            entries: Mutex::new(entries),
+            seen: Mutex::new(HashSet::new()),
+        }))
+    }
+
+    /// The saved results for the given file within the period, if this version of it has been
+    /// processed before
+    pub fn cached(&self, key: &str, fingerprint: &str, config: Arc<ConsumerConfig>) -> Option<(Consumer, FileReport)> {
+        self.seen.lock().unwrap().insert(key.to_string());
+        let entry = match self.entries.lock().unwrap().get(key) {
+            Some(e) if e.fingerprint == fingerprint => e.clone(),
+            _ => return None,
+        };
+        match Consumer::load_snapshot(config, &self.dir.join(PARTIALS_DIR).join(&entry.snapshot)) {
+            Ok(consumer) => {
+                let mut report = FileReport::new(key);
+                report.lines = entry.lines;
+                report.rejected = entry.rejected;
+                report.filtered = entry.filtered;
+                Some((self.in_period(consumer), report))
+            },
+            Err(e) => {
+                println!("Ignoring ledger entry for {}: {}", key, e);
+                None
+            },
+        }
+    }
+
+    /// Cut the results for a whole file down to the timeslices wholly within the period
+    pub fn in_period(&self, consumer: Consumer) -> Consumer {
+        if !self.period.is_bounded() {
+            return consumer;
+        }
+        let (start, end) = period_bounds(&self.period);
+        consumer.between(start, end)
+    }
+
+    /// Save the results of successfully processing the whole of the given file
+    pub fn record(&self, key: &str, fingerprint: &str, consumer: &Consumer, report: &FileReport) -> Result<(), Error> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        fingerprint.hash(&mut hasher);
+        let snapshot = format!("{:016x}.snap", hasher.finish());
+        consumer.save_snapshot(&self.dir.join(PARTIALS_DIR).join(&snapshot))?;
+        let range = consumer.timeslice_range();
+        let entry = Entry {
+            key: key.to_string(),
+            fingerprint: fingerprint.to_string(),
+            snapshot: snapshot,
+            lines: report.lines,
+            rejected: report.rejected,
+            filtered: report.filtered,
+            first_timeslice: range.map(|(first, _)| first ),
+            last_timeslice: range.map(|(_, last)| last ),
+        };
+        self.seen.lock().unwrap().insert(key.to_string());
+        let old = self.entries.lock().unwrap().insert(key.to_string(), entry);
+        if let Some(old) = old {
+            if old.fingerprint != fingerprint {
+                let _ = fs::remove_file(self.dir.join(PARTIALS_DIR).join(&old.snapshot));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a file that wasn't looked up during this run is gone for good: a local file that no
+    /// longer exists, or an object whose results all lie within the period, since it would have
+    /// been listed if it was still there
+    fn is_gone(&self, entry: &Entry) -> bool {
+        if !entry.key.starts_with("s3://") {
+            return !Path::new(&entry.key).exists();
+        }
+        let (start, end) = period_bounds(&self.period);
+        match (entry.first_timeslice, entry.last_timeslice) {
+            (Some(first), Some(last)) => first >= start && last < end,
+            _ => !self.period.is_bounded(),
+        }
+    }
+
+    /// Write out the index of processed files, dropping those which are gone along with their
+    /// results.  The old index is only replaced once the new one is complete.
+    pub fn save(&self) -> Result<(), Error> {
+        {
+            let seen = self.seen.lock().unwrap();
+            let mut entries = self.entries.lock().unwrap();
+            let gone = entries.values()
+                .filter(|e| !seen.contains(&e.key) && self.is_gone(e) )
+                .map(|e| e.key.clone() )
+                .collect::<Vec<String>>();
+            for key in gone {
+                if let Some(entry) = entries.remove(&key) {
+                    let _ = fs::remove_file(self.dir.join(PARTIALS_DIR).join(&entry.snapshot));
+                }
+            }
+        }
+        let mut entries = self.entries.lock().unwrap().values().cloned().collect::<Vec<Entry>>();
+        entries.sort_by(|a, b| a.key.cmp(&b.key) );
+        let index = Index {
+            settings: self.settings.clone(),
+            entries: entries,
+        };
+        let tmp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        {
+            let mut out = BufWriter::new(File::create(&tmp)?);
+            serde_json::to_writer(&mut out, &index).map_err(|e| Error::new(ErrorKind::Other, e))?;
+            out.flush()?;
+        }
+        fs::rename(tmp, self.dir.join(INDEX_FILE))
+    }
+}
+
+/// Identifies the current version of a local file
+pub fn file_fingerprint(path: &Path) -> Result<String, Error> {
+    let meta = fs::metadata(path)?;
+    let mtime = meta.modified()?.duration_since(UNIX_EPOCH).map_err(|e| Error::new(ErrorKind::Other, e))?;
+    Ok(format!("size={} mtime={}.{:09}", meta.len(), mtime.as_secs(), mtime.subsec_nanos()))
+}
+
+/// Identifies the current version of an S3 object, from its listing
+pub fn object_fingerprint(obj: &s3::Object) -> String {
+    format!("etag={} size={}",
+            obj.e_tag.as_ref().map(|t| &t[..] ).unwrap_or(""),
+            obj.size.map(|s| s.to_string() ).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use time::Timespec;
+    use classify::Classifier;
+    use parse_access_log::Record;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("whatf-ledger-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn config() -> Arc<ConsumerConfig> {
+        Arc::new(ConsumerConfig {
+            classifier: Classifier::default_rules(),
+            slice_seconds: 60,
+            query: None,
+            top: None,
+            unique: None,
+            breakdown: None,
+        })
+    }
+
+    /// Results with a request in each of the given timeslices
+    fn results(timeslices: &[i64]) -> Consumer {
+        let mut consumer = Consumer::new(config());
+        for ts in timeslices {
+            let mut r = Record::default();
+            r.timestamp = Timespec::new(*ts, 0);
+            r.response_status = "200".to_string();
+            consumer.handle(r);
+        }
+        consumer
+    }
+
+    fn report(key: &str) -> FileReport {
+        let mut report = FileReport::new(key);
+        report.lines = 2;
+        report
+    }
+
+    #[test]
+    fn reuse_and_invalidation() {
+        let dir = temp_dir("reuse");
+        let ledger = Ledger::open(&dir, "a", &Period::default()).unwrap();
+        ledger.record("s3://b/k", "etag=1", &results(&[0, 60]), &report("s3://b/k")).unwrap();
+        ledger.save().unwrap();
+
+        let ledger = Ledger::open(&dir, "a", &Period::default()).unwrap();
+        let (cached, report) = ledger.cached("s3://b/k", "etag=1", config()).unwrap();
+        assert_eq!(Some((0, 60)), cached.timeslice_range());
+        assert_eq!(2, report.lines);
+        // the file has changed
+        assert!(ledger.cached("s3://b/k", "etag=2", config()).is_none());
+        // the options have changed
+        let ledger = Ledger::open(&dir, "b", &Period::default()).unwrap();
+        assert!(ledger.cached("s3://b/k", "etag=1", config()).is_none());
+    }
+
+    #[test]
+    fn moving_period() {
+        let dir = temp_dir("period");
+        let ledger = Ledger::open(&dir, "a", &Period::default()).unwrap();
+        ledger.record("s3://b/k", "etag=1", &results(&[0, 60, 120]), &report("s3://b/k")).unwrap();
+        ledger.save().unwrap();
+
+        let period = Period {
+            from: Some(Timespec::new(60, 0)),
+            to: Some(Timespec::new(180, 0)),
+        };
+        let ledger = Ledger::open(&dir, "a", &period).unwrap();
+        let (cached, _) = ledger.cached("s3://b/k", "etag=1", config()).unwrap();
+        assert_eq!(Some((60, 120)), cached.timeslice_range());
+    }
+
+    #[test]
+    fn pruning() {
+        let dir = temp_dir("pruning");
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("access.log");
+        File::create(&log).unwrap();
+        let key = log.display().to_string();
+        let ledger = Ledger::open(&dir, "a", &Period::default()).unwrap();
+        ledger.record(&key, "size=0", &results(&[0]), &report(&key)).unwrap();
+        ledger.record("s3://b/k", "etag=1", &results(&[0]), &report("s3://b/k")).unwrap();
+        ledger.save().unwrap();
+
+        // the local file is still there, and the object wouldn't have been listed for this period
+        let period = Period {
+            from: Some(Timespec::new(3600, 0)),
+            to: None,
+        };
+        Ledger::open(&dir, "a", &period).unwrap().save().unwrap();
+        let ledger = Ledger::open(&dir, "a", &Period::default()).unwrap();
+        assert!(ledger.cached(&key, "size=0", config()).is_some());
+        assert!(ledger.cached("s3://b/k", "etag=1", config()).is_some());
+
+        // the local file has gone, and a listing of everything didn't include the object
+        fs::remove_file(&log).unwrap();
+        Ledger::open(&dir, "a", &Period::default()).unwrap().save().unwrap();
+        let ledger = Ledger::open(&dir, "a", &Period::default()).unwrap();
+        assert!(ledger.cached(&key, "size=0", config()).is_none());
+        assert!(ledger.cached("s3://b/k", "etag=1", config()).is_none());
+        assert_eq!(0, fs::read_dir(dir.join(PARTIALS_DIR)).unwrap().count());
+    }
+}
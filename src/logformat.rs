@@ -0,0 +1,591 @@
+use std::str::from_utf8;
+use std::io::Error;
+use std::io::ErrorKind;
+use time::strptime;
+use time::Timespec;
+use parse_access_log::Record;
+use parse_error::LineError;
+use urlparse;
+
+/// The default Apache `LogFormat`, as used by the edge servers whatf was first written for
+pub const DEFAULT_HTTPD_LOG_FORMAT: &'static str = "%t %h %l %u \"%r\" %>s %b \"%{Referer}i\" \"%{User-Agent}i\" \"%{Host}i\" %D \"%{X-Forwarded-For}i\" %{local}p %{cache-status}e %R";
+
+/// nginx's predefined `combined` log_format
+pub const DEFAULT_NGINX_LOG_FORMAT: &'static str = "$remote_addr - $remote_user [$time_local] \"$request\" $status $body_bytes_sent \"$http_referer\" \"$http_user_agent\"";
+
+/// Identifies which `Record` field (if any) a value within a log line will populate, and how the
+/// text of that value is to be interpreted.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Field {
+    /// a timestamp to be parsed with `strptime()` using the given format
+    Timestamp(String),
+    EpochSeconds,
+    EpochMillis,
+    EpochMicros,
+    /// seconds since the epoch with a fractional part, e.g. `1490000000.123`
+    EpochFractionalSeconds,
+    RemoteHost,
+    RemoteLogname,
+    RemoteUser,
+    /// the whole first line of the request, e.g. `GET /foo HTTP/1.1`
+    RequestLine,
+    RequestMethod,
+    /// path plus any query-string
+    RequestUri,
+    RequestPath,
+    /// query-string including the leading `?`
+    RequestQuery,
+    /// query-string without the leading `?`
+    RequestArgs,
+    RequestProto,
+    Status,
+    Bytes,
+    ServiceTimeSeconds,
+    ServiceTimeMillis,
+    ServiceTimeMicros,
+    /// seconds with a fractional part, e.g. `0.123`
+    ServiceTimeFractionalSeconds,
+    /// a list of fractional-second times, one for each upstream server contacted
+    UpstreamResponseTime,
+    Referer,
+    UserAgent,
+    Host,
+    ForwardedFor,
+    LocalPort,
+    CacheStatus,
+    Handler,
+    /// a value that appears in the log line, but which whatf has no use for
+    Ignored,
+}
+
+impl Field {
+    /// The name of the `Record` field that this populates
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Field::Timestamp(_) | Field::EpochSeconds | Field::EpochMillis | Field::EpochMicros | Field::EpochFractionalSeconds => "timestamp",
+            Field::RemoteHost => "remote_host",
+            Field::RemoteLogname => "remote_logname",
+            Field::RemoteUser => "remote_user",
+            Field::RequestLine => "request_line",
+            Field::RequestMethod => "request_method",
+            Field::RequestUri | Field::RequestPath | Field::RequestQuery | Field::RequestArgs => "request_uri",
+            Field::RequestProto => "request_proto",
+            Field::Status => "response_status",
+            Field::Bytes => "response_bytes",
+            Field::ServiceTimeSeconds | Field::ServiceTimeMillis | Field::ServiceTimeMicros | Field::ServiceTimeFractionalSeconds | Field::UpstreamResponseTime => "response_time_micros",
+            Field::Referer => "request_referer",
+            Field::UserAgent => "request_useragent",
+            Field::Host => "request_host",
+            Field::ForwardedFor => "request_forwarded_for",
+            Field::LocalPort => "request_local_port",
+            Field::CacheStatus => "response_cache_status",
+            Field::Handler => "request_handler",
+            Field::Ignored => "ignored",
+        }
+    }
+
+    /// Interpret `value` according to this field type, storing the result into the given record
+    pub fn apply(&self, value: &[u8], r: &mut Record) -> Result<(), Error> {
+        match *self {
+            Field::Ignored => (),
+            Field::Timestamp(ref fmt) => {
+                let s = string_from_slice(value)?;
+                let tm = strptime(s, fmt).map_err(|e| invalid_data(&format!("timestamp {:?}: {}", s, e)))?;
+                r.timestamp = tm.to_timespec();
+            },
+            Field::EpochSeconds => r.timestamp = Timespec::new(parse_num(value, "timestamp")?, 0),
+            Field::EpochMillis => {
+                let ms: i64 = parse_num(value, "timestamp")?;
+                r.timestamp = Timespec::new(ms / 1000, ((ms % 1000) * 1000000) as i32);
+            },
+            Field::EpochMicros => {
+                let us: i64 = parse_num(value, "timestamp")?;
+                r.timestamp = Timespec::new(us / 1000000, ((us % 1000000) * 1000) as i32);
+            },
+            Field::EpochFractionalSeconds => {
+                let us = parse_fractional_micros(value).ok_or_else(|| invalid_data(&format!("timestamp: {:?}", String::from_utf8_lossy(value))))?;
+                r.timestamp = Timespec::new((us / 1000000) as i64, ((us % 1000000) * 1000) as i32);
+            },
+            Field::RemoteHost => r.remote_host = string_from_slice(value)?.to_string(),
+            Field::RemoteLogname => r.remote_logname = string_from_slice(value)?.to_string(),
+            Field::RemoteUser => r.remote_user = string_from_slice(value)?.to_string(),
+            Field::RequestLine => {
+                let mut itr = string_from_slice(value)?.split_whitespace();
+                r.request_method = itr.next().ok_or(invalid_data("method"))?.to_string();
+                r.request_uri = decode_uri(itr.next().ok_or(invalid_data("uri"))?);
+                r.request_proto = itr.next().ok_or(invalid_data("protocol"))?.to_string();
+            },
+            Field::RequestMethod => r.request_method = string_from_slice(value)?.to_string(),
+            Field::RequestUri => r.request_uri = decode_uri(string_from_slice(value)?),
+            Field::RequestPath => {
+                // the query-string may already have been seen, if it was logged first
+                let path = decode_uri(string_from_slice(value)?);
+                r.request_uri.insert_str(0, &path);
+            },
+            Field::RequestQuery => r.request_uri.push_str(string_from_slice(value)?),
+            Field::RequestArgs => {
+                if value != &b"-"[..] && !value.is_empty() {
+                    r.request_uri.push('?');
+                    r.request_uri.push_str(string_from_slice(value)?);
+                }
+            },
+            Field::RequestProto => r.request_proto = string_from_slice(value)?.to_string(),
+            Field::Status => r.response_status = string_from_slice(value)?.to_string(),
+            Field::Bytes => {
+                r.response_bytes = match value {
+                    b"-" => None,
+                    _ => Some(parse_num(value, "bytes")?),
+                };
+            },
+            Field::ServiceTimeSeconds => r.response_time_micros = parse_num::<u64>(value, "service-time")? * 1000000,
+            Field::ServiceTimeMillis => r.response_time_micros = parse_num::<u64>(value, "service-time")? * 1000,
+            Field::ServiceTimeMicros => r.response_time_micros = parse_num(value, "service-time")?,
+            Field::ServiceTimeFractionalSeconds => {
+                r.response_time_micros = parse_fractional_micros(value).ok_or_else(|| invalid_data(&format!("service-time: {:?}", String::from_utf8_lossy(value))))?;
+            },
+            Field::UpstreamResponseTime => {
+                // e.g. "0.010, 0.020 : 0.005" when nginx tried more than one upstream, or "-"
+                // when the request was never passed upstream
+                let mut total = 0;
+                for t in string_from_slice(value)?.split(|c: char| c == ',' || c == ':') {
+                    let t = t.trim();
+                    if t != "-" {
+                        total += parse_fractional_micros(t.as_bytes()).ok_or_else(|| invalid_data(&format!("upstream-time: {:?}", t)))?;
+                    }
+                }
+                r.response_time_micros = total;
+            },
+            Field::Referer => r.request_referer = string_from_slice(value)?.to_string(),
+            Field::UserAgent => r.request_useragent = string_from_slice(value)?.to_string(),
+            Field::Host => r.request_host = string_from_slice(value)?.to_string(),
+            Field::ForwardedFor => r.request_forwarded_for = string_from_slice(value)?.to_string(),
+            Field::LocalPort => r.request_local_port = parse_num(value, "port")?,
+            Field::CacheStatus => r.response_cache_status = string_from_slice(value)?.to_string(),
+            Field::Handler => r.request_handler = string_from_slice(value)?.to_string(),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug,Clone)]
+enum Item {
+    Literal(Vec<u8>),
+    Field(Field),
+}
+
+/// A compiled description of the layout of each line in an access log, alternating between
+/// literal text and the fields that are to be extracted into a `Record`.
+#[derive(Debug,Clone)]
+pub struct Layout {
+    items: Vec<Item>,
+}
+
+impl Layout {
+    fn new(items: Vec<Item>) -> Result<Layout, Error> {
+        for pair in items.windows(2) {
+            if let (&Item::Field(ref a), &Item::Field(ref b)) = (&pair[0], &pair[1]) {
+                return Err(invalid_input(&format!("fields {:?} and {:?} must be separated by some literal text", a, b)));
+            }
+        }
+        Ok(Layout { items: items })
+    }
+
+    /// Compile an Apache httpd `mod_log_config` format string, e.g. `%h %l %u %t "%r" %>s %b`
+    pub fn httpd(format: &str) -> Result<Layout, Error> {
+        let mut items = Vec::new();
+        let mut lit = String::new();
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    match chars.next() {
+                        Some('t') => lit.push('\t'),
+                        Some('n') => lit.push('\n'),
+                        Some(other) => lit.push(other),
+                        None => lit.push('\\'),
+                    }
+                },
+                '%' => {
+                    if let Some(&'%') = chars.peek() {
+                        chars.next();
+                        lit.push('%');
+                        continue;
+                    }
+                    // status-code conditions and the original/final request modifiers do not
+                    // change how the value looks in the log
+                    while let Some(&m) = chars.peek() {
+                        if m == '<' || m == '>' || m == '!' || m == ',' || m.is_digit(10) {
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    let arg = if let Some(&'{') = chars.peek() {
+                        chars.next();
+                        let mut a = String::new();
+                        loop {
+                            match chars.next() {
+                                Some('}') => break,
+                                Some(ch) => a.push(ch),
+                                None => return Err(invalid_input("unterminated '{' in LogFormat")),
+                            }
+                        }
+                        Some(a)
+                    } else {
+                        None
+                    };
+                    let directive = chars.next().ok_or(invalid_input("LogFormat ends with '%'"))?;
+                    if directive == 't' && arg.is_none() {
+                        // the default time format includes the surrounding brackets
+                        lit.push('[');
+                        push_field(&mut items, &mut lit, Field::Timestamp("%d/%b/%Y:%H:%M:%S %z".to_string()));
+                        lit.push(']');
+                    } else {
+                        let field = httpd_field(directive, arg.as_ref().map(|a| &a[..]))?;
+                        push_field(&mut items, &mut lit, field);
+                    }
+                },
+                _ => lit.push(c),
+            }
+        }
+        if !lit.is_empty() {
+            items.push(Item::Literal(lit.into_bytes()));
+        }
+        Layout::new(items)
+    }
+
+    /// Compile an nginx `log_format` string, e.g. `$remote_addr [$time_local] "$request" $status`
+    ///
+    /// `$request_time` supplies the service time; if the format only includes
+    /// `$upstream_response_time` then that is used instead.
+    pub fn nginx(format: &str) -> Result<Layout, Error> {
+        let has_request_time = format.contains("$request_time") || format.contains("${request_time}");
+        let mut items = Vec::new();
+        let mut lit = String::new();
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                lit.push(c);
+                continue;
+            }
+            let mut name = String::new();
+            if let Some(&'{') = chars.peek() {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(ch) => name.push(ch),
+                        None => return Err(invalid_input("unterminated '{' in log_format")),
+                    }
+                }
+            } else {
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_alphanumeric() || ch == '_' {
+                        name.push(ch);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+            }
+            if name.is_empty() {
+                return Err(invalid_input("'$' not followed by a variable name in log_format"));
+            }
+            let field = match nginx_field(&name) {
+                Field::UpstreamResponseTime if has_request_time => Field::Ignored,
+                f => f,
+            };
+            push_field(&mut items, &mut lit, field);
+        }
+        if !lit.is_empty() {
+            items.push(Item::Literal(lit.into_bytes()));
+        }
+        Layout::new(items)
+    }
+
+    /// Extract a `Record` from the given line of text, which must not include the line-ending
+    pub fn parse(&self, line: &[u8]) -> Result<Record, LineError> {
+        let mut record = Record::default();
+        let mut idx = 0;
+        for (i, item) in self.items.iter().enumerate() {
+            match *item {
+                Item::Literal(ref lit) => {
+                    if !line[idx..].starts_with(lit) {
+                        let msg = format!("expected {:?}", String::from_utf8_lossy(lit));
+                        return Err(LineError::layout(self.next_field_name(i), idx, msg));
+                    }
+                    idx += lit.len();
+                },
+                Item::Field(ref field) => {
+                    let end = self.field_end(i, line, idx);
+                    field.apply(&line[idx..end], &mut record)
+                        .map_err(|e| LineError::invalid_value(field.name(), Some(idx), e) )?;
+                    idx = end;
+                },
+            }
+        }
+        Ok(record)
+    }
+
+    fn next_field_name(&self, i: usize) -> Option<&'static str> {
+        self.items[i..].iter().filter_map(|item| match *item {
+            Item::Field(ref f) => Some(f.name()),
+            _ => None,
+        }).next()
+    }
+
+    /// Find where the value of the field at `items[i]` ends, given that it starts at `start`.
+    /// When the field is surrounded by double-quotes, backslash-escaped characters within the
+    /// value will not terminate it.
+    fn field_end(&self, i: usize, line: &[u8], start: usize) -> usize {
+        let quoted = i > 0 && match self.items[i-1] {
+            Item::Literal(ref lit) => lit.ends_with(b"\""),
+            _ => false,
+        };
+        if let Some(&Item::Literal(ref lit)) = self.items.get(i+1) {
+            let term = lit[0];
+            let mut j = start;
+            while j < line.len() {
+                if quoted && line[j] == b'\\' {
+                    j += 2;
+                    continue;
+                }
+                if line[j] == term {
+                    return j;
+                }
+                j += 1;
+            }
+        }
+        line.len()
+    }
+}
+
+fn push_field(items: &mut Vec<Item>, lit: &mut String, field: Field) {
+    if !lit.is_empty() {
+        items.push(Item::Literal(lit.clone().into_bytes()));
+        lit.clear();
+    }
+    items.push(Item::Field(field));
+}
+
+fn httpd_field(directive: char, arg: Option<&str>) -> Result<Field, Error> {
+    Ok(match directive {
+        'h' | 'a' => Field::RemoteHost,
+        'l' => Field::RemoteLogname,
+        'u' => Field::RemoteUser,
+        't' => {
+            match arg {
+                Some("sec") => Field::EpochSeconds,
+                Some("msec") => Field::EpochMillis,
+                Some("usec") => Field::EpochMicros,
+                Some("msec_frac") | Some("usec_frac") => Field::Ignored,
+                Some(fmt) => {
+                    let fmt = fmt.trim_left_matches("begin:").trim_left_matches("end:");
+                    Field::Timestamp(fmt.to_string())
+                },
+                None => Field::Timestamp("%d/%b/%Y:%H:%M:%S %z".to_string()),
+            }
+        },
+        'r' => Field::RequestLine,
+        'm' => Field::RequestMethod,
+        'U' => Field::RequestPath,
+        'q' => Field::RequestQuery,
+        'H' => Field::RequestProto,
+        's' => Field::Status,
+        'b' | 'B' | 'O' => Field::Bytes,
+        'D' => Field::ServiceTimeMicros,
+        'T' => {
+            match arg {
+                None | Some("s") => Field::ServiceTimeSeconds,
+                Some("ms") => Field::ServiceTimeMillis,
+                Some("us") => Field::ServiceTimeMicros,
+                Some(unit) => return Err(invalid_input(&format!("unsupported %T unit {:?}", unit))),
+            }
+        },
+        'i' => {
+            match arg.map(|a| a.to_lowercase() ) {
+                Some(ref h) if h == "referer" => Field::Referer,
+                Some(ref h) if h == "user-agent" => Field::UserAgent,
+                Some(ref h) if h == "host" => Field::Host,
+                Some(ref h) if h == "x-forwarded-for" => Field::ForwardedFor,
+                _ => Field::Ignored,
+            }
+        },
+        'e' => {
+            match arg {
+                Some("cache-status") => Field::CacheStatus,
+                _ => Field::Ignored,
+            }
+        },
+        'p' => {
+            match arg {
+                None | Some("canonical") | Some("local") => Field::LocalPort,
+                _ => Field::Ignored,
+            }
+        },
+        'R' => Field::Handler,
+        'A' | 'C' | 'f' | 'I' | 'k' | 'L' | 'n' | 'o' | 'P' | 'S' | 'v' | 'V' | 'X' => Field::Ignored,
+        _ => return Err(invalid_input(&format!("unsupported LogFormat directive %{}", directive))),
+    })
+}
+
+fn nginx_field(name: &str) -> Field {
+    match name {
+        "remote_addr" | "realip_remote_addr" => Field::RemoteHost,
+        "remote_user" => Field::RemoteUser,
+        "time_local" => Field::Timestamp("%d/%b/%Y:%H:%M:%S %z".to_string()),
+        "time_iso8601" => Field::Timestamp("%Y-%m-%dT%H:%M:%S%z".to_string()),
+        "msec" => Field::EpochFractionalSeconds,
+        "request" => Field::RequestLine,
+        "request_method" => Field::RequestMethod,
+        "request_uri" => Field::RequestUri,
+        "uri" | "document_uri" => Field::RequestPath,
+        "args" | "query_string" => Field::RequestArgs,
+        "server_protocol" => Field::RequestProto,
+        "status" => Field::Status,
+        "body_bytes_sent" | "bytes_sent" => Field::Bytes,
+        "request_time" => Field::ServiceTimeFractionalSeconds,
+        "upstream_response_time" => Field::UpstreamResponseTime,
+        "http_referer" => Field::Referer,
+        "http_user_agent" => Field::UserAgent,
+        "host" | "http_host" => Field::Host,
+        "http_x_forwarded_for" => Field::ForwardedFor,
+        "server_port" => Field::LocalPort,
+        "upstream_cache_status" => Field::CacheStatus,
+        _ => Field::Ignored,
+    }
+}
+
+/// Parse seconds with an optional fractional part (e.g. `1.5`, `0.123`) into microseconds,
+/// without going via floating point
+pub fn parse_fractional_micros(value: &[u8]) -> Option<u64> {
+    let s = match from_utf8(value) {
+        Ok(s) => s,
+        Err(_) => return None,
+    };
+    let mut i = s.splitn(2, '.');
+    let secs: u64 = match i.next() {
+        Some("") => 0,
+        Some(whole) => match whole.parse() {
+            Ok(n) => n,
+            Err(_) => return None,
+        },
+        None => return None,
+    };
+    let mut micros = 0;
+    if let Some(frac) = i.next() {
+        let mut mult = 100000;
+        for c in frac.chars() {
+            match c.to_digit(10) {
+                Some(d) => micros += d as u64 * mult,
+                None => return None,
+            }
+            mult /= 10;
+        }
+    }
+    Some(secs * 1000000 + micros)
+}
+
+pub fn decode_uri(uri: &str) -> String {
+    urlparse::unquote(uri)
+        .unwrap_or_else(|e| {
+            println!("failed to urldecode request uri {:?}", e);
+            uri.to_string()
+        })
+}
+
+fn parse_num<N: ::std::str::FromStr>(value: &[u8], name: &str) -> Result<N, Error> {
+    string_from_slice(value)?.parse().map_err(|_| invalid_data(&format!("{}: {:?}", name, String::from_utf8_lossy(value))))
+}
+
+pub fn string_from_slice(slice: &[u8]) -> Result<&str,Error> {
+    from_utf8(slice).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+pub fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+fn invalid_input(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_format() {
+        let layout = Layout::httpd(DEFAULT_HTTPD_LOG_FORMAT).unwrap();
+        let line = b"[03/Feb/2017:11:20:34 +0000] 10.0.0.1 - - \"GET /a%20b.m3u8 HTTP/1.1\" 200 1234 \"-\" \"curl/7.1\" \"cdn.example.com\" 4567 \"192.168.0.1\" 80 HIT live-handler";
+        let r = layout.parse(line).unwrap();
+        assert_eq!(1486120834, r.timestamp.sec);
+        assert_eq!("10.0.0.1", r.remote_host);
+        assert_eq!("GET", r.request_method);
+        assert_eq!("/a b.m3u8", r.request_uri);
+        assert_eq!("200", r.response_status);
+        assert_eq!(Some(1234), r.response_bytes);
+        assert_eq!("curl/7.1", r.request_useragent);
+        assert_eq!("cdn.example.com", r.request_host);
+        assert_eq!(4567, r.response_time_micros);
+        assert_eq!("192.168.0.1", r.request_forwarded_for);
+        assert_eq!(80, r.request_local_port);
+        assert_eq!("HIT", r.response_cache_status);
+        assert_eq!("live-handler", r.request_handler);
+    }
+
+    #[test]
+    fn common_format() {
+        let layout = Layout::httpd("%h %l %u %t \"%r\" %>s %b").unwrap();
+        let r = layout.parse(b"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] \"GET /apache_pb.gif HTTP/1.0\" 200 -").unwrap();
+        assert_eq!("frank", r.remote_user);
+        assert_eq!("/apache_pb.gif", r.request_uri);
+        assert_eq!(None, r.response_bytes);
+        assert_eq!(971211336, r.timestamp.sec);
+    }
+
+    #[test]
+    fn escaped_quote() {
+        let layout = Layout::httpd("\"%{User-Agent}i\" %s").unwrap();
+        let r = layout.parse(b"\"foo \\\"bar\\\"\" 404").unwrap();
+        assert_eq!("foo \\\"bar\\\"", r.request_useragent);
+        assert_eq!("404", r.response_status);
+    }
+
+    #[test]
+    fn nginx_combined() {
+        let layout = Layout::nginx(&format!("{} $request_time $upstream_response_time", DEFAULT_NGINX_LOG_FORMAT)).unwrap();
+        let r = layout.parse(b"10.0.0.1 - - [03/Feb/2017:11:20:34 +0000] \"GET /x?y=1 HTTP/1.1\" 304 0 \"-\" \"curl/7.1\" 0.012 0.010").unwrap();
+        assert_eq!(1486120834, r.timestamp.sec);
+        assert_eq!("/x?y=1", r.request_uri);
+        assert_eq!("304", r.response_status);
+        assert_eq!(12000, r.response_time_micros);
+    }
+
+    #[test]
+    fn nginx_upstream_time() {
+        let layout = Layout::nginx("$status ${upstream_response_time}s").unwrap();
+        assert_eq!(30500, layout.parse(b"200 0.010, 0.0205s").unwrap().response_time_micros);
+        assert_eq!(0, layout.parse(b"200 -s").unwrap().response_time_micros);
+    }
+
+    #[test]
+    fn fractional_micros() {
+        assert_eq!(Some(1500000), parse_fractional_micros(b"1.5"));
+        assert_eq!(Some(123), parse_fractional_micros(b"0.000123"));
+        assert_eq!(Some(2000000), parse_fractional_micros(b"2"));
+        assert_eq!(None, parse_fractional_micros(b"x"));
+    }
+
+    #[test]
+    fn adjacent_fields() {
+        assert!(Layout::httpd("%h%u").is_err());
+    }
+
+    #[test]
+    fn mismatch() {
+        let layout = Layout::httpd("%h [%{sec}t]").unwrap();
+        assert!(layout.parse(b"127.0.0.1 1486120834").is_err());
+        assert_eq!(1486120834, layout.parse(b"127.0.0.1 [1486120834]").unwrap().timestamp.sec);
+    }
+}
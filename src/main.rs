@@ -20,26 +20,61 @@ extern crate serde_derive;
 extern crate tokio_core;
 extern crate futures;
 extern crate xml;
+extern crate serde_json;
 
 mod parse_access_log;
+mod logformat;
+mod parse_aws_log;
+mod parse_error;
 mod process;
 mod pathexpression;
 mod rusoto_workarounds;
 mod datasource;
+mod chunkreader;
+mod classify;
+mod duration;
+mod query;
+mod cidr;
+mod filter;
+mod output;
+mod snapshot;
+mod ledger;
+mod follow;
+mod server;
+mod topn;
+mod unique;
+mod bytes;
+mod cache;
+mod breakdown;
+mod alert;
+mod anomaly;
 
 use std::path::Path;
 use std::path::PathBuf;
 use std::fs::File;
+use std::io::BufReader;
+use std::io::{Read, Seek, SeekFrom};
 use flate2::read::GzDecoder;
-use parse_access_log::HttpdAccessLogParser;
-use process::Consumer;
-use std::time::{Instant, Duration};
+use parse_access_log::{ParseSettings, LineParser, process_lines};
+use parse_error::{FileReport, ErrorSummary};
+use process::{Consumer, ConsumerConfig};
+use query::Query;
+use topn::TopConfig;
+use unique::UniqueConfig;
+use breakdown::BreakdownConfig;
+use anomaly::AnomalyConfig;
+use classify::Classifier;
+use datasource::Datasources;
+use ledger::Ledger;
+use filter::{Filter, Period};
+use output::{OutputWriter, ReportOptions, TimeFormat, TimeStyle, Zone, Buckets, DEFAULT_PERCENTILES};
+use std::time::{Instant, Duration, UNIX_EPOCH};
 use std::thread;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use clap::{Arg, App};
-use time::strptime;
+use std::sync::{Arc, Mutex};
+use clap::{Arg, App, ArgMatches};
+use time::{strptime, Timespec};
 use pathexpression::{PathExpression,PathMatchOptions};
 use rusoto::s3;
 use rusoto::{DefaultCredentialsProvider, Region};
@@ -53,15 +88,90 @@ use futures::Stream;
 use futures::Future;
 use futures::future;
 
-fn process_file(name: &Path, consumer: &mut Consumer) -> Result<(), std::io::Error> {
-    let parser = HttpdAccessLogParser::new();
-    let f = File::open(name)?;
+fn process_file(name: &Path, settings: &ParseSettings, consumer: &mut Consumer) -> Result<FileReport, std::io::Error> {
+    let mut f = File::open(name)?;
+    let display_name = name.display().to_string();
     match name.extension().map(|e| e.to_str() ) {
         Some(Some("gz")) => {
+            if settings.skip_files_outside_period && settings.period.is_bounded() && gzip_outside_period(&mut f, settings)? {
+                println!("{}: outside period, skipped", display_name);
+                return Ok(FileReport::new(&display_name));
+            }
             let gunzip = GzDecoder::new(f)?;
-            parser.process_lines(gunzip, consumer)
+            process_lines(settings, &display_name, BufReader::new(gunzip), consumer)
+        },
+        _ => {
+            if settings.skip_files_outside_period && settings.period.is_bounded() && outside_period(&mut f, settings)? {
+                println!("{}: outside period, skipped", display_name);
+                return Ok(FileReport::new(&display_name));
+            }
+            process_lines(settings, &display_name, BufReader::new(f), consumer)
         },
-        _ => parser.process_lines(f, consumer),
+    }
+}
+
+/// How far back from the end of a file to look for its last line
+const TAIL_BYTES: u64 = 64 * 1024;
+
+/// The timestamp of the first record found in some lines from the start of a file
+fn first_timestamp(head: &[u8], settings: &ParseSettings) -> Option<Timespec> {
+    let mut parser = settings.parser.clone();
+    head.split(|b| *b == b'\n' )
+        .filter_map(|line| parser.parse_line(trim_line_end(line)).ok().and_then(|r| r) )
+        .map(|r| r.timestamp )
+        .next()
+}
+
+/// Check the timestamps of the first and last lines of a file, on the assumption that the
+/// lines in between are in time order.  The file is left positioned at its start.
+fn outside_period(f: &mut File, settings: &ParseSettings) -> Result<bool, std::io::Error> {
+    let len = f.metadata()?.len();
+    let mut head = Vec::new();
+    (&mut *f).take(TAIL_BYTES).read_to_end(&mut head)?;
+    let start = if len > TAIL_BYTES { len - TAIL_BYTES } else { 0 };
+    f.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::new();
+    f.read_to_end(&mut tail)?;
+    f.seek(SeekFrom::Start(0))?;
+    let mut parser = settings.parser.clone();
+    let last = tail.split(|b| *b == b'\n' ).rev()
+        .filter_map(|line| parser.parse_line(trim_line_end(line)).ok().and_then(|r| r) )
+        .next();
+    Ok(match (first_timestamp(&head, settings), last) {
+        (Some(first), Some(last)) => settings.period.is_after(&first) || settings.period.is_before(&last.timestamp),
+        _ => false,
+    })
+}
+
+/// As `outside_period()`, for a compressed file, whose end can't be read without decompressing
+/// all of it.  The file's modification time stands in for its last record, which can't have
+/// been written any later.
+fn gzip_outside_period(f: &mut File, settings: &ParseSettings) -> Result<bool, std::io::Error> {
+    let modified = f.metadata()?.modified()?.duration_since(UNIX_EPOCH).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    let mut head = Vec::new();
+    GzDecoder::new(&mut *f)?.take(TAIL_BYTES).read_to_end(&mut head)?;
+    f.seek(SeekFrom::Start(0))?;
+    let modified = Timespec::new(modified.as_secs() as i64, modified.subsec_nanos() as i32);
+    Ok(match first_timestamp(&head, settings) {
+        Some(first) => settings.period.is_after(&first) || settings.period.is_before(&modified),
+        None => settings.period.is_before(&modified),
+    })
+}
+
+/// Whether an S3 object was last modified before the period began, so that all its records
+/// must be from before the period too
+fn object_before_period(obj: &s3::Object, period: &Period) -> bool {
+    // e.g. 2017-02-03T11:20:34.000Z
+    obj.last_modified.as_ref()
+        .and_then(|t| strptime(t.split('.').next().unwrap_or("").trim_right_matches('Z'), "%Y-%m-%dT%H:%M:%S").ok() )
+        .map_or(false, |t| period.is_before(&t.to_timespec()) )
+}
+
+fn trim_line_end(line: &[u8]) -> &[u8] {
+    if line.last() == Some(&b'\r') {
+        &line[..line.len()-1]
+    } else {
+        line
     }
 }
 
@@ -75,48 +185,86 @@ fn is_gzip(key: &str, resp: &hyper::client::Response) -> bool {
     }
 }
 
+/// The number of response body chunks which may be queued up waiting for the parser
+const CHUNK_BACKLOG: usize = 16;
+/// How long to wait for the response headers, and then for each chunk of the body
+const RESPONSE_TIMEOUT_SECONDS: u64 = 10;
+
+/// Run the future on the event loop, giving up if it takes longer than the response timeout
+fn run_with_timeout<F>(core: &mut Core, f: F, what: &str) -> Result<F::Item, std::io::Error>
+    where F: Future<Error=std::io::Error>
+{
+    let message = format!("timed out waiting for {}", what);
+    let timeout = Timeout::new(Duration::new(RESPONSE_TIMEOUT_SECONDS, 0), &core.handle())?
+        .then(|r| match r {Err(e)=>future::err(e), Ok(_)=>future::err(std::io::Error::new(std::io::ErrorKind::Other, message))} );
+    core.run(f.select(timeout)).map(|(select_ok, _)| select_ok ).map_err(|(select_err, _)| select_err)
+}
+
 fn process_s3obj(mut core: &mut Core,
                  client: &S3ClientWorkarounds<DefaultCredentialsProvider,Client<HttpsConnector>>,
                  bucket: &str,
                  obj: &s3::Object,
-                 consumer: &mut Consumer) -> Result<(), std::io::Error>
+                 settings: &ParseSettings,
+                 consumer: Consumer) -> Result<(Consumer, FileReport), std::io::Error>
 {
-    let parser = HttpdAccessLogParser::new();
     let mut req = s3::GetObjectRequest::default();
     req.bucket = bucket.to_string();
     req.key = obj.key.clone().unwrap();
     let result = client
         .get_object(&req)
-        .map_err(|e| {println!("map_err 1"); std::io::Error::new(std::io::ErrorKind::Other, e) })?
-        .map_err(|e| {println!("map_err 2"); std::io::Error::new(std::io::ErrorKind::Other, e) });
-    let process = result.and_then(|resp| {
-        let is_gzip = is_gzip(&req.key, &resp);
-        let future_body = resp.body()
-            .map_err(|_| {println!("map_err 3"); std::io::Error::new(std::io::ErrorKind::Other, "failure reading response body".to_string()) })
-            .map(|chunk| chunk.to_vec(/*TODO: avoid this copy*/) )
-            .concat();
-        future_body.and_then(move |body| {
-            if is_gzip {
-                let gunzip = GzDecoder::new(&body[..])?;
-                parser.process_lines(gunzip, consumer)
-            } else {
-                parser.process_lines(&body[..], consumer)
-            }
-        })
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("failure making request: {}", e)) )?
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("failure getting response: {}", e)) );
+    let resp = run_with_timeout(core, result, "the response")?;
+    let is_gzip = is_gzip(&req.key, &resp);
+
+    // decompress and parse on a separate thread, as chunks of the body arrive
+    let (chunk_send, chunk_recv) = chunkreader::channel(CHUNK_BACKLOG);
+    let settings = settings.clone();
+    let key = req.key.clone();
+    let parse_thread = thread::spawn(move || {
+        let mut consumer = consumer;
+        let result = if is_gzip {
+            GzDecoder::new(chunk_recv)
+                .and_then(|gunzip| process_lines(&settings, &key, BufReader::new(gunzip), &mut consumer) )
+        } else {
+            process_lines(&settings, &key, BufReader::new(chunk_recv), &mut consumer)
+        };
+        result.map(|report| (consumer, report) )
     });
-    let timeout = Timeout::new(Duration::new(10, 0), &core.handle())?
-        .then(|r| match r {Err(e)=>future::err(e), Ok(_)=>future::err(std::io::Error::new(std::io::ErrorKind::Other, "request timed out"))} );
-    let with_timeout = process
-        .map_err(|e| {println!("map_err 4"); std::io::Error::new(std::io::ErrorKind::Other, e) })
-        .select(timeout);
-    core.run(with_timeout).map(|(select_ok, _)| select_ok ).map_err(|(select_err, _)| select_err)
+    // each chunk is waited for with the event loop running, and then handed over with it stopped,
+    // so a send which blocks while the parser catches up neither stalls the loop mid-request
+    // nor counts against the timeout for the next chunk
+    let mut body = resp.body();
+    loop {
+        let next = body.into_future()
+            .map_err(|(e, _)| std::io::Error::new(std::io::ErrorKind::Other, format!("failure reading response body: {}", e)) );
+        match run_with_timeout(core, next, "the response body") {
+            Ok((Some(chunk), rest)) => {
+                if chunk_send.send(Ok(chunk.to_vec())).is_err() {
+                    // the parser has stopped, and will report why
+                    break;
+                }
+                body = rest;
+            },
+            Ok((None, _)) => break,
+            Err(e) => {
+                // let the parser know the data is incomplete, rather than leaving it to see a
+                // clean end-of-file
+                let _ = chunk_send.send(Err(e));
+                break;
+            },
+        }
+    }
+    drop(chunk_send);
+    parse_thread.join()
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "parser thread panicked"))?
 }
 
 enum Action {
     ProcessFile(PathBuf),
 }
 
-fn process_files(exp: PathExpression, options: PathMatchOptions) -> Result<(), std::io::Error> {
+fn process_files(exp: PathExpression, options: PathMatchOptions, settings: ParseSettings, config: Arc<ConsumerConfig>, ledger: Option<Arc<Ledger>>) -> Result<Consumer, std::io::Error> {
     let work_count: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
     let result_recv = {
         let (action_send, action_recv) = chan::async();
@@ -149,17 +297,42 @@ fn process_files(exp: PathExpression, options: PathMatchOptions) -> Result<(), s
         for _ in 0..6 {
             let action_recv = action_recv.clone();
             let result_send = result_send.clone();
+            let settings = file_settings(&settings, &ledger);
+            let config = config.clone();
+            let ledger = ledger.clone();
             thread::spawn(move || {
                 for action in action_recv {
                     match action {
                         Action::ProcessFile(path) => {
-                            let mut consumer = Consumer::new();
+                            let key = path.display().to_string();
+                            // taken before reading, so that a file which grows while it's being
+                            // processed is seen as changed next time
+                            let fingerprint = ledger.as_ref().and_then(|_| ledger::file_fingerprint(&path).ok() );
+                            if let (Some(ledger), Some(fingerprint)) = (ledger.as_ref(), fingerprint.as_ref()) {
+                                if let Some(cached) = ledger.cached(&key, fingerprint, config.clone()) {
+                                    println!("{} (unchanged, results taken from ledger)", key);
+                                    result_send.send(cached);
+                                    continue;
+                                }
+                            }
+                            let mut consumer = Consumer::new(config.clone());
                             let time = Instant::now();
-                            process_file(&path, &mut consumer).unwrap();
+                            let report = process_file(&path, &settings, &mut consumer)
+                                .unwrap_or_else(|e| FileReport::failed(&path.display().to_string(), e) );
                             let elapsed = time.elapsed();
                             let elapsed = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000;
                             println!("{} ({}ms)", path.display(), elapsed);
-                            result_send.send(consumer);
+                            if report.failure.is_some() {
+                                consumer = Consumer::new(config.clone());
+                            } else if let (Some(ledger), Some(fingerprint)) = (ledger.as_ref(), fingerprint.as_ref()) {
+                                if let Err(e) = ledger.record(&key, fingerprint, &consumer, &report) {
+                                    println!("Couldn't record {} in the ledger: {}", key, e);
+                                }
+                            }
+                            if let Some(ref ledger) = ledger {
+                                consumer = ledger.in_period(consumer);
+                            }
+                            result_send.send((consumer, report));
                         },
                     }
                 }
@@ -167,23 +340,29 @@ fn process_files(exp: PathExpression, options: PathMatchOptions) -> Result<(), s
         }
         result_recv
     };
-    let mut reduced = Consumer::new();
-    for (completed, result) in result_recv.iter().enumerate() {
+    let mut reduced = Consumer::new(config);
+    let mut summary = ErrorSummary::new();
+    for (completed, (result, report)) in result_recv.iter().enumerate() {
         let remaining_work = work_count.fetch_sub(1, Ordering::AcqRel);
-        reduced.merge(&result);
+        reduced.merge(&result)?;
+        summary.add(report);
         println!("{} completed ({} known left)", completed+1, remaining_work);
     }
-    {
-        let mut f = File::create("by_status_timeslice.tsv")?;
-        reduced.dump_by_status_timeslice(&mut f)?;
+    if let Some(ledger) = ledger {
+        ledger.save()?;
     }
-    {
-        let mut f = File::create("by_uritype_timeslice.tsv")?;
-        reduced.dump_by_uritype_timeslice(&mut f)?;
+    summary.print();
+    Ok(reduced)
+}
+
+fn write_outputs(reduced: &Consumer, options: &ReportOptions, outputs: &mut [Box<OutputWriter>]) -> Result<(), std::io::Error> {
+    for report in reduced.reports(options) {
+        for output in outputs.iter_mut() {
+            output.write(&report)?;
+        }
     }
-    {
-        let mut f = File::create("servicetime_by_timeslice.tsv")?;
-        reduced.dump_servicetimes_by_timeslice(&mut f)?;
+    for output in outputs.iter_mut() {
+        output.finish()?;
     }
     Ok(())
 }
@@ -199,7 +378,7 @@ fn s3client(region: Region, http_client: Client<HttpsConnector>) -> S3ClientWork
     S3ClientWorkarounds::new(http_client, provider, region)
 }
 
-fn process_s3(region: Region, bucket: &str, pathexp: PathExpression, options: PathMatchOptions) -> Result<(), std::io::Error> {
+fn process_s3(region: Region, bucket: &str, pathexp: PathExpression, options: PathMatchOptions, settings: ParseSettings, config: Arc<ConsumerConfig>, ledger: Option<Arc<Ledger>>) -> Result<Consumer, std::io::Error> {
     let work_count: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
     let result_recv = {
         let (pathexp_send, pathexp_recv) = chan::async();
@@ -266,63 +445,189 @@ fn process_s3(region: Region, bucket: &str, pathexp: PathExpression, options: Pa
             let s3obj_recv = s3obj_recv.clone();
             let result_send = result_send.clone();
             let bucket = bucket.to_string();
+            let settings = file_settings(&settings, &ledger);
+            let config = config.clone();
+            let ledger = ledger.clone();
             thread::spawn(move || {
                 let mut core = Core::new().unwrap();
                 let handle = core.handle();
                 let http_client = http_client(&handle).unwrap();
                 let client = s3client(region, http_client);
                 for obj in s3obj_recv {
-                    let mut consumer = Consumer::new();
                     let time = Instant::now();
-                    process_s3obj(&mut core, &client, &bucket, &obj, &mut consumer).unwrap();
+                    let key = obj.key.clone().unwrap();
+                    if settings.skip_files_outside_period && settings.period.is_bounded() && object_before_period(&obj, &settings.period) {
+                        println!("{}: outside period, skipped", key);
+                        result_send.send((Consumer::new(config.clone()), FileReport::new(&key)));
+                        continue;
+                    }
+                    let ledger_key = format!("s3://{}/{}", bucket, key);
+                    let fingerprint = ledger::object_fingerprint(&obj);
+                    if let Some(ref ledger) = ledger {
+                        if let Some(cached) = ledger.cached(&ledger_key, &fingerprint, config.clone()) {
+                            println!("{} (unchanged, results taken from ledger)", key);
+                            result_send.send(cached);
+                            continue;
+                        }
+                    }
+                    let (consumer, report) = process_s3obj(&mut core, &client, &bucket, &obj, &settings, Consumer::new(config.clone()))
+                        .unwrap_or_else(|e| (Consumer::new(config.clone()), FileReport::failed(&key, e)) );
                     let elapsed = time.elapsed();
                     let elapsed = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000;
-                    println!("{} ({}ms)", obj.key.unwrap(), elapsed);
-                    result_send.send(consumer);
+                    println!("{} ({}ms)", key, elapsed);
+                    let consumer = if report.failure.is_some() { Consumer::new(config.clone()) } else { consumer };
+                    let consumer = match ledger {
+                        Some(ref ledger) => {
+                            if report.failure.is_none() {
+                                if let Err(e) = ledger.record(&ledger_key, &fingerprint, &consumer, &report) {
+                                    println!("Couldn't record {} in the ledger: {}", key, e);
+                                }
+                            }
+                            ledger.in_period(consumer)
+                        },
+                        None => consumer,
+                    };
+                    result_send.send((consumer, report));
                 }
             });
         }
         result_recv
     };
-    let mut reduced = Consumer::new();
-    for (completed, result) in result_recv.iter().enumerate() {
+    let mut reduced = Consumer::new(config);
+    let mut summary = ErrorSummary::new();
+    for (completed, (result, report)) in result_recv.iter().enumerate() {
         let remaining_work = work_count.fetch_sub(1, Ordering::AcqRel);
-        reduced.merge(&result);
+        reduced.merge(&result)?;
+        summary.add(report);
         println!("{} completed ({} known left)", completed+1, remaining_work);
     }
-    {
-        let mut f = File::create("by_status_timeslice.tsv")?;
-        reduced.dump_by_status_timeslice(&mut f)?;
+    if let Some(ledger) = ledger {
+        ledger.save()?;
     }
-    {
-        let mut f = File::create("by_uritype_timeslice.tsv")?;
-        reduced.dump_by_uritype_timeslice(&mut f)?;
-    }
-    {
-        let mut f = File::create("servicetime_by_timeslice.tsv")?;
-        reduced.dump_servicetimes_by_timeslice(&mut f)?;
-    }
-    Ok(())
+    summary.print();
+    Ok(reduced)
 }
 
 fn parse_datetime(datetime: &str) -> Result<time::Tm, time::ParseError> {
     strptime(datetime, "%Y-%m-%d:%H:%M:%S")
 }
 
-fn range_to_opts(range: Option<&str>) -> Result<PathMatchOptions, time::ParseError> {
+/// The `--period` range, both as options for listing files and as the period that individual
+/// records must fall within
+fn range_to_opts(range: Option<&str>) -> Result<(PathMatchOptions, Period), time::ParseError> {
     let mut options = PathMatchOptions::new();
+    let mut period = Period::default();
     if let Some(range) = range {
         let mut i = range.split("..");
         if let Some(datetime) = i.next() {
-            parse_datetime(datetime)
-                .and_then(|from| Ok(options.from(from)) )?;
+            let from = parse_datetime(datetime)?;
+            period.from = Some(from.to_timespec());
+            options.from(from);
         }
         if let Some(datetime) = i.next() {
-            parse_datetime(datetime)
-                .and_then(|to| Ok(options.to(to)) )?;
+            let to = parse_datetime(datetime)?;
+            period.to = Some(to.to_timespec());
+            options.to(to);
+        }
+    }
+    Ok((options, period))
+}
+
+/// The timeslice width from `--slice`, falling back to the datasource's own setting and then to
+/// the default of 20 minutes
+fn slice_seconds(arg: Option<&str>, source: &Option<String>) -> Result<i64, String> {
+    match arg.or(source.as_ref().map(|s| &s[..] )) {
+        Some(text) => duration::parse_duration(text),
+        None => Ok(DEFAULT_SLICE_SECONDS),
+    }
+}
+
+const DEFAULT_SLICE_SECONDS: i64 = 1200;
+
+/// Write out the results, rolled up if need be, returning the results as written
+fn finish(reduced: Consumer, rollup: Option<i64>, options: &ReportOptions, outputs: &mut [Box<OutputWriter>]) -> Result<Consumer, std::io::Error> {
+    let reduced = match rollup {
+        Some(seconds) => reduced.rollup(seconds)?,
+        None => reduced,
+    };
+    write_outputs(&reduced, options, outputs)?;
+    Ok(reduced)
+}
+
+/// Describes everything that affects the results from each file, so that a ledger written with
+/// different options isn't reused.  The `--period` isn't included, since the ledger keeps the
+/// results for whole files.
+fn ledger_settings(source: &std::fmt::Debug, sources: &Datasources, classifier: &Option<String>, config: &ConsumerConfig, matches: &ArgMatches) -> String {
+    let classifier = sources.classifier.iter().find(|c| Some(&c.name) == classifier.as_ref() );
+    format!("{:?} classifier={:?} slice={} query={} top={:?} unique={} breakdown={} filter={}",
+            source,
+            classifier,
+            config.slice_seconds,
+            config.query.as_ref().map(|q| q.to_string() ).unwrap_or_default(),
+            config.top,
+            config.unique.as_ref().map(|u| u.to_string() ).unwrap_or_default(),
+            config.breakdown.as_ref().map(|b| b.to_string() ).unwrap_or_default(),
+            matches.value_of("filter").unwrap_or(""))
+}
+
+/// With `--alerts`, check the rules before any files are read, exiting straight away if one of
+/// them could never be checked
+fn validate_alerts(sources: &Datasources, config: &Arc<ConsumerConfig>, options: &ReportOptions, matches: &ArgMatches) {
+    if matches.is_present("alerts") {
+        if let Err(e) = alert::validate(&sources.alert, config.clone(), options) {
+            println!("{}", e);
+            std::process::exit(alert::INVALID_EXIT_STATUS);
         }
     }
-    Ok(options)
+}
+
+/// Open the `--ledger`, if one was given
+fn open_ledger(settings: String, period: &Period, matches: &ArgMatches) -> Option<Arc<Ledger>> {
+    matches.value_of("ledger").map(|dir| Ledger::open(Path::new(dir), &settings, period).expect("couldn't open ledger") )
+}
+
+/// The settings to process each file with.  With a ledger, whole files are processed, so that
+/// their results can be reused whatever the period, and the ledger cuts them down to the period.
+fn file_settings(settings: &ParseSettings, ledger: &Option<Arc<Ledger>>) -> ParseSettings {
+    let mut settings = settings.clone();
+    if ledger.is_some() {
+        settings.period = Period::default();
+        settings.skip_files_outside_period = false;
+    }
+    settings
+}
+
+/// Create a writer for each of the comma-separated `--format` names
+fn output_writers(formats: &str, dir: &Path, times: TimeFormat) -> Result<Vec<Box<OutputWriter>>, std::io::Error> {
+    formats.split(',').map(|f| output::writer(f.trim(), dir, times) ).collect()
+}
+
+fn parse_percentiles(list: Option<&str>) -> Result<Vec<f64>, String> {
+    let list = match list {
+        Some(list) => list,
+        None => return Ok(DEFAULT_PERCENTILES.to_vec()),
+    };
+    list.split(',').map(|p| {
+        match p.trim().trim_left_matches('p').parse::<f64>() {
+            Ok(v) if v >= 0.0 && v <= 100.0 => Ok(v),
+            _ => Err(format!("percentile must be between 0 and 100, not {:?}", p)),
+        }
+    }).collect()
+}
+
+fn time_format(style: Option<&str>, tz: Option<&str>) -> Result<TimeFormat, String> {
+    let style = match style {
+        None | Some("epoch") => TimeStyle::Epoch,
+        Some("iso8601") => TimeStyle::Iso8601,
+        Some(other) => return Err(format!("time format must be epoch or iso8601, not {:?}", other)),
+    };
+    Ok(TimeFormat {
+        style: style,
+        zone: match tz {
+            Some(tz) => Zone::parse(tz)?,
+            None => Zone::Utc,
+        },
+    })
 }
 
 fn main() {
@@ -333,38 +638,290 @@ fn main() {
              .long("period")
              .value_name("RANGE")
              .help("YYYY-MM-DD:hh:mm:ss..YYYY-MM-DD:hh:mm:ss"))
+        .arg(Arg::with_name("skip-files-outside-period")
+             .long("skip-files-outside-period")
+             .help("don't parse files whose records all lie before, or all after, the --period, judging by their first and last records, or for compressed files and S3 objects by their modification times"))
         .arg(Arg::with_name("source")
              .long("source")
              .value_name("SOURCE NAME")
              .help("name of a source from datasources.toml"))
+        .arg(Arg::with_name("slice")
+             .long("slice")
+             .value_name("DURATION")
+             .help("width of each timeslice, e.g. 10s, 5m or 1h (default 20m)"))
+        .arg(Arg::with_name("rollup")
+             .long("rollup")
+             .value_name("DURATION")
+             .help("combine timeslices into wider ones before writing output; must be a multiple of the slice width"))
+        .arg(Arg::with_name("filter")
+             .long("filter")
+             .value_name("EXPRESSION")
+             .help("only include records matching the expression, e.g. 'request_host == \"cdn.example.com\" && response_status =~ \"^5\"'"))
+        .arg(Arg::with_name("group-by")
+             .long("group-by")
+             .value_name("FIELDS")
+             .help("comma-separated Record fields (or timeslice, uritype) to group by, written to query.tsv"))
+        .arg(Arg::with_name("agg")
+             .long("agg")
+             .value_name("AGGREGATES")
+             .help("comma-separated aggregates for each group, e.g. count,sum(response_bytes),p99(response_time_micros) (default count)"))
+        .arg(Arg::with_name("top")
+             .long("top")
+             .value_name("N")
+             .help("report the N most frequent URIs, clients, user agents and referers, per timeslice and overall"))
+        .arg(Arg::with_name("top-fields")
+             .long("top-fields")
+             .value_name("FIELDS")
+             .help("comma-separated Record fields for --top (default request_uri,remote_host,request_forwarded_for,request_useragent,request_referer)"))
+        .arg(Arg::with_name("unique-clients")
+             .long("unique-clients")
+             .value_name("FIELDS")
+             .help("estimate the number of distinct clients per timeslice, identifying clients by these comma-separated fields, e.g. client_ip or client_ip,request_useragent"))
+        .arg(Arg::with_name("trusted-proxies")
+             .long("trusted-proxies")
+             .value_name("NETWORKS")
+             .help("comma-separated networks, e.g. 10.0.0.0/8, whose X-Forwarded-For entries are believed when finding the client_ip"))
+        .arg(Arg::with_name("breakdown")
+             .long("breakdown")
+             .value_name("FIELDS")
+             .help("give requests, statuses and service times for each value of these comma-separated fields, e.g. request_handler,request_local_port"))
+        .arg(Arg::with_name("output-dir")
+             .long("output-dir")
+             .value_name("DIR")
+             .help("directory to write results into (default is the current directory)"))
+        .arg(Arg::with_name("format")
+             .long("format")
+             .value_name("FORMATS")
+             .help("comma-separated output formats: tsv (the default), csv, jsonl, hdr-json, prometheus"))
+        .arg(Arg::with_name("time-format")
+             .long("time-format")
+             .value_name("STYLE")
+             .help("how to write timeslices: epoch (seconds, the default) or iso8601"))
+        .arg(Arg::with_name("tz")
+             .long("tz")
+             .value_name("ZONE")
+             .help("timezone for iso8601 times: utc (the default), local, or an offset like +01:00"))
+        .arg(Arg::with_name("slice-end")
+             .long("slice-end")
+             .help("include the end time of each timeslice, as well as its start"))
+        .arg(Arg::with_name("zero-fill")
+             .long("zero-fill")
+             .help("include rows for timeslices with no traffic"))
+        .arg(Arg::with_name("alerts")
+             .long("alerts")
+             .help("check the [[alert]] rules in datasources.toml, exiting with status 1 if any are breached, or 2 if a rule is invalid"))
+        .arg(Arg::with_name("anomalies")
+             .long("anomalies")
+             .value_name("BASELINE")
+             .help("list unusual timeslices in anomalies.tsv, comparing each with the timeslices before it (rolling) or with the same time on previous days (daily)"))
+        .arg(Arg::with_name("anomaly-window")
+             .long("anomaly-window")
+             .value_name("N")
+             .help("how many earlier timeslices (default 12) or days (default 7) to compare with"))
+        .arg(Arg::with_name("anomaly-threshold")
+             .long("anomaly-threshold")
+             .value_name("SCORE")
+             .help("the modified z-score beyond which a value is unusual (default 3.5)"))
+        .arg(Arg::with_name("anomaly-min-change")
+             .long("anomaly-min-change")
+             .value_name("FRACTION")
+             .help("the least change from the baseline, as a fraction of it, that can be unusual (default 0.1)"))
+        .arg(Arg::with_name("status-classes")
+             .long("status-classes")
+             .help("group the statuses in by_status_timeslice into 1xx to 5xx"))
+        .arg(Arg::with_name("percentiles")
+             .long("percentiles")
+             .value_name("LIST")
+             .help("comma-separated service time percentiles to report (default 50,90,99,99.9)"))
+        .arg(Arg::with_name("histogram-buckets")
+             .long("histogram-buckets")
+             .value_name("MODE")
+             .help("service time histogram columns: recorded (every distinct value) or log (1ms, 2ms, 4ms ... 65s); by default, recorded for servicetime_by_timeslice and log for the cache hit and miss histograms"))
+        .arg(Arg::with_name("save-snapshot")
+             .long("save-snapshot")
+             .value_name("FILE")
+             .help("save the combined results, before any --rollup, so they can be reloaded later"))
+        .arg(Arg::with_name("load-snapshot")
+             .long("load-snapshot")
+             .value_name("FILE")
+             .multiple(true)
+             .number_of_values(1)
+             .help("merge in results saved by an earlier --save-snapshot (may be repeated)"))
+        .arg(Arg::with_name("follow")
+             .long("follow")
+             .help("keep reading the file currently being written by a file source, following log rotation, and write out each timeslice as it completes"))
+        .arg(Arg::with_name("retain")
+             .long("retain")
+             .value_name("DURATION")
+             .help("with --follow, how long to keep completed timeslices for, in the outputs and for --serve, e.g. 6h (default 1d)"))
+        .arg(Arg::with_name("serve")
+             .long("serve")
+             .value_name("ADDRESS")
+             .help("with --follow, serve the live results over HTTP on e.g. 127.0.0.1:9180, as JSON tables and Prometheus /metrics"))
+        .arg(Arg::with_name("ledger")
+             .long("ledger")
+             .value_name("DIR")
+             .help("keep each file's results in DIR, and reuse them on later runs unless the file has changed"))
         .get_matches();
 
     let _ = env_logger::init();
 
-    let source_name = matches.value_of("source").expect("A --source argument must be supplied");
-    let sources = datasource::get_datasources().unwrap();
-    let source = sources.s3.iter().find(|s| s.name == source_name);
-    let options = range_to_opts(matches.value_of("period")).expect("bad --range value");
+    let source_name = matches.value_of("source");
+    // datasources.toml isn't needed when only re-rendering snapshots, unless alerts are checked
+    let sources = if source_name.is_some() || matches.is_present("alerts") {
+        datasource::get_datasources().unwrap()
+    } else {
+        Datasources::default()
+    };
+    let source = sources.s3.iter().find(|s| Some(&s.name[..]) == source_name );
+    let (options, period) = range_to_opts(matches.value_of("period")).expect("bad --period value");
+    let query = if matches.is_present("group-by") || matches.is_present("agg") {
+        Some(Query::parse(matches.value_of("group-by").unwrap_or(""), matches.value_of("agg").unwrap_or("count")).expect("bad --group-by/--agg value"))
+    } else {
+        None
+    };
+    let top = matches.value_of("top").map(|n| TopConfig::parse(n, matches.value_of("top-fields")).expect("bad --top or --top-fields value") );
+    let unique = matches.value_of("unique-clients").map(|f| UniqueConfig::parse(f, matches.value_of("trusted-proxies")).expect("bad --unique-clients or --trusted-proxies value") );
+    let breakdown = matches.value_of("breakdown").map(|f| BreakdownConfig::parse(f).expect("bad --breakdown value") );
+    let filter = matches.value_of("filter").map(|f| Filter::parse(f).expect("bad --filter value") );
+    let output_dir = Path::new(matches.value_of("output-dir").unwrap_or("."));
+    let times = time_format(matches.value_of("time-format"), matches.value_of("tz")).expect("bad --time-format or --tz value");
+    let mut outputs = output_writers(matches.value_of("format").unwrap_or("tsv"), output_dir, times).expect("bad --format or --output-dir value");
+    let report_options = ReportOptions {
+        zero_fill: matches.is_present("zero-fill"),
+        slice_end: matches.is_present("slice-end"),
+        status_classes: matches.is_present("status-classes"),
+        anomalies: matches.value_of("anomalies").map(|b| AnomalyConfig::parse(b, matches.value_of("anomaly-window"), matches.value_of("anomaly-threshold"), matches.value_of("anomaly-min-change")).expect("bad --anomalies, --anomaly-window, --anomaly-threshold or --anomaly-min-change value") ),
+        percentiles: parse_percentiles(matches.value_of("percentiles")).expect("bad --percentiles value"),
+        buckets: match matches.value_of("histogram-buckets") {
+            None => None,
+            Some("recorded") => Some(Buckets::Recorded),
+            Some("log") => Some(Buckets::default_log()),
+            Some(other) => panic!("bad --histogram-buckets value {:?}, expected recorded or log", other),
+        },
+    };
+    let rollup = matches.value_of("rollup").map(|r| duration::parse_duration(r).expect("bad --rollup value") );
+    if matches.is_present("serve") && !matches.is_present("follow") {
+        println!("--serve only works together with --follow");
+        return;
+    }
+    let time = Instant::now();
+    let mut reduced = None;
     if let Some(s3source) = source {
         let expr = PathExpression::parse(&s3source.pathexp).unwrap();
-        let time = Instant::now();
+        let mut settings = s3source.parse_settings().expect("bad datasource settings");
+        settings.filter = filter.clone();
+        settings.period = period.clone();
+        settings.skip_files_outside_period = matches.is_present("skip-files-outside-period");
+        let config = Arc::new(ConsumerConfig {
+            classifier: sources.build_classifier(&s3source.classifier).expect("bad classifier"),
+            slice_seconds: slice_seconds(matches.value_of("slice"), &s3source.slice).expect("bad timeslice width"),
+            query: query.clone(),
+            top: top.clone(),
+            unique: unique.clone(),
+            breakdown: breakdown.clone(),
+        });
+        if matches.is_present("follow") {
+            println!("--follow only works with file sources");
+            return;
+        }
         let region = s3source.region.parse::<Region>();
         if region.is_err() {
             println!("Invalid AWS region: {:?}", s3source.region);
             return;
         }
-        process_s3(region.unwrap(), &s3source.bucket, expr, options).unwrap();
-        let elapsed = time.elapsed();
-        let elapsed = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000;
-        println!("Complete in {} ms", elapsed);
-        return;
-    }
-    let source = sources.file.iter().find(|s| s.name == source_name);
-    if let Some(filesource) = source {
+        validate_alerts(&sources, &config, &report_options, &matches);
+        let ledger = open_ledger(ledger_settings(s3source, &sources, &s3source.classifier, &config, &matches), &period, &matches);
+        reduced = Some(process_s3(region.unwrap(), &s3source.bucket, expr, options, settings, config, ledger).unwrap());
+    } else if let Some(filesource) = sources.file.iter().find(|s| Some(&s.name[..]) == source_name ) {
         let expr = PathExpression::parse(&filesource.pathexp).unwrap();
-        process_files(expr, options).unwrap();
+        let mut settings = filesource.parse_settings().expect("bad datasource settings");
+        settings.filter = filter.clone();
+        settings.period = period.clone();
+        settings.skip_files_outside_period = matches.is_present("skip-files-outside-period");
+        let config = Arc::new(ConsumerConfig {
+            classifier: sources.build_classifier(&filesource.classifier).expect("bad classifier"),
+            slice_seconds: slice_seconds(matches.value_of("slice"), &filesource.slice).expect("bad timeslice width"),
+            query: query.clone(),
+            top: top.clone(),
+            unique: unique.clone(),
+            breakdown: breakdown.clone(),
+        });
+        if matches.is_present("follow") {
+            let formats = matches.value_of("format").unwrap_or("tsv");
+            let flush_seconds = rollup.unwrap_or(config.slice_seconds);
+            let retain_seconds = duration::parse_duration(matches.value_of("retain").unwrap_or("1d")).expect("bad --retain value");
+            if retain_seconds < flush_seconds {
+                println!("--retain must be at least as long as a timeslice");
+                return;
+            }
+            let live = Arc::new(Mutex::new(Consumer::new(config)));
+            if let Some(addr) = matches.value_of("serve") {
+                let addr = addr.parse().expect("bad --serve address");
+                server::spawn(addr, server::StatsService::new(live.clone(), report_options.clone(), rollup, times)).unwrap();
+            }
+            follow::follow(&expr, &settings, live, flush_seconds, retain_seconds, |closed| {
+                // each flush rewrites the outputs from scratch
+                let mut outputs = output_writers(formats, output_dir, times)?;
+                finish(closed, rollup, &report_options, &mut outputs).map(|_| () )
+            }).unwrap();
+            return;
+        }
+        validate_alerts(&sources, &config, &report_options, &matches);
+        let ledger = open_ledger(ledger_settings(filesource, &sources, &filesource.classifier, &config, &matches), &period, &matches);
+        reduced = Some(process_files(expr, options, settings, config, ledger).unwrap());
+    } else if let Some(name) = source_name {
+        println!("No source named {:?} in datasources.toml", name);
         return;
     }
-
-
+    if let Some(paths) = matches.values_of("load-snapshot") {
+        for path in paths {
+            let config = match reduced {
+                Some(ref r) => r.config(),
+                None => Arc::new(ConsumerConfig {
+                    classifier: Classifier::default_rules(),
+                    slice_seconds: slice_seconds(matches.value_of("slice"), &None).expect("bad timeslice width"),
+                    query: query.clone(),
+                    top: top.clone(),
+                    unique: unique.clone(),
+                    breakdown: breakdown.clone(),
+                }),
+            };
+            let snapshot = Consumer::load_snapshot(config, Path::new(path)).expect("couldn't load snapshot");
+            reduced = Some(match reduced.take() {
+                Some(mut r) => {
+                    r.merge(&snapshot).expect("couldn't merge snapshot");
+                    r
+                },
+                None => snapshot,
+            });
+        }
+    }
+    let reduced = match reduced {
+        Some(r) => r,
+        None => {
+            println!("A --source or --load-snapshot argument must be supplied");
+            return;
+        },
+    };
+    if let Some(path) = matches.value_of("save-snapshot") {
+        reduced.save_snapshot(Path::new(path)).expect("couldn't save snapshot");
+    }
+    let reduced = finish(reduced, rollup, &report_options, &mut outputs).unwrap();
+    let elapsed = time.elapsed();
+    let elapsed = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000;
+    println!("Complete in {} ms", elapsed);
+    if matches.is_present("alerts") {
+        let breaches = match alert::check_all(&sources.alert, &reduced, &report_options) {
+            Ok(breaches) => breaches,
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(alert::INVALID_EXIT_STATUS);
+            },
+        };
+        if !breaches.is_empty() {
+            alert::write_report(&sources.alert, &breaches, &times, &mut std::io::stdout()).unwrap();
+            std::process::exit(alert::BREACHED_EXIT_STATUS);
+        }
+    }
 }
@@ -0,0 +1,748 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use hdrsample::Histogram;
+use time;
+use time::Timespec;
+use serde_json;
+use serde_json::{Map, Value};
+use anomaly::AnomalyConfig;
+
+/// A single value within a `Table`
+#[derive(Debug,Clone,PartialEq)]
+pub enum Cell {
+    /// the start of a timeslice, in seconds since the epoch
+    Time(i64),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    /// no value, e.g. the mean of an empty group
+    Empty,
+}
+
+/// How times are written, e.g. `2017-03-01T12:00:00Z` or `1488369600`
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum TimeStyle {
+    /// seconds since the epoch
+    Epoch,
+    Iso8601,
+}
+
+/// The timezone in which ISO-8601 times are given
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Zone {
+    Utc,
+    /// the local timezone of the machine running the analysis
+    Local,
+    /// a fixed offset from UTC, in seconds
+    Offset(i32),
+}
+
+impl Zone {
+    /// Parse `utc`, `local` or an offset such as `+01:00` or `-0800`
+    pub fn parse(text: &str) -> Result<Zone, String> {
+        match text {
+            "utc" | "UTC" | "Z" => return Ok(Zone::Utc),
+            "local" => return Ok(Zone::Local),
+            _ => (),
+        }
+        let bad = || format!("timezone must be utc, local or an offset like +01:00, not {:?}", text);
+        let sign = match text.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return Err(bad()),
+        };
+        let digits = text[1..].replace(":", "");
+        if digits.len() != 4 || !digits.chars().all(|c| c.is_digit(10) ) {
+            return Err(bad());
+        }
+        let hours: i32 = digits[..2].parse().unwrap();
+        let minutes: i32 = digits[2..].parse().unwrap();
+        if hours > 23 || minutes > 59 {
+            return Err(bad());
+        }
+        Ok(Zone::Offset(sign * (hours * 3600 + minutes * 60)))
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct TimeFormat {
+    pub style: TimeStyle,
+    pub zone: Zone,
+}
+
+impl TimeFormat {
+    pub fn epoch() -> TimeFormat {
+        TimeFormat {
+            style: TimeStyle::Epoch,
+            zone: Zone::Utc,
+        }
+    }
+
+    pub fn format(&self, t: i64) -> String {
+        if self.style == TimeStyle::Epoch {
+            return t.to_string();
+        }
+        let tm = match self.zone {
+            Zone::Utc => time::at_utc(Timespec::new(t, 0)),
+            Zone::Local => time::at(Timespec::new(t, 0)),
+            Zone::Offset(offset) => {
+                let mut tm = time::at_utc(Timespec::new(t + offset as i64, 0));
+                tm.tm_utcoff = offset;
+                tm
+            },
+        };
+        let base = time::strftime("%Y-%m-%dT%H:%M:%S", &tm).unwrap();
+        if tm.tm_utcoff == 0 {
+            format!("{}Z", base)
+        } else {
+            let off = tm.tm_utcoff.abs();
+            format!("{}{}{:02}:{:02}", base, if tm.tm_utcoff < 0 { '-' } else { '+' }, off / 3600, (off % 3600) / 60)
+        }
+    }
+}
+
+/// Options affecting the layout of the tables produced by a `Consumer`
+#[derive(Debug,Clone)]
+pub struct ReportOptions {
+    /// include a row for every timeslice between the first and the last, even those where there
+    /// was no traffic
+    pub zero_fill: bool,
+    /// include a `timeslice_end` column after `timeslice`
+    pub slice_end: bool,
+    /// the percentiles to give in the service time summary, e.g. `99.9`
+    pub percentiles: Vec<f64>,
+    /// how to divide up the histograms, or `None` to leave each to its own default
+    pub buckets: Option<Buckets>,
+    /// group `by_status_timeslice` columns into classes such as `5xx`
+    pub status_classes: bool,
+    /// when set, look for unusual timeslices and list them in an `anomalies` table
+    pub anomalies: Option<AnomalyConfig>,
+}
+
+/// The percentiles reported unless `--percentiles` says otherwise
+pub const DEFAULT_PERCENTILES: &'static [f64] = &[50.0, 90.0, 99.0, 99.9];
+
+/// How the values in a histogram are divided into columns
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Buckets {
+    /// a column for every distinct value recorded in any timeslice
+    Recorded,
+    /// a fixed set of columns, the first counting values up to `first`, and each subsequent
+    /// one having an upper bound `base` times that of the one before.  A final column counts
+    /// anything larger.
+    Log { first: u64, base: u64, count: usize },
+}
+
+impl Buckets {
+    /// 1ms, 2ms, 4ms ... ~65s, for microsecond values
+    pub fn default_log() -> Buckets {
+        Buckets::Log { first: 1000, base: 2, count: 17 }
+    }
+}
+
+impl Cell {
+    pub fn text(&self, times: &TimeFormat) -> String {
+        match *self {
+            Cell::Time(t) => times.format(t),
+            Cell::Int(t) => t.to_string(),
+            Cell::Float(f) => f.to_string(),
+            Cell::Str(ref s) => s.clone(),
+            Cell::Empty => String::new(),
+        }
+    }
+
+    fn json(&self, times: &TimeFormat) -> Value {
+        match *self {
+            Cell::Time(t) if times.style == TimeStyle::Iso8601 => Value::from(times.format(t)),
+            Cell::Time(t) | Cell::Int(t) => Value::from(t),
+            Cell::Float(f) => Value::from(f),
+            Cell::Str(ref s) => Value::from(&s[..]),
+            Cell::Empty => Value::Null,
+        }
+    }
+
+    pub fn number(&self) -> Option<f64> {
+        match *self {
+            Cell::Time(t) | Cell::Int(t) => Some(t as f64),
+            Cell::Float(f) => Some(f),
+            Cell::Str(_) | Cell::Empty => None,
+        }
+    }
+}
+
+/// A set of results ready to be written out, such as counts of each response status per
+/// timeslice
+#[derive(Debug)]
+pub struct Table {
+    /// used to name the output file, e.g. `by_status_timeslice`
+    pub name: String,
+    /// the names of the leading columns which identify each row, e.g. `timeslice`
+    pub keys: Vec<String>,
+    /// the names of the remaining columns
+    pub columns: Vec<String>,
+    /// when given, `columns` are the values of this dimension (e.g. `http_status`) rather than
+    /// separate measurements
+    pub pivot: Option<String>,
+    /// each row has a cell for every key, followed by a cell for every column
+    pub rows: Vec<Vec<Cell>>,
+}
+
+impl Table {
+    /// Add a `timeslice_end` key column after the `timeslice` column, if there is one
+    pub fn add_slice_end(&mut self, slice_seconds: i64) {
+        if let Some(i) = self.keys.iter().position(|k| k == "timeslice" ) {
+            self.keys.insert(i + 1, SLICE_END.to_string());
+            for row in self.rows.iter_mut() {
+                let end = match row[i] {
+                    Cell::Time(t) => Cell::Time(t + slice_seconds),
+                    _ => Cell::Empty,
+                };
+                row.insert(i + 1, end);
+            }
+        }
+    }
+}
+
+const SLICE_END: &'static str = "timeslice_end";
+
+/// A latency histogram for each timeslice
+pub struct HistogramSeries<'a> {
+    pub name: String,
+    /// the quantity recorded in the histograms, e.g. `response_time_micros`
+    pub unit: String,
+    /// `None` for timeslices with no traffic
+    pub slices: Vec<(i64, Option<&'a Histogram<u64>>)>,
+    /// all the slices combined
+    pub total: &'a Histogram<u64>,
+    /// when set, the width of the timeslices, so that their end times can be given
+    pub slice_end: Option<i64>,
+    pub buckets: Buckets,
+}
+
+impl<'a> HistogramSeries<'a> {
+    /// The upper bound of each bucket.  For `Buckets::Log` there's also a final, unbounded bucket.
+    pub fn bounds(&self) -> Vec<u64> {
+        match self.buckets {
+            Buckets::Recorded => self.total.iter_recorded().map(|v| v.value() ).collect(),
+            Buckets::Log { first, base, count } => {
+                let mut bounds = Vec::with_capacity(count);
+                let mut bound = first;
+                for _ in 0..count {
+                    bounds.push(bound);
+                    bound = bound.saturating_mul(base);
+                }
+                bounds
+            },
+        }
+    }
+
+    /// The count of values in each bucket for each timeslice
+    pub fn to_table(&self) -> Table {
+        let bounds = self.bounds();
+        let overflow = self.buckets != Buckets::Recorded;
+        let rows = self.slices.iter().map(|&(ts, hist)| {
+            let mut row = vec![Cell::Time(ts)];
+            let counts = bucket_counts(hist, &bounds);
+            let cols = if overflow { counts.len() } else { bounds.len() };
+            row.extend(counts[..cols].iter().map(|c| Cell::Int(*c as i64) ));
+            row
+        }).collect();
+        let mut columns = bounds.iter().map(|b| b.to_string() ).collect::<Vec<String>>();
+        if overflow {
+            columns.push("inf".to_string());
+        }
+        let mut table = Table {
+            name: self.name.clone(),
+            keys: vec!["timeslice".to_string()],
+            columns: columns,
+            pivot: Some(self.unit.clone()),
+            rows: rows,
+        };
+        if let Some(width) = self.slice_end {
+            table.add_slice_end(width);
+        }
+        table
+    }
+}
+
+/// The number of values falling into each of the buckets with the given upper bounds, followed
+/// by the number larger than all of them
+fn bucket_counts(hist: Option<&Histogram<u64>>, bounds: &[u64]) -> Vec<u64> {
+    let mut counts = vec![0; bounds.len() + 1];
+    if let Some(hist) = hist {
+        for v in hist.iter_recorded() {
+            let i = match bounds.binary_search(&v.value()) {
+                Ok(i) | Err(i) => i,
+            };
+            counts[i] += v.count_at_value();
+        }
+    }
+    counts
+}
+
+/// A table giving the chosen percentiles of the values in each timeslice's histogram, along
+/// with the maximum, mean and count
+pub fn percentile_table(name: &str, slices: &[(i64, Option<&Histogram<u64>>)], percentiles: &[f64]) -> Table {
+    let mut columns = percentiles.iter().map(|p| format!("p{}", p) ).collect::<Vec<String>>();
+    columns.push("max".to_string());
+    columns.push("mean".to_string());
+    columns.push("count".to_string());
+    let rows = slices.iter().map(|&(ts, hist)| {
+        let mut row = vec![Cell::Time(ts)];
+        match hist {
+            Some(hist) if hist.count() > 0 => {
+                row.extend(percentiles.iter().map(|p| Cell::Int(hist.value_at_percentile(*p) as i64) ));
+                row.push(Cell::Int(hist.max() as i64));
+                row.push(Cell::Float(hist.mean()));
+                row.push(Cell::Int(hist.count() as i64));
+            },
+            _ => {
+                row.extend(percentiles.iter().map(|_| Cell::Empty ));
+                row.push(Cell::Empty);
+                row.push(Cell::Empty);
+                row.push(Cell::Int(0));
+            },
+        }
+        row
+    }).collect();
+    Table {
+        name: name.to_string(),
+        keys: vec!["timeslice".to_string()],
+        columns: columns,
+        pivot: None,
+        rows: rows,
+    }
+}
+
+pub enum Report<'a> {
+    Table(Table),
+    Histograms(HistogramSeries<'a>),
+}
+
+/// Somewhere to put the results of a run
+pub trait OutputWriter {
+    fn write(&mut self, report: &Report) -> Result<(), Error>;
+
+    /// Called once all reports have been written
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// The names accepted by `--format`
+pub const FORMATS: &'static [&'static str] = &["tsv", "csv", "jsonl", "hdr-json", "prometheus"];
+
+/// Create a writer for the named format, putting its files into the given directory
+pub fn writer(format: &str, dir: &Path, times: TimeFormat) -> Result<Box<OutputWriter>, Error> {
+    fs::create_dir_all(dir)?;
+    let dir = dir.to_path_buf();
+    match format {
+        "tsv" => Ok(Box::new(DelimitedWriter::new(dir, b'\t', "tsv", times))),
+        "csv" => Ok(Box::new(DelimitedWriter::new(dir, b',', "csv", times))),
+        "jsonl" => Ok(Box::new(JsonLinesWriter::new(dir, false, times))),
+        "hdr-json" => Ok(Box::new(JsonLinesWriter::new(dir, true, times))),
+        "prometheus" => Ok(Box::new(PrometheusWriter::new(&dir)?)),
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown output format {:?}, expected one of {}", format, FORMATS.join(", ")))),
+    }
+}
+
+/// Writes each table to its own file, with columns separated by tabs or commas
+pub struct DelimitedWriter {
+    dir: PathBuf,
+    delimiter: u8,
+    extension: &'static str,
+    times: TimeFormat,
+}
+
+impl DelimitedWriter {
+    pub fn new(dir: PathBuf, delimiter: u8, extension: &'static str, times: TimeFormat) -> DelimitedWriter {
+        DelimitedWriter {
+            dir: dir,
+            delimiter: delimiter,
+            extension: extension,
+            times: times,
+        }
+    }
+}
+
+impl OutputWriter for DelimitedWriter {
+    fn write(&mut self, report: &Report) -> Result<(), Error> {
+        let histogram_table;
+        let table = match *report {
+            Report::Table(ref t) => t,
+            Report::Histograms(ref h) => {
+                histogram_table = h.to_table();
+                &histogram_table
+            },
+        };
+        let f = File::create(self.dir.join(format!("{}.{}", table.name, self.extension)))?;
+        write_delimited(table, self.delimiter, &self.times, &mut BufWriter::new(f))
+    }
+}
+
+pub fn write_delimited(table: &Table, delimiter: u8, times: &TimeFormat, out: &mut Write) -> Result<(), Error> {
+    let header = table.keys.iter().chain(table.columns.iter()).map(|c| &c[..] ).collect::<Vec<&str>>();
+    write_delimited_row(&header, delimiter, out)?;
+    for row in table.rows.iter() {
+        let cells = row.iter().map(|c| c.text(times) ).collect::<Vec<String>>();
+        write_delimited_row(&cells, delimiter, out)?;
+    }
+    Ok(())
+}
+
+fn write_delimited_row<S: AsRef<str>>(cells: &[S], delimiter: u8, out: &mut Write) -> Result<(), Error> {
+    for (i, cell) in cells.iter().enumerate() {
+        if i > 0 {
+            out.write_all(&[delimiter])?;
+        }
+        let cell = cell.as_ref();
+        if delimiter == b',' && cell.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r' ) {
+            write!(out, "\"{}\"", cell.replace('"', "\"\""))?;
+        } else if delimiter == b'\t' {
+            // TSV has no quoting, so just keep the columns intact
+            out.write_all(cell.replace(|c: char| c == '\t' || c == '\n' || c == '\r', " ").as_bytes())?;
+        } else {
+            out.write_all(cell.as_bytes())?;
+        }
+    }
+    writeln!(out, "")
+}
+
+/// Writes each table to its own file with a JSON object per row, keyed by column name.  When
+/// `preserve_histograms` is set, each timeslice's histogram is written out bucket by bucket,
+/// rather than as a table of counts.
+pub struct JsonLinesWriter {
+    dir: PathBuf,
+    preserve_histograms: bool,
+    times: TimeFormat,
+}
+
+impl JsonLinesWriter {
+    pub fn new(dir: PathBuf, preserve_histograms: bool, times: TimeFormat) -> JsonLinesWriter {
+        JsonLinesWriter {
+            dir: dir,
+            preserve_histograms: preserve_histograms,
+            times: times,
+        }
+    }
+
+    fn write_table(&self, table: &Table) -> Result<(), Error> {
+        let f = File::create(self.dir.join(format!("{}.jsonl", table.name)))?;
+        let mut out = BufWriter::new(f);
+        for row in table.rows.iter() {
+            write_json_line(&row_json(table, row, &self.times), &mut out)?;
+        }
+        Ok(())
+    }
+
+    fn write_histograms(&self, series: &HistogramSeries) -> Result<(), Error> {
+        let f = File::create(self.dir.join(format!("{}.hdr.jsonl", series.name)))?;
+        let mut out = BufWriter::new(f);
+        for &(ts, hist) in series.slices.iter() {
+            let mut obj = Map::new();
+            obj.insert("timeslice".to_string(), Cell::Time(ts).json(&self.times));
+            if let Some(width) = series.slice_end {
+                obj.insert(SLICE_END.to_string(), Cell::Time(ts + width).json(&self.times));
+            }
+            obj.insert("unit".to_string(), Value::from(&series.unit[..]));
+            obj.insert("significant_figures".to_string(), Value::from(series.total.sigfig() as u64));
+            match hist {
+                Some(hist) if hist.count() > 0 => {
+                    obj.insert("count".to_string(), Value::from(hist.count()));
+                    obj.insert("min".to_string(), Value::from(hist.min()));
+                    obj.insert("max".to_string(), Value::from(hist.max()));
+                    obj.insert("mean".to_string(), Value::from(hist.mean()));
+                    // each populated bucket as [highest equivalent value, count]
+                    let buckets = hist.iter_recorded()
+                        .map(|v| Value::from(vec![Value::from(v.value()), Value::from(v.count_at_value())]) )
+                        .collect::<Vec<Value>>();
+                    obj.insert("buckets".to_string(), Value::from(buckets));
+                },
+                _ => {
+                    obj.insert("count".to_string(), Value::from(0));
+                    obj.insert("buckets".to_string(), Value::Array(Vec::new()));
+                },
+            }
+            write_json_line(&Value::Object(obj), &mut out)?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputWriter for JsonLinesWriter {
+    fn write(&mut self, report: &Report) -> Result<(), Error> {
+        match *report {
+            Report::Table(ref t) => self.write_table(t),
+            Report::Histograms(ref h) if self.preserve_histograms => self.write_histograms(h),
+            Report::Histograms(ref h) => self.write_table(&h.to_table()),
+        }
+    }
+}
+
+/// A row of a table as a JSON object, keyed by column name
+fn row_json(table: &Table, row: &[Cell], times: &TimeFormat) -> Value {
+    let mut obj = Map::new();
+    for (name, cell) in table.keys.iter().chain(table.columns.iter()).zip(row.iter()) {
+        obj.insert(name.clone(), cell.json(times));
+    }
+    Value::Object(obj)
+}
+
+/// A whole table as a JSON array of row objects
+pub fn table_json(table: &Table, times: &TimeFormat) -> Value {
+    Value::Array(table.rows.iter().map(|row| row_json(table, row, times) ).collect())
+}
+
+fn write_json_line(value: &Value, out: &mut Write) -> Result<(), Error> {
+    serde_json::to_writer(&mut *out, value).map_err(|e| Error::new(ErrorKind::Other, e))?;
+    writeln!(out, "")
+}
+
+/// Writes all results into a single `metrics.prom` file in the OpenMetrics text format, with
+/// each sample timestamped with the start of its timeslice, suitable for backfilling into
+/// Prometheus
+pub struct PrometheusWriter<W: Write> {
+    out: W,
+}
+
+/// Prefix for the names of all metrics
+const METRIC_PREFIX: &'static str = "whatf_";
+
+impl PrometheusWriter<BufWriter<File>> {
+    pub fn new(dir: &Path) -> Result<PrometheusWriter<BufWriter<File>>, Error> {
+        Ok(PrometheusWriter::to(BufWriter::new(File::create(dir.join("metrics.prom"))?)))
+    }
+}
+
+impl<W: Write> PrometheusWriter<W> {
+    /// Write the metrics somewhere other than `metrics.prom`, e.g. into an HTTP response
+    pub fn to(out: W) -> PrometheusWriter<W> {
+        PrometheusWriter {
+            out: out,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write_table(&mut self, table: &Table) -> Result<(), Error> {
+        let ts_key = table.keys.iter().position(|k| k == "timeslice" );
+        match table.pivot {
+            Some(ref label) => {
+                let name = metric_name(&table.name);
+                writeln!(self.out, "# TYPE {} gauge", name)?;
+                for row in table.rows.iter() {
+                    for (i, column) in table.columns.iter().enumerate() {
+                        let extra = (&label[..], &column[..]);
+                        self.write_sample(&name, table, row, ts_key, Some(extra), &row[table.keys.len() + i])?;
+                    }
+                }
+            },
+            None => {
+                // every sample of a metric family must be written together
+                for (i, column) in table.columns.iter().enumerate() {
+                    let name = metric_name(&format!("{}_{}", table.name, column));
+                    writeln!(self.out, "# TYPE {} gauge", name)?;
+                    for row in table.rows.iter() {
+                        self.write_sample(&name, table, row, ts_key, None, &row[table.keys.len() + i])?;
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn write_sample(&mut self, name: &str, table: &Table, row: &[Cell], ts_key: Option<usize>, extra: Option<(&str, &str)>, value: &Cell) -> Result<(), Error> {
+        let value = match value.number() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let mut labels = table.keys.iter().zip(row.iter()).enumerate()
+            .filter(|&(i, (k, _))| Some(i) != ts_key && k != SLICE_END )
+            .map(|(_, (k, v))| (label_name(k), v.text(&TimeFormat::epoch())) )
+            .collect::<Vec<(String, String)>>();
+        if let Some((k, v)) = extra {
+            labels.push((label_name(k), v.to_string()));
+        }
+        write!(self.out, "{}", name)?;
+        write_labels(&mut self.out, &labels)?;
+        write!(self.out, " {}", value)?;
+        if let Some(ts) = ts_key.and_then(|i| row[i].number() ) {
+            write!(self.out, " {}", ts)?;
+        }
+        writeln!(self.out, "")
+    }
+
+    fn write_histograms(&mut self, series: &HistogramSeries) -> Result<(), Error> {
+        let name = metric_name(&format!("{}_{}", series.name, series.unit));
+        let bounds = series.bounds();
+        writeln!(self.out, "# TYPE {} histogram", name)?;
+        for &(ts, hist) in series.slices.iter() {
+            let mut cumulative = 0;
+            for (b, count) in bounds.iter().zip(bucket_counts(hist, &bounds)) {
+                cumulative += count;
+                writeln!(self.out, "{}_bucket{{le=\"{:.1}\"}} {} {}", name, *b as f64, cumulative, ts)?;
+            }
+            let (count, sum) = match hist {
+                Some(h) if h.count() > 0 => (h.count(), h.mean() * h.count() as f64),
+                _ => (0, 0.0),
+            };
+            writeln!(self.out, "{}_bucket{{le=\"+Inf\"}} {} {}", name, count, ts)?;
+            writeln!(self.out, "{}_count {} {}", name, count, ts)?;
+            writeln!(self.out, "{}_sum {} {}", name, sum, ts)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> OutputWriter for PrometheusWriter<W> {
+    fn write(&mut self, report: &Report) -> Result<(), Error> {
+        match *report {
+            Report::Table(ref t) => self.write_table(t),
+            Report::Histograms(ref h) => self.write_histograms(h),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        writeln!(self.out, "# EOF")?;
+        self.out.flush()
+    }
+}
+
+fn metric_name(name: &str) -> String {
+    format!("{}{}", METRIC_PREFIX, label_name(name))
+}
+
+/// Replace the characters not allowed in metric and label names, collapsing runs of them, so
+/// that e.g. `p99(response_time_micros)` becomes `p99_response_time_micros`
+fn label_name(name: &str) -> String {
+    let mut result = String::new();
+    for c in name.chars() {
+        if (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') || c == '_' {
+            result.push(c);
+        } else if !result.ends_with('_') {
+            result.push('_');
+        }
+    }
+    let result = result.trim_right_matches('_').to_string();
+    if result.chars().next().map(|c| c.is_digit(10) ).unwrap_or(true) {
+        format!("_{}", result)
+    } else {
+        result
+    }
+}
+
+fn write_labels(out: &mut Write, labels: &[(String, String)]) -> io::Result<()> {
+    if labels.is_empty() {
+        return Ok(());
+    }
+    write!(out, "{{")?;
+    for (i, &(ref k, ref v)) in labels.iter().enumerate() {
+        if i > 0 {
+            write!(out, ",")?;
+        }
+        let v = v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+        write!(out, "{}=\"{}\"", k, v)?;
+    }
+    write!(out, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> Table {
+        Table {
+            name: "by_status_timeslice".to_string(),
+            keys: vec!["timeslice".to_string()],
+            columns: vec!["200".to_string(), "404".to_string()],
+            pivot: Some("http_status".to_string()),
+            rows: vec![
+                vec![Cell::Time(1200), Cell::Int(10), Cell::Int(0)],
+                vec![Cell::Time(2400), Cell::Int(5), Cell::Int(1)],
+            ],
+        }
+    }
+
+    #[test]
+    fn delimited() {
+        let mut out = Vec::new();
+        write_delimited(&table(), b'\t', &TimeFormat::epoch(), &mut out).unwrap();
+        assert_eq!("timeslice\t200\t404\n1200\t10\t0\n2400\t5\t1\n", String::from_utf8(out).unwrap());
+        let t = Table {
+            name: "q".to_string(),
+            keys: vec!["request_useragent".to_string()],
+            columns: vec!["count".to_string()],
+            pivot: None,
+            rows: vec![vec![Cell::Str("a, \"b\"".to_string()), Cell::Int(1)]],
+        };
+        let mut out = Vec::new();
+        write_delimited(&t, b',', &TimeFormat::epoch(), &mut out).unwrap();
+        assert_eq!("request_useragent,count\n\"a, \"\"b\"\"\",1\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn floats_keep_their_precision() {
+        let times = TimeFormat::epoch();
+        assert_eq!("0.0004", Cell::Float(0.0004).text(&times));
+        assert_eq!("2.5", Cell::Float(2.5).text(&times));
+    }
+
+    #[test]
+    fn slice_end_and_times() {
+        let mut t = table();
+        t.add_slice_end(1200);
+        let times = TimeFormat {
+            style: TimeStyle::Iso8601,
+            zone: Zone::Offset(3600),
+        };
+        let mut out = Vec::new();
+        write_delimited(&t, b',', &times, &mut out).unwrap();
+        assert_eq!("timeslice,timeslice_end,200,404\n\
+                    1970-01-01T01:20:00+01:00,1970-01-01T01:40:00+01:00,10,0\n\
+                    1970-01-01T01:40:00+01:00,1970-01-01T02:00:00+01:00,5,1\n",
+                   String::from_utf8(out).unwrap());
+        let utc = TimeFormat { style: TimeStyle::Iso8601, zone: Zone::Utc };
+        assert_eq!("2017-03-01T00:00:00Z", utc.format(1488326400));
+    }
+
+    #[test]
+    fn zones() {
+        assert_eq!(Ok(Zone::Utc), Zone::parse("utc"));
+        assert_eq!(Ok(Zone::Offset(-(8 * 3600 + 30 * 60))), Zone::parse("-08:30"));
+        assert_eq!(Ok(Zone::Offset(3600)), Zone::parse("+0100"));
+        assert!(Zone::parse("Europe/London").is_err());
+        assert!(Zone::parse("+25:00").is_err());
+    }
+
+    #[test]
+    fn log_buckets() {
+        let mut hist = Histogram::<u64>::new(2).unwrap();
+        for v in &[500, 900, 1500, 3000, 100000] {
+            hist.record(*v).unwrap();
+        }
+        let series = HistogramSeries {
+            name: "servicetime_by_timeslice".to_string(),
+            unit: "response_time_micros".to_string(),
+            slices: vec![(0, Some(&hist)), (60, None)],
+            total: &hist,
+            slice_end: None,
+            buckets: Buckets::Log { first: 1000, base: 2, count: 3 },
+        };
+        let t = series.to_table();
+        assert_eq!(vec!["1000", "2000", "4000", "inf"], t.columns);
+        assert_eq!(vec![Cell::Time(0), Cell::Int(2), Cell::Int(1), Cell::Int(1), Cell::Int(1)], t.rows[0]);
+        assert_eq!(vec![Cell::Time(60), Cell::Int(0), Cell::Int(0), Cell::Int(0), Cell::Int(0)], t.rows[1]);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!("p99_response_time_micros", label_name("p99(response_time_micros)"));
+        assert_eq!("_200", label_name("200"));
+        assert_eq!("whatf_by_status_timeslice", metric_name("by_status_timeslice"));
+    }
+}
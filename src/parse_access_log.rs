@@ -1,193 +1,162 @@
-use std::str::from_utf8;
-use time::strptime;
 use time::Timespec;
 use std::io::Error;
-use std::io::Read;
+use std::io::BufRead;
 use std::io::ErrorKind;
 use process::Consumer;
-use urlparse;
+use logformat::{Layout, DEFAULT_HTTPD_LOG_FORMAT, DEFAULT_NGINX_LOG_FORMAT};
+use parse_aws_log::{ElbAccessLogParser, AlbAccessLogParser, CloudFrontAccessLogParser};
+use parse_error::{LineError, ParseError, FileReport, ErrorPolicy};
+use filter::{Filter, Period};
 
-fn string_from_slice(slice: &[u8]) -> Result<&str,Error> {
-    from_utf8(slice).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+/// Turns the lines of some particular log format into `Record` values
+pub trait LineParser {
+    /// Parse a single line, which will not include the line-ending.  Lines which are valid, but
+    /// which describe no request (headers, comments etc.) produce `Ok(None)`.
+    fn parse_line(&mut self, line: &[u8]) -> Result<Option<Record>, LineError>;
 }
 
-pub struct HttpdAccessLogParser {
+/// The number of parse errors to print in full for each file, before just counting the rest
+const MAX_PRINTED_ERRORS: u64 = 5;
+
+/// How the files of a particular datasource are to be parsed
+#[derive(Clone)]
+pub struct ParseSettings {
+    pub parser: LogParser,
+    pub policy: ErrorPolicy,
+    /// records not matching the filter are dropped before they reach the consumer
+    pub filter: Option<Filter>,
+    /// records with timestamps outside the `--period` range are dropped, as for the filter
+    pub period: Period,
+    /// avoid parsing files whose first and last records both lie on the same side of the period
+    pub skip_files_outside_period: bool,
 }
-impl HttpdAccessLogParser {
-    pub fn new() -> HttpdAccessLogParser {
-        HttpdAccessLogParser { }
-    }
 
-    // LogFormat "%t %h %l %u \"%r\" %>s %b \"%{Referer}i\" \"%{User-Agent}i\" \"%{Host}i\" %D \"%{X-Forwarded-For}i\" %{local}p %{cache-status}e %R" combined
-    pub fn process_lines<T: Read>(&self, mut data: T, consumer: &mut Consumer) -> Result<(), Error> {
-        let mut buf = Vec::new();
-        data.read_to_end(&mut buf)?;
-        let buf = buf;
-        let mut lineno: u64 = 0;
-        let mut idx = 0;
-        // TODO: line endings!
-        'lines: while idx < buf.len() {
-            lineno += 1;
-            if buf[idx] != b'[' {
-                println!("timestamp not there at line start {:?}", &buf[idx..idx+10]);
-                skip_to_eol(&buf, &mut idx);
-                continue 'lines;
-            }
-            idx += 1;
-            let mut line_date:Option<Timespec> = None;
-            let start = idx;
-            while idx < buf.len() {
-                if buf[idx] == b']' {
-                    let dat = string_from_slice(&buf[start..idx])?;
-                    let date = strptime(&dat, "%d/%b/%Y:%H:%M:%S %z");
-                    if let Err(e) = date {
-                        println!("date error line {}: {}", lineno, e);
-                        println!("timestamp");
-                        skip_to_eol(&buf, &mut idx);
-                        continue 'lines;
-                    }
-                    line_date = Some(date.unwrap().to_timespec());
-                    idx += 1;
-                    break;
+/// Feed each line from the given reader through the parser, passing the resulting records to
+/// the consumer.  Only one line is held in memory at a time.
+///
+/// If the returned report indicates a failure, the records already given to the consumer should
+/// be discarded.
+pub fn process_lines<T: BufRead>(settings: &ParseSettings, file: &str, mut data: T, consumer: &mut Consumer) -> Result<FileReport, Error> {
+    let mut parser = settings.parser.clone();
+    let mut report = FileReport::new(file);
+    let mut line = Vec::new();
+    let mut offset: u64 = 0;
+    loop {
+        line.clear();
+        let len = data.read_until(b'\n', &mut line)?;
+        if len == 0 {
+            break;
+        }
+        report.lines += 1;
+        let line_offset = offset;
+        offset += len as u64;
+        let mut end = line.len();
+        if end > 0 && line[end-1] == b'\n' {
+            end -= 1;
+        }
+        if end > 0 && line[end-1] == b'\r' {
+            end -= 1;
+        }
+        if end == 0 {
+            continue;
+        }
+        match parser.parse_line(&line[..end]) {
+            Ok(Some(record)) => {
+                if settings.period.contains(&record.timestamp) && settings.filter.as_ref().map(|f| f.matches(&record) ).unwrap_or(true) {
+                    consumer.handle(record);
+                } else {
+                    report.filtered += 1;
                 }
-                idx += 1;
-            }
-            if let None = line_date {
-                println!("timestamp not found in line");
-                skip_to_eol(&buf, &mut idx);
-                continue 'lines;
-            }
-
-            if !expect(&buf, &mut idx, b' ') {
-                println!("after timestamp");
-                skip_to_eol(&buf, &mut idx);
-                continue 'lines;
-            }
-
-            let remote_host = expect_field_ws(&buf, &mut idx).ok_or(invalid_data("remote_host"))?;
-            let remote_logname = expect_field_ws(&buf, &mut idx).ok_or(invalid_data("logname"))?;
-            let remote_user = expect_field_ws(&buf, &mut idx).ok_or(invalid_data("user"))?;
-            let request_line = expect_field_qot(&buf, &mut idx).ok_or(invalid_data("reqest-line"))?;
-            let mut itr = string_from_slice(request_line)?.split_whitespace();
-            let request_method = itr.next().ok_or(invalid_data("method"))?;
-            let request_uri = itr.next().ok_or(invalid_data("uri"))?;
-            let request_proto = itr.next().ok_or(invalid_data("protocol"))?;
-            if !expect(&buf, &mut idx, b' ') {
-                println!("before status");
-                skip_to_eol(&buf, &mut idx);
-                continue 'lines;
-            }
-            let response_status = expect_field_ws(&buf, &mut idx).ok_or(invalid_data("status"))?;
-            let bytes = expect_field_ws(&buf, &mut idx).ok_or(invalid_data("bytes"))?;
-            let response_bytes: Option<usize> = match bytes {
-                b"-" => None,
-                _ => Some(string_from_slice(bytes)?.parse().map_err(|_| invalid_data(&format!("bytes: {:?}", bytes)))?),
-            };
-            let request_referer = expect_field_qot(&buf, &mut idx).ok_or(invalid_data("referer"))?;
-            if !expect(&buf, &mut idx, b' ') {
-                println!("before agent");
-                skip_to_eol(&buf, &mut idx);
-                continue 'lines;
-            }
-            let request_useragent = expect_field_qot(&buf, &mut idx).ok_or(invalid_data("agent"))?;
-            if !expect(&buf, &mut idx, b' ') {
-                println!("before request_host");
-                skip_to_eol(&buf, &mut idx);
-                continue 'lines;
-            }
-            let request_host = expect_field_qot(&buf, &mut idx).ok_or(invalid_data("request_host"))?;
-            if !expect(&buf, &mut idx, b' ') {
-                println!("before service-time");
-                skip_to_eol(&buf, &mut idx);
-                continue 'lines;
-            }
-            let micros = expect_field_ws(&buf, &mut idx).ok_or(invalid_data("service-time"))?;
-            let response_time_micros: u64 = string_from_slice(micros)?.parse().map_err(|_| invalid_data("service-time"))?;
-            let request_forwarded_for = expect_field_qot(&buf, &mut idx).ok_or(invalid_data("forwarded-for"))?;
-            if !expect(&buf, &mut idx, b' ') {
-                println!("before port");
-                skip_to_eol(&buf, &mut idx);
-                continue 'lines;
-            }
-            let port = expect_field_ws(&buf, &mut idx).ok_or(invalid_data("port"))?;
-            let request_local_port: u32 = string_from_slice(port)?.parse().map_err(|_| invalid_data("port"))?;
-            let response_cache_status = expect_field_ws(&buf, &mut idx).ok_or(invalid_data("cache-status"))?;
-            let request_handler = expect_field_ws(&buf, &mut idx).ok_or(invalid_data("handler"))?;
-            let decoded_uri = urlparse::unquote(request_uri)
-                .unwrap_or_else(|e| {
-                    println!("failed to urldecode request uri {:?}", e);
-                    request_uri.to_string()
-                });
-
-            consumer.handle(Record{
-                timestamp: line_date.unwrap(),
-                remote_host: string_from_slice(remote_host)?.to_string(),
-                remote_logname: string_from_slice(remote_logname)?.to_string(),
-                remote_user: string_from_slice(remote_user)?.to_string(),
-                request_method: request_method.to_string(),
-                request_uri: decoded_uri,
-                request_proto: request_proto.to_string(),
-                response_status: string_from_slice(response_status)?.to_string(),
-                response_bytes: response_bytes,
-                request_referer: string_from_slice(request_referer)?.to_string(),
-                request_useragent: string_from_slice(request_useragent)?.to_string(),
-                request_host: string_from_slice(request_host)?.to_string(),
-                response_time_micros: response_time_micros,
-                request_forwarded_for: string_from_slice(request_forwarded_for)?.to_string(),
-                request_local_port: request_local_port,
-                response_cache_status: string_from_slice(response_cache_status)?.to_string(),
-                request_handler: string_from_slice(request_handler)?.to_string(),
-            });
+            },
+            Ok(None) => (),
+            Err(e) => {
+                let err = ParseError::new(file, report.lines, line_offset, &line[..end], e);
+                report.reject(&err);
+                if report.rejected <= MAX_PRINTED_ERRORS {
+                    println!("{}", err);
+                }
+                if let Some(failure) = report.check(&settings.policy, false) {
+                    report.failure = Some(failure);
+                    return Ok(report);
+                }
+            },
         }
-        Ok(())
     }
+    report.failure = report.check(&settings.policy, true);
+    Ok(report)
 }
 
-fn expect(data: &[u8], idx: &mut usize, expected: u8) -> bool {
-    let m = data[*idx] == expected;
-    *idx += 1;
-    m
+#[derive(Clone)]
+pub struct HttpdAccessLogParser {
+    layout: Layout,
+}
+impl HttpdAccessLogParser {
+    /// Create a parser for logs written with the given Apache `LogFormat` string
+    pub fn new(logformat: &str) -> Result<HttpdAccessLogParser, Error> {
+        Ok(HttpdAccessLogParser {
+            layout: Layout::httpd(logformat)?,
+        })
+    }
+}
+impl LineParser for HttpdAccessLogParser {
+    fn parse_line(&mut self, line: &[u8]) -> Result<Option<Record>, LineError> {
+        self.layout.parse(line).map(Some)
+    }
 }
 
-fn skip_to_eol<'a>(data: &'a [u8], idx: &mut usize) {
-    let start = *idx;
-    for i in start..data.len() {
-        if data[i] == b'\n' {
-            *idx = i+1;
-            return;
-        }
+#[derive(Clone)]
+pub struct NginxAccessLogParser {
+    layout: Layout,
+}
+impl NginxAccessLogParser {
+    /// Create a parser for logs written with the given nginx `log_format` string
+    pub fn new(log_format: &str) -> Result<NginxAccessLogParser, Error> {
+        Ok(NginxAccessLogParser {
+            layout: Layout::nginx(log_format)?,
+        })
     }
-    *idx = data.len();
 }
-fn expect_field_ws<'a>(data: &'a [u8], idx: &mut usize) -> Option<(&'a [u8])> {
-    let start = *idx;
-    for i in start..data.len() {
-        // TODO: line endings
-        if data[i] == b' ' || data[i] == b'\n' {
-            *idx = i+1;
-            return Some(&data[start..i]);
-        }
+impl LineParser for NginxAccessLogParser {
+    fn parse_line(&mut self, line: &[u8]) -> Result<Option<Record>, LineError> {
+        self.layout.parse(line).map(Some)
     }
-    *idx = data.len();
-    Some(&data[start..])
 }
 
-fn expect_field_qot<'a>(data: &'a [u8], mut idx: &mut usize) -> Option<(&'a [u8])> {
-    if !expect(data, &mut idx, b'"') {
-        return None;
+/// The parser selected for a datasource.  Worker threads take a fresh clone of this for each
+/// file that they process.
+#[derive(Clone)]
+pub enum LogParser {
+    Httpd(HttpdAccessLogParser),
+    Nginx(NginxAccessLogParser),
+    Elb(ElbAccessLogParser),
+    Alb(AlbAccessLogParser),
+    CloudFront(CloudFrontAccessLogParser),
+}
+impl LogParser {
+    /// `format` names the kind of log (`"httpd"`, `"nginx"`, `"elb"`, `"alb"` or
+    /// `"cloudfront"`), and `logformat` gives the server's own description of the line layout,
+    /// where that is configurable
+    pub fn new(format: &str, logformat: Option<&str>) -> Result<LogParser, Error> {
+        match format {
+            "httpd" => Ok(LogParser::Httpd(HttpdAccessLogParser::new(logformat.unwrap_or(DEFAULT_HTTPD_LOG_FORMAT))?)),
+            "nginx" => Ok(LogParser::Nginx(NginxAccessLogParser::new(logformat.unwrap_or(DEFAULT_NGINX_LOG_FORMAT))?)),
+            "elb" => Ok(LogParser::Elb(ElbAccessLogParser::new())),
+            "alb" => Ok(LogParser::Alb(AlbAccessLogParser::new())),
+            "cloudfront" => Ok(LogParser::CloudFront(CloudFrontAccessLogParser::new())),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown log format {:?}", format))),
+        }
     }
-    let start = *idx + 1;
-    let mut i = start;
-    while i < data.len() {
-        if data[i] == b'\\' {
-            i += 1;
-        } else if data[i] == b'"' {
-            *idx = i+1;
-            return Some(&data[start..i]);
+}
+impl LineParser for LogParser {
+    fn parse_line(&mut self, line: &[u8]) -> Result<Option<Record>, LineError> {
+        match *self {
+            LogParser::Httpd(ref mut p) => p.parse_line(line),
+            LogParser::Nginx(ref mut p) => p.parse_line(line),
+            LogParser::Elb(ref mut p) => p.parse_line(line),
+            LogParser::Alb(ref mut p) => p.parse_line(line),
+            LogParser::CloudFront(ref mut p) => p.parse_line(line),
         }
-        i += 1;
     }
-    None
 }
 
 #[derive(Debug)]
@@ -211,7 +180,28 @@ pub struct Record {
     pub request_handler: String,
 }
 
-fn invalid_data(msg: &str) -> Error {
-    Error::new(ErrorKind::InvalidData, msg.to_string())
+impl Default for Record {
+    /// A record with every field empty, for parsers to fill in from whatever the log format
+    /// happens to provide
+    fn default() -> Record {
+        Record {
+            timestamp: Timespec::new(0, 0),
+            remote_host: String::new(),
+            remote_logname: String::new(),
+            remote_user: String::new(),
+            request_method: String::new(),
+            request_uri: String::new(),
+            request_proto: String::new(),
+            response_status: String::new(),
+            response_bytes: None,
+            request_referer: String::new(),
+            request_useragent: String::new(),
+            request_host: String::new(),
+            response_time_micros: 0,
+            request_forwarded_for: String::new(),
+            request_local_port: 0,
+            response_cache_status: String::new(),
+            request_handler: String::new(),
+        }
+    }
 }
-
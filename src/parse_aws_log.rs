@@ -0,0 +1,289 @@
+use std::io::Error;
+use time::strptime;
+use logformat::{Field, parse_fractional_micros, decode_uri, string_from_slice, invalid_data};
+use parse_access_log::{LineParser, Record};
+use parse_error::LineError;
+
+// Parsers for the access logs that AWS services deliver into S3 buckets,
+//  - http://docs.aws.amazon.com/elasticloadbalancing/latest/classic/access-log-collection.html
+//  - http://docs.aws.amazon.com/elasticloadbalancing/latest/application/load-balancer-access-logs.html
+//  - http://docs.aws.amazon.com/AmazonCloudFront/latest/DeveloperGuide/AccessLogs.html
+
+#[derive(Debug,Clone)]
+enum Column {
+    Field(Field),
+    /// `address:port`
+    Client,
+    /// one of several processing-time values which are summed to give the service time, or `-1`
+    /// if the load balancer could not dispatch the request
+    ProcessingTime,
+    /// a request line containing an absolute URL, e.g. `GET http://example.com:80/ HTTP/1.1`
+    AbsoluteRequestLine,
+    /// the date part of a timestamp split over two columns
+    Date,
+    /// the time part of a timestamp split over two columns
+    Time,
+}
+
+impl Column {
+    fn name(&self) -> &'static str {
+        match *self {
+            Column::Field(ref f) => f.name(),
+            Column::Client => "remote_host",
+            Column::ProcessingTime => "response_time_micros",
+            Column::AbsoluteRequestLine => "request_line",
+            Column::Date | Column::Time => "timestamp",
+        }
+    }
+}
+
+const ELB_TIMESTAMP: &'static str = "%Y-%m-%dT%H:%M:%S.%f%z";
+
+fn elb_columns() -> Vec<Column> {
+    vec![
+        Column::Field(Field::Timestamp(ELB_TIMESTAMP.to_string())),
+        Column::Field(Field::Ignored),  // elb
+        Column::Client,
+        Column::Field(Field::Ignored),  // backend:port
+        Column::ProcessingTime,         // request_processing_time
+        Column::ProcessingTime,         // backend_processing_time
+        Column::ProcessingTime,         // response_processing_time
+        Column::Field(Field::Status),   // elb_status_code
+        Column::Field(Field::Ignored),  // backend_status_code
+        Column::Field(Field::Ignored),  // received_bytes
+        Column::Field(Field::Bytes),    // sent_bytes
+        Column::AbsoluteRequestLine,
+        Column::Field(Field::UserAgent),
+    ]
+}
+
+fn alb_columns() -> Vec<Column> {
+    let mut cols = vec![Column::Field(Field::Ignored)];  // type
+    cols.extend(elb_columns());
+    cols
+}
+
+const CLOUDFRONT_DEFAULT_FIELDS: &'static str = "date time x-edge-location sc-bytes c-ip cs-method cs(Host) cs-uri-stem sc-status cs(Referer) cs(User-Agent) cs-uri-query cs(Cookie) x-edge-result-type x-edge-request-id x-host-header cs-protocol cs-bytes time-taken x-forwarded-for ssl-protocol ssl-cipher x-edge-response-result-type cs-protocol-version";
+
+fn cloudfront_columns(fields: &str) -> Vec<Column> {
+    // cs(Host) is the CloudFront distribution's own domain name; prefer the Host header the
+    // viewer actually sent, when that is available
+    let has_host_header = fields.split_whitespace().any(|f| f == "x-host-header" );
+    fields.split_whitespace().map(|f| {
+        match f {
+            "date" => Column::Date,
+            "time" => Column::Time,
+            "c-ip" => Column::Field(Field::RemoteHost),
+            "cs-method" => Column::Field(Field::RequestMethod),
+            "cs(Host)" if !has_host_header => Column::Field(Field::Host),
+            "x-host-header" => Column::Field(Field::Host),
+            "cs-uri-stem" => Column::Field(Field::RequestPath),
+            "cs-uri-query" => Column::Field(Field::RequestArgs),
+            "sc-status" => Column::Field(Field::Status),
+            "sc-bytes" => Column::Field(Field::Bytes),
+            "cs(Referer)" => Column::Field(Field::Referer),
+            "cs(User-Agent)" => Column::Field(Field::UserAgent),
+            "x-edge-result-type" => Column::Field(Field::CacheStatus),
+            "time-taken" => Column::Field(Field::ServiceTimeFractionalSeconds),
+            "x-forwarded-for" => Column::Field(Field::ForwardedFor),
+            "cs-protocol-version" => Column::Field(Field::RequestProto),
+            _ => Column::Field(Field::Ignored),
+        }
+    }).collect()
+}
+
+fn apply_columns(columns: &[Column], values: &[&[u8]]) -> Result<Record, LineError> {
+    if values.len() < columns.len() {
+        return Err(LineError::missing_fields(columns.len(), values.len()));
+    }
+    let mut r = Record::default();
+    let mut date = None;
+    let mut time = None;
+    for (col, val) in columns.iter().zip(values.iter()) {
+        apply_column(col, *val, &mut r, &mut date, &mut time)
+            .map_err(|e| LineError::invalid_value(col.name(), None, e) )?;
+    }
+    if let (Some(date), Some(time)) = (date, time) {
+        let datetime = format!("{} {}", date, time);
+        let tm = strptime(&datetime, "%Y-%m-%d %H:%M:%S")
+            .map_err(|e| LineError::invalid_value("timestamp", None, invalid_data(&format!("{:?}: {}", datetime, e))) )?;
+        r.timestamp = tm.to_timespec();
+    }
+    Ok(r)
+}
+
+fn apply_column<'a>(col: &Column, val: &'a [u8], r: &mut Record, date: &mut Option<&'a str>, time: &mut Option<&'a str>) -> Result<(), Error> {
+    match *col {
+        Column::Field(ref f) => f.apply(val, r)?,
+        Column::Client => {
+            let s = string_from_slice(val)?;
+            r.remote_host = match s.rfind(':') {
+                Some(i) => s[..i].to_string(),
+                None => s.to_string(),
+            };
+        },
+        Column::ProcessingTime => {
+            if val != &b"-1"[..] {
+                r.response_time_micros += parse_fractional_micros(val).ok_or_else(|| invalid_data(&format!("{:?}", String::from_utf8_lossy(val))))?;
+            }
+        },
+        Column::AbsoluteRequestLine => parse_absolute_request_line(val, r)?,
+        Column::Date => *date = Some(string_from_slice(val)?),
+        Column::Time => *time = Some(string_from_slice(val)?),
+    }
+    Ok(())
+}
+
+fn parse_absolute_request_line(value: &[u8], r: &mut Record) -> Result<(), Error> {
+    let mut itr = string_from_slice(value)?.split_whitespace();
+    r.request_method = itr.next().ok_or(invalid_data("method"))?.to_string();
+    let url = itr.next().ok_or(invalid_data("url"))?;
+    r.request_proto = itr.next().unwrap_or("-").to_string();
+    let without_scheme = match url.find("://") {
+        Some(i) => &url[i+3..],
+        None => {
+            // e.g. "- - - " for requests the load balancer could not make sense of
+            r.request_uri = url.to_string();
+            return Ok(());
+        },
+    };
+    let (authority, path) = match without_scheme.find('/') {
+        Some(i) => (&without_scheme[..i], &without_scheme[i..]),
+        None => (without_scheme, "/"),
+    };
+    r.request_host = match authority.rfind(':') {
+        Some(i) => authority[..i].to_string(),
+        None => authority.to_string(),
+    };
+    r.request_uri = decode_uri(path);
+    Ok(())
+}
+
+/// Split on spaces, except within double-quoted values (which are returned without their quotes)
+fn split_quoted(line: &[u8]) -> Vec<&[u8]> {
+    let mut values = Vec::new();
+    let mut i = 0;
+    while i < line.len() {
+        if line[i] == b'"' {
+            let start = i + 1;
+            i = start;
+            while i < line.len() && line[i] != b'"' {
+                if line[i] == b'\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            values.push(&line[start..::std::cmp::min(i, line.len())]);
+            i += 1;
+        } else {
+            let start = i;
+            while i < line.len() && line[i] != b' ' {
+                i += 1;
+            }
+            values.push(&line[start..i]);
+        }
+        // skip the separator
+        i += 1;
+    }
+    values
+}
+
+/// Classic Elastic Load Balancer access logs
+#[derive(Clone)]
+pub struct ElbAccessLogParser {
+    columns: Vec<Column>,
+}
+impl ElbAccessLogParser {
+    pub fn new() -> ElbAccessLogParser {
+        ElbAccessLogParser { columns: elb_columns() }
+    }
+}
+impl LineParser for ElbAccessLogParser {
+    fn parse_line(&mut self, line: &[u8]) -> Result<Option<Record>, LineError> {
+        apply_columns(&self.columns, &split_quoted(line)).map(Some)
+    }
+}
+
+/// Application Load Balancer access logs
+#[derive(Clone)]
+pub struct AlbAccessLogParser {
+    columns: Vec<Column>,
+}
+impl AlbAccessLogParser {
+    pub fn new() -> AlbAccessLogParser {
+        AlbAccessLogParser { columns: alb_columns() }
+    }
+}
+impl LineParser for AlbAccessLogParser {
+    fn parse_line(&mut self, line: &[u8]) -> Result<Option<Record>, LineError> {
+        apply_columns(&self.columns, &split_quoted(line)).map(Some)
+    }
+}
+
+/// CloudFront web distribution access logs.  The layout of the tab-separated values is taken from
+/// the `#Fields:` header at the top of each file.
+#[derive(Clone)]
+pub struct CloudFrontAccessLogParser {
+    columns: Vec<Column>,
+}
+impl CloudFrontAccessLogParser {
+    pub fn new() -> CloudFrontAccessLogParser {
+        CloudFrontAccessLogParser { columns: cloudfront_columns(CLOUDFRONT_DEFAULT_FIELDS) }
+    }
+}
+impl LineParser for CloudFrontAccessLogParser {
+    fn parse_line(&mut self, line: &[u8]) -> Result<Option<Record>, LineError> {
+        if line.starts_with(b"#") {
+            if line.starts_with(b"#Fields:") {
+                let fields = string_from_slice(&line[8..])
+                    .map_err(|e| LineError::invalid_value("#Fields", Some(8), e) )?;
+                self.columns = cloudfront_columns(fields);
+            }
+            return Ok(None);
+        }
+        let values: Vec<&[u8]> = line.split(|b| *b == b'\t').collect();
+        apply_columns(&self.columns, &values).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parse_access_log::LineParser;
+
+    #[test]
+    fn alb() {
+        let mut p = AlbAccessLogParser::new();
+        let line = b"http 2016-08-10T22:08:42.945958Z app/my-loadbalancer/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.000 0.001 0.000 200 200 34 366 \"GET http://www.example.com:80/a%20b?c HTTP/1.1\" \"curl/7.46.0\" - - arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067 \"Root=1-58337262-36d228ad5d99923122bbe354\"";
+        let r = p.parse_line(line).unwrap().unwrap();
+        assert_eq!(1470866922, r.timestamp.sec);
+        assert_eq!("192.168.131.39", r.remote_host);
+        assert_eq!("www.example.com", r.request_host);
+        assert_eq!("/a b?c", r.request_uri);
+        assert_eq!("200", r.response_status);
+        assert_eq!(Some(366), r.response_bytes);
+        assert_eq!(1000, r.response_time_micros);
+        assert_eq!("curl/7.46.0", r.request_useragent);
+    }
+
+    #[test]
+    fn elb_not_dispatched() {
+        let mut p = ElbAccessLogParser::new();
+        let line = b"2015-05-13T23:39:43.945958Z my-loadbalancer 192.168.131.39:2817 - -1 -1 -1 504 0 0 0 \"GET http://www.example.com:80/ HTTP/1.1\" \"curl/7.38.0\" - -";
+        let r = p.parse_line(line).unwrap().unwrap();
+        assert_eq!("504", r.response_status);
+        assert_eq!(0, r.response_time_micros);
+    }
+
+    #[test]
+    fn cloudfront() {
+        let mut p = CloudFrontAccessLogParser::new();
+        assert!(p.parse_line(b"#Version: 1.0").unwrap().is_none());
+        assert!(p.parse_line(b"#Fields: date time c-ip cs-uri-stem sc-status x-edge-result-type time-taken").unwrap().is_none());
+        let r = p.parse_line(b"2014-05-23\t01:13:11\t192.0.2.10\t/view/my/file.html\t200\tHit\t0.012").unwrap().unwrap();
+        assert_eq!(1400807591, r.timestamp.sec);
+        assert_eq!("/view/my/file.html", r.request_uri);
+        assert_eq!("Hit", r.response_cache_status);
+        assert_eq!(12000, r.response_time_micros);
+    }
+}
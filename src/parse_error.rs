@@ -0,0 +1,230 @@
+use std::fmt;
+use std::io;
+use std::collections::BTreeMap;
+
+/// Broad categories of problem found when parsing a log line
+#[derive(Debug,Clone,Copy,Hash,Eq,PartialEq,Ord,PartialOrd)]
+pub enum ParseErrorKind {
+    /// the line did not have the literal text expected between fields
+    Layout,
+    /// the line had fewer fields than the format requires
+    MissingFields,
+    /// a field was present, but its value could not be interpreted
+    InvalidValue,
+}
+
+/// A problem found by a `LineParser`, which knows nothing about the file that the line came from
+#[derive(Debug)]
+pub struct LineError {
+    pub kind: ParseErrorKind,
+    /// the field being parsed when the problem was found, if any
+    pub field: Option<&'static str>,
+    /// position within the line at which the problem was found, if known
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl LineError {
+    pub fn layout(field: Option<&'static str>, column: usize, message: String) -> LineError {
+        LineError {
+            kind: ParseErrorKind::Layout,
+            field: field,
+            column: Some(column),
+            message: message,
+        }
+    }
+
+    pub fn missing_fields(expected: usize, found: usize) -> LineError {
+        LineError {
+            kind: ParseErrorKind::MissingFields,
+            field: None,
+            column: None,
+            message: format!("expected at least {} fields, but found {}", expected, found),
+        }
+    }
+
+    pub fn invalid_value(field: &'static str, column: Option<usize>, e: io::Error) -> LineError {
+        LineError {
+            kind: ParseErrorKind::InvalidValue,
+            field: Some(field),
+            column: column,
+            message: e.to_string(),
+        }
+    }
+}
+
+/// A `LineError`, together with the details needed to track down the offending line
+#[derive(Debug,Clone)]
+pub struct ParseError {
+    pub file: String,
+    pub line: u64,
+    /// byte offset within the (decompressed) file at which the problem was found
+    pub offset: u64,
+    pub field: Option<&'static str>,
+    pub kind: ParseErrorKind,
+    pub message: String,
+    pub raw_line: String,
+}
+
+impl ParseError {
+    pub fn new(file: &str, line: u64, line_offset: u64, raw_line: &[u8], e: LineError) -> ParseError {
+        ParseError {
+            file: file.to_string(),
+            line: line,
+            offset: line_offset + e.column.unwrap_or(0) as u64,
+            field: e.field,
+            kind: e.kind,
+            message: e.message,
+            raw_line: String::from_utf8_lossy(raw_line).into_owned(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{} (byte {}): {:?}", self.file, self.line, self.offset, self.kind)?;
+        if let Some(field) = self.field {
+            write!(f, " in {}", field)?;
+        }
+        write!(f, ": {}\n    {}", self.message, self.raw_line)
+    }
+}
+
+/// What to do with a file when some of its lines can't be parsed
+#[derive(Debug,Clone)]
+pub enum ErrorPolicy {
+    /// skip bad lines, however many there are
+    Skip,
+    /// skip bad lines, but give up on the file once more than this many have been seen
+    MaxCount(u64),
+    /// skip bad lines, but reject the whole file if more than this fraction of its lines are bad
+    MaxRate(f64),
+    /// give up on the file at the first bad line
+    Fail,
+}
+
+/// The outcome of parsing a single file
+#[derive(Debug)]
+pub struct FileReport {
+    pub file: String,
+    pub lines: u64,
+    pub rejected: u64,
+    pub rejected_by_kind: BTreeMap<ParseErrorKind, u64>,
+    /// records which parsed successfully, but which the filter or `--period` excluded
+    pub filtered: u64,
+    /// set when the results from the file were discarded, either because of an I/O error or
+    /// because the error policy was breached
+    pub failure: Option<String>,
+}
+
+impl FileReport {
+    pub fn new(file: &str) -> FileReport {
+        FileReport {
+            file: file.to_string(),
+            lines: 0,
+            rejected: 0,
+            rejected_by_kind: BTreeMap::new(),
+            filtered: 0,
+            failure: None,
+        }
+    }
+
+    pub fn failed(file: &str, e: io::Error) -> FileReport {
+        let mut report = FileReport::new(file);
+        report.failure = Some(e.to_string());
+        report
+    }
+
+    pub fn reject(&mut self, e: &ParseError) {
+        self.rejected += 1;
+        *self.rejected_by_kind.entry(e.kind).or_insert(0) += 1;
+    }
+
+    /// Check the counts so far against the policy, returning a description of the problem if the
+    /// file should be abandoned.  `complete` indicates that the whole file has been seen.
+    pub fn check(&self, policy: &ErrorPolicy, complete: bool) -> Option<String> {
+        match *policy {
+            ErrorPolicy::Skip => None,
+            ErrorPolicy::Fail if self.rejected > 0 => {
+                Some("rejected line, and error policy is 'fail'".to_string())
+            },
+            ErrorPolicy::MaxCount(max) if self.rejected > max => {
+                Some(format!("more than {} rejected lines", max))
+            },
+            ErrorPolicy::MaxRate(max) if complete && self.lines > 0 => {
+                let rate = self.rejected as f64 / self.lines as f64;
+                if rate > max {
+                    Some(format!("{:.2}% of lines rejected, exceeding limit of {:.2}%", rate * 100.0, max * 100.0))
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Totals of the problems seen over the course of a whole run
+pub struct ErrorSummary {
+    files: u64,
+    lines: u64,
+    filtered: u64,
+    rejected_by_kind: BTreeMap<ParseErrorKind, u64>,
+    /// only files which had some problem are retained
+    problem_files: Vec<FileReport>,
+}
+
+impl ErrorSummary {
+    pub fn new() -> ErrorSummary {
+        ErrorSummary {
+            files: 0,
+            lines: 0,
+            filtered: 0,
+            rejected_by_kind: BTreeMap::new(),
+            problem_files: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, report: FileReport) {
+        self.files += 1;
+        self.lines += report.lines;
+        self.filtered += report.filtered;
+        for (kind, count) in report.rejected_by_kind.iter() {
+            *self.rejected_by_kind.entry(*kind).or_insert(0) += *count;
+        }
+        if report.rejected > 0 || report.failure.is_some() {
+            self.problem_files.push(report);
+        }
+    }
+
+    pub fn print(&self) {
+        if self.filtered > 0 {
+            println!("{} records excluded by filter or period", self.filtered);
+        }
+        if self.problem_files.is_empty() {
+            println!("{} files, {} lines, no parse errors", self.files, self.lines);
+            return;
+        }
+        println!("Parse errors:");
+        for report in self.problem_files.iter() {
+            print!("  {}: {} of {} lines rejected", report.file, report.rejected, report.lines);
+            for (kind, count) in report.rejected_by_kind.iter() {
+                print!(", {:?}={}", kind, count);
+            }
+            if let Some(ref failure) = report.failure {
+                print!(" -- FILE DISCARDED: {}", failure);
+            }
+            println!("");
+        }
+        let total: u64 = self.rejected_by_kind.values().sum();
+        print!("{} files, {} lines, {} rejected", self.files, self.lines, total);
+        for (kind, count) in self.rejected_by_kind.iter() {
+            print!(", {:?}={}", kind, count);
+        }
+        println!("");
+        let discarded = self.problem_files.iter().filter(|r| r.failure.is_some() ).count();
+        if discarded > 0 {
+            println!("{} files discarded", discarded);
+        }
+    }
+}
@@ -1,57 +1,54 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::io::Write;
-use regex::RegexSet;
+use std::sync::Arc;
 use parse_access_log::Record;
 use std::io::Error;
+use std::io::ErrorKind;
+use std::io::{Read, Write, BufReader, BufWriter};
+use std::fs::File;
+use std::path::Path;
 use time::Timespec;
 use hdrsample::Histogram;
+use classify::Classifier;
+use query::{Query, QueryResult};
+use topn::{TopConfig, TopResult};
+use unique::{UniqueConfig, UniqueResult};
+use bytes::BytesResult;
+use cache::{CacheResult, OutcomeHistograms};
+use breakdown::{BreakdownConfig, BreakdownResult};
+use anomaly;
+use anomaly::AnomalyConfig;
+use snapshot::{SnapshotWriter, SnapshotReader, invalid_data};
+use output::{Buckets, Cell, Table, HistogramSeries, Report, ReportOptions, percentile_table};
 
-#[derive(Debug,Clone,Copy,Hash,Eq,PartialEq)]
-enum UriType {
-    HdsBootstrap,
-    HlsSegment,
-    HdsSegment,
-    HlsMediaManifest,
-    HlsMasterManifest,
-    HdsF4mManifest,
-    DashInitialisationSegment,
-    DashSegment,
-    DashManifest,
-    Admin,
-    UnknownOther,
+/// Settings shared by every `Consumer` taking part in a run
+pub struct ConsumerConfig {
+    pub classifier: Classifier,
+    /// width of each timeslice, in seconds
+    pub slice_seconds: i64,
+    /// an optional `--group-by`/`--agg` query to run alongside the standard reports
+    pub query: Option<Query>,
+    /// which fields to report the most frequent values of, if any
+    pub top: Option<TopConfig>,
+    /// what identifies a client, if distinct clients are to be counted
+    pub unique: Option<UniqueConfig>,
+    /// which fields to give separate counts, statuses and service times for, if any
+    pub breakdown: Option<BreakdownConfig>,
 }
 
-fn classify(uri: &str) -> UriType {
-    lazy_static! {
-        static ref RSET: RegexSet = RegexSet::new(&[
-            r"\.bootstrap",
-            r"/[^/]+.ts",
-            r"-Seg1-Frag(\d+)",
-            r"(?:audio=|video=)[^/]+\.m3u8",
-            r"\.m3u8",
-            r"\.f4m",
-            r"\.dash",
-            r"\.m4s",
-            r"\.mpd",
-            r"/test\.txt$|/Manifest?iss_client_manifest_version=22$|/archive-segment-length-seconds$|/state$|/statistics$|/servicePaths.txt$|/server-status$",
-        ]).unwrap();
-    }
-
-    match RSET.matches(uri).into_iter().next() {
-        Some(0) => UriType::HdsBootstrap,
-        Some(1) => UriType::HlsSegment,
-        Some(2) => UriType::HdsSegment,
-        Some(3) => UriType::HlsMediaManifest,
-        Some(4) => UriType::HlsMasterManifest,
-        Some(5) => UriType::HdsF4mManifest,
-        Some(6) => UriType::DashInitialisationSegment,
-        Some(7) => UriType::DashSegment,
-        Some(8) => UriType::DashManifest,
-        Some(9) => UriType::Admin,
-        Some(n) => panic!("Unexpected RegexSet index {}", n),
-        None    => UriType::UnknownOther,
-    }
+/// The results of one of the reports a `Consumer` keeps alongside its own counts, which are
+/// merged from other consumers and saved in snapshots along with them
+pub trait SliceResult: Sized {
+    /// the settings the results were gathered with, or `()` if they don't depend on any
+    type Config;
+
+    /// Merge another result into this one, mapping timeslices onto the given slice width and
+    /// leaving out those that `keep` rejects
+    fn absorb(&mut self, config: &Self::Config, other: &Self, slice_seconds: i64, keep: &Fn(i64) -> bool);
+
+    fn write_snapshot(&self, w: &mut SnapshotWriter) -> Result<(), Error>;
+
+    fn read_snapshot(r: &mut SnapshotReader) -> Result<Self, Error>;
 }
 
 #[derive(Debug,Clone,Hash,Eq,PartialEq)]
@@ -63,22 +60,46 @@ struct KeyStatusTimeslice {
 #[derive(Debug,Clone,Hash,Eq,PartialEq)]
 struct KeyUritypeTimeslice {
     timeslice: i64,
-    uritype: UriType,
+    uritype: String,
+}
+
+/// Counts for the values of a named capture group from the URI classification rules
+#[derive(Debug,Clone,Hash,Eq,PartialEq)]
+struct KeyCaptureTimeslice {
+    timeslice: i64,
+    name: String,
+    value: String,
 }
 
 pub struct Consumer {
+    config: Arc<ConsumerConfig>,
+    slice_seconds: i64,
     servicetime_hist_by_timeslice: HashMap<i64,Histogram<u64>>,
     servicetime_hist: Histogram<u64>,
     by_status_timeslice: HashMap<KeyStatusTimeslice,u64>,
     timeslices: HashSet<i64>,
     statuses: HashSet<String>,
     by_uritype_timeslice: HashMap<KeyUritypeTimeslice,u64>,
-    uritypes: HashSet<UriType>,
+    uritypes: HashSet<String>,
+    by_capture_timeslice: HashMap<KeyCaptureTimeslice,u64>,
+    captures: HashMap<String,HashSet<String>>,
+    query_result: QueryResult,
+    top_result: TopResult,
+    unique_result: UniqueResult,
+    bytes_result: BytesResult,
+    cache_result: CacheResult,
+    breakdown_result: BreakdownResult,
 }
 
 impl Consumer {
-    pub fn new() -> Consumer {
+    pub fn new(config: Arc<ConsumerConfig>) -> Consumer {
+        let slice_seconds = config.slice_seconds;
+        Consumer::with_slice(config, slice_seconds)
+    }
+    fn with_slice(config: Arc<ConsumerConfig>, slice_seconds: i64) -> Consumer {
         Consumer {
+            config: config,
+            slice_seconds: slice_seconds,
             servicetime_hist_by_timeslice: HashMap::new(),
             servicetime_hist: Histogram::new(1).unwrap(),
             by_status_timeslice: HashMap::new(),
@@ -86,13 +107,37 @@ impl Consumer {
             timeslices: HashSet::new(),
             statuses: HashSet::new(),
             uritypes: HashSet::new(),
+            by_capture_timeslice: HashMap::new(),
+            captures: HashMap::new(),
+            query_result: QueryResult::new(),
+            top_result: TopResult::new(),
+            unique_result: UniqueResult::new(),
+            bytes_result: BytesResult::new(),
+            cache_result: CacheResult::new(),
+            breakdown_result: BreakdownResult::new(),
         }
     }
     pub fn handle(&mut self, r: Record) {
-        let slice = timeslice(r.timestamp, 1200);
+        let slice = timeslice(r.timestamp, self.slice_seconds);
         self.timeslices.insert(slice);
+        let config = self.config.clone();
+        let classification = config.classifier.classify(&r.request_uri);
+        if let Some(ref query) = config.query {
+            self.query_result.record(query, &r, slice, classification.label);
+        }
+        if let Some(ref top) = config.top {
+            self.top_result.record(top, &r, slice);
+        }
+        if let Some(ref unique) = config.unique {
+            self.unique_result.record(unique, &r, slice);
+        }
+        if let Some(ref breakdown) = config.breakdown {
+            self.breakdown_result.record(breakdown, &r, slice);
+        }
+        self.bytes_result.record(&r, slice, classification.label);
+        self.cache_result.record(&r, slice, classification.label);
+        self.record_uritype(slice, classification.label, classification.captures);
         self.record_http_status(slice, r.response_status);
-        self.record_uritype(slice, &r.request_uri);
         self.record_service_time(slice, r.response_time_micros);
     }
     fn record_http_status(&mut self, slice: i64, response_status: String) {
@@ -103,112 +148,658 @@ impl Consumer {
         *self.by_status_timeslice.entry(key_status_timeslice).or_insert(0) += 1;
         self.statuses.insert(response_status);
     }
-    fn record_uritype(&mut self, slice: i64, request_uri: &str) {
-        let uritype = classify(request_uri);
+    fn record_uritype(&mut self, slice: i64, label: &str, captures: Vec<(&str, String)>) {
         let key_uritype_timeslice = KeyUritypeTimeslice {
             timeslice: slice,
-            uritype: uritype,
+            uritype: label.to_string(),
         };
         *self.by_uritype_timeslice.entry(key_uritype_timeslice).or_insert(0) += 1;
-        self.uritypes.insert(uritype);
+        if !self.uritypes.contains(label) {
+            self.uritypes.insert(label.to_string());
+        }
+        for (name, value) in captures {
+            let values = self.captures.entry(name.to_string()).or_insert_with(HashSet::new);
+            if !values.contains(&value) {
+                values.insert(value.clone());
+            }
+            let key_capture_timeslice = KeyCaptureTimeslice {
+                timeslice: slice,
+                name: name.to_string(),
+                value: value,
+            };
+            *self.by_capture_timeslice.entry(key_capture_timeslice).or_insert(0) += 1;
+        }
     }
     fn record_service_time(&mut self, slice: i64, response_time_micros: u64) {
         self.servicetime_hist_by_timeslice.entry(slice).or_insert_with(|| Histogram::new(1).unwrap()).record(response_time_micros);
         self.servicetime_hist.record(response_time_micros);
     }
 
-    pub fn merge(&mut self, other: &Consumer) {
-        for timeslice in other.timeslices.iter() {
-            self.timeslices.insert(*timeslice);
-            let other_times = other.servicetime_hist_by_timeslice.get(timeslice).unwrap();
-            self.servicetime_hist_by_timeslice.entry(*timeslice).or_insert_with(|| Histogram::new(1).unwrap()).add(other_times);
+    pub fn slice_seconds(&self) -> i64 {
+        self.slice_seconds
+    }
+
+    /// The first and last timeslices with any results, if there are any
+    pub fn timeslice_range(&self) -> Option<(i64, i64)> {
+        match (self.timeslices.iter().min(), self.timeslices.iter().max()) {
+            (Some(first), Some(last)) => Some((*first, *last)),
+            _ => None,
         }
-        for status in other.statuses.iter() {
-            self.statuses.insert(status.clone());
+    }
+
+    pub fn config(&self) -> Arc<ConsumerConfig> {
+        self.config.clone()
+    }
+
+    /// Save everything this consumer has counted, so that it can later be reloaded with
+    /// `load_snapshot()` and merged with other results
+    pub fn save_snapshot(&self, path: &Path) -> Result<(), Error> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_snapshot(&mut out)?;
+        out.flush()
+    }
+
+    pub fn write_snapshot(&self, out: &mut Write) -> Result<(), Error> {
+        let mut w = SnapshotWriter::new(out)?;
+        w.write_i64(self.slice_seconds)?;
+        w.write_u64(self.timeslices.len() as u64)?;
+        for ts in self.timeslices.iter() {
+            w.write_i64(*ts)?;
+            w.write_histogram(&self.servicetime_hist_by_timeslice[ts])?;
+        }
+        w.write_histogram(&self.servicetime_hist)?;
+        w.write_u64(self.by_status_timeslice.len() as u64)?;
+        for (k, v) in self.by_status_timeslice.iter() {
+            w.write_i64(k.timeslice)?;
+            w.write_str(&k.http_status)?;
+            w.write_u64(*v)?;
+        }
+        w.write_u64(self.by_uritype_timeslice.len() as u64)?;
+        for (k, v) in self.by_uritype_timeslice.iter() {
+            w.write_i64(k.timeslice)?;
+            w.write_str(&k.uritype)?;
+            w.write_u64(*v)?;
+        }
+        w.write_u64(self.by_capture_timeslice.len() as u64)?;
+        for (k, v) in self.by_capture_timeslice.iter() {
+            w.write_i64(k.timeslice)?;
+            w.write_str(&k.name)?;
+            w.write_str(&k.value)?;
+            w.write_u64(*v)?;
         }
-        for (k, v) in other.by_status_timeslice.iter() {
-            *self.by_status_timeslice.entry(k.clone()).or_insert(0) += *v;
+        // the query is recorded so that results from different queries are never mixed up
+        match self.config.query {
+            Some(ref query) => {
+                w.write_str(&query.to_string())?;
+                self.query_result.write_snapshot(&mut w)?;
+            },
+            None => w.write_str("")?,
         }
-        for uritype in other.uritypes.iter() {
-            self.uritypes.insert(*uritype);
+        self.top_result.write_snapshot(&mut w)?;
+        self.bytes_result.write_snapshot(&mut w)?;
+        self.cache_result.write_snapshot(&mut w)?;
+        // as for the query, estimates for different definitions of a client mustn't be mixed
+        match self.config.unique {
+            Some(ref unique) => {
+                w.write_str(&unique.to_string())?;
+                self.unique_result.write_snapshot(&mut w)?;
+            },
+            None => w.write_str("")?,
         }
-        for (k, v) in other.by_uritype_timeslice.iter() {
-            *self.by_uritype_timeslice.entry(k.clone()).or_insert(0) += *v;
+        match self.config.breakdown {
+            Some(ref breakdown) => {
+                w.write_str(&breakdown.to_string())?;
+                self.breakdown_result.write_snapshot(&mut w)?;
+            },
+            None => w.write_str("")?,
         }
-        self.servicetime_hist.add(&other.servicetime_hist);
+        Ok(())
+    }
+
+    /// Load a snapshot written by `save_snapshot()`.  The timeslice width is taken from the
+    /// snapshot, rather than from the given config.
+    pub fn load_snapshot(config: Arc<ConsumerConfig>, path: &Path) -> Result<Consumer, Error> {
+        let mut input = BufReader::new(File::open(path)?);
+        Consumer::read_snapshot(config, &mut input)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
     }
 
-    pub fn dump_by_status_timeslice(&self, out: &mut Write) -> Result<(),Error>{
-        let cols = self.statuses.iter().collect::<Vec<&String>>();
-        write!(out, "timeslice")?;
-        for c in &cols {
-            write!(out, "\t{}", c)?;
+    pub fn read_snapshot(config: Arc<ConsumerConfig>, input: &mut Read) -> Result<Consumer, Error> {
+        let mut r = SnapshotReader::new(input)?;
+        let slice_seconds = r.read_i64()?;
+        if slice_seconds <= 0 {
+            return Err(invalid_data("bad timeslice width"));
         }
-        writeln!(out, "")?;
-        let mut timeslices = self.timeslices.iter().map(|ts| *ts ).collect::<Vec<i64>>();
-        timeslices.sort();
-        for ts in timeslices.iter() {
-            write!(out, "{}\t", ts)?;
-            for c in &cols {
-                let key = KeyStatusTimeslice {
-                    timeslice: *ts,
-                    http_status: (*c).clone(),
-                };
-                let def = 0;
-                let val = self.by_status_timeslice.get(&key).unwrap_or(&def);
-                write!(out, "{}\t", val)?;
+        let mut c = Consumer::with_slice(config, slice_seconds);
+        for _ in 0..r.read_len()? {
+            let ts = r.read_i64()?;
+            c.timeslices.insert(ts);
+            c.servicetime_hist_by_timeslice.insert(ts, r.read_histogram()?);
+        }
+        c.servicetime_hist = r.read_histogram()?;
+        for _ in 0..r.read_len()? {
+            let key = KeyStatusTimeslice {
+                timeslice: r.read_i64()?,
+                http_status: r.read_string()?,
+            };
+            c.statuses.insert(key.http_status.clone());
+            c.by_status_timeslice.insert(key, r.read_u64()?);
+        }
+        for _ in 0..r.read_len()? {
+            let key = KeyUritypeTimeslice {
+                timeslice: r.read_i64()?,
+                uritype: r.read_string()?,
+            };
+            c.uritypes.insert(key.uritype.clone());
+            c.by_uritype_timeslice.insert(key, r.read_u64()?);
+        }
+        for _ in 0..r.read_len()? {
+            let key = KeyCaptureTimeslice {
+                timeslice: r.read_i64()?,
+                name: r.read_string()?,
+                value: r.read_string()?,
+            };
+            c.captures.entry(key.name.clone()).or_insert_with(HashSet::new).insert(key.value.clone());
+            c.by_capture_timeslice.insert(key, r.read_u64()?);
+        }
+        let query = r.read_string()?;
+        if !query.is_empty() {
+            let query_result = QueryResult::read_snapshot(&mut r)?;
+            let config = c.config.clone();
+            match config.query {
+                Some(ref q) if q.to_string() == query => c.query_result = query_result,
+                Some(ref q) => return Err(Error::new(ErrorKind::InvalidInput, format!("snapshot has results for query '{}', not '{}'", query, q))),
+                // the query results aren't wanted this time
+                None => (),
             }
-            writeln!(out, "")?;
+        } else if c.config.query.is_some() {
+            return Err(Error::new(ErrorKind::InvalidInput, "snapshot has no query results"));
+        }
+        let top_result = TopResult::read_snapshot(&mut r)?;
+        if let Some(ref top) = c.config.top {
+            if !top_result.covers(top) {
+                return Err(Error::new(ErrorKind::InvalidInput, "snapshot is missing the most frequent values of some of the --top-fields"));
+            }
+        }
+        c.top_result = top_result;
+        c.bytes_result = BytesResult::read_snapshot(&mut r)?;
+        c.cache_result = CacheResult::read_snapshot(&mut r)?;
+        let unique = r.read_string()?;
+        if !unique.is_empty() {
+            let unique_result = UniqueResult::read_snapshot(&mut r)?;
+            let config = c.config.clone();
+            match config.unique {
+                Some(ref u) if u.to_string() == unique => c.unique_result = unique_result,
+                Some(ref u) => return Err(Error::new(ErrorKind::InvalidInput, format!("snapshot counts clients by '{}', not '{}'", unique, u))),
+                None => (),
+            }
+        } else if c.config.unique.is_some() {
+            return Err(Error::new(ErrorKind::InvalidInput, "snapshot has no unique client counts"));
+        }
+        let breakdown = r.read_string()?;
+        if !breakdown.is_empty() {
+            let breakdown_result = BreakdownResult::read_snapshot(&mut r)?;
+            let config = c.config.clone();
+            match config.breakdown {
+                Some(ref b) if b.to_string() == breakdown => c.breakdown_result = breakdown_result,
+                Some(ref b) => return Err(Error::new(ErrorKind::InvalidInput, format!("snapshot has results for '{}', not '{}'", breakdown, b))),
+                None => (),
+            }
+        } else if c.config.breakdown.is_some() {
+            return Err(Error::new(ErrorKind::InvalidInput, "snapshot has no --breakdown results"));
+        }
+        Ok(c)
+    }
+
+    /// Combine the results from another consumer into this one.  Both must have been built
+    /// with the same timeslice width.
+    pub fn merge(&mut self, other: &Consumer) -> Result<(), Error> {
+        if other.slice_seconds != self.slice_seconds {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("can't merge results with {}s timeslices into results with {}s timeslices", other.slice_seconds, self.slice_seconds)));
         }
+        self.absorb(other);
         Ok(())
     }
 
-    pub fn dump_by_uritype_timeslice(&self, out: &mut Write) -> Result<(),Error>{
-        let cols = self.uritypes.iter().collect::<Vec<&UriType>>();
-        write!(out, "timeslice")?;
-        for c in &cols {
-            write!(out, "\t{:?}", c)?;
+    /// Produce a copy of these results using wider timeslices, which must be a whole multiple of
+    /// the current width
+    pub fn rollup(&self, slice_seconds: i64) -> Result<Consumer, Error> {
+        if slice_seconds < self.slice_seconds || slice_seconds % self.slice_seconds != 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("can't roll up {}s timeslices into {}s timeslices", self.slice_seconds, slice_seconds)));
+        }
+        let mut result = Consumer::with_slice(self.config.clone(), slice_seconds);
+        result.absorb(self);
+        Ok(result)
+    }
+
+    /// A copy of the results for the timeslices lying wholly between `start` and `end`, e.g. so
+    /// that completed timeslices can be written out while later ones are still being added to.
+    /// Query results which aren't grouped by timeslice can't be divided up, so are copied whole.
+    pub fn between(&self, start: i64, end: i64) -> Consumer {
+        let width = self.slice_seconds;
+        let mut result = Consumer::with_slice(self.config.clone(), width);
+        result.absorb_slices(self, &|ts| ts >= start && ts + width <= end );
+        result
+    }
+
+    /// Add the other consumer's results to ours, mapping each of its timeslices onto the
+    /// (possibly wider) timeslice that contains it
+    fn absorb(&mut self, other: &Consumer) {
+        self.absorb_slices(other, &|_| true );
+    }
+
+    /// As `absorb()`, but only for the other consumer's timeslices that `keep` accepts
+    fn absorb_slices(&mut self, other: &Consumer, keep: &Fn(i64) -> bool) {
+        let width = self.slice_seconds;
+        for timeslice in other.timeslices.iter().filter(|ts| keep(**ts) ) {
+            let slice = rescale(*timeslice, width);
+            self.timeslices.insert(slice);
+            let other_times = other.servicetime_hist_by_timeslice.get(timeslice).unwrap();
+            self.servicetime_hist_by_timeslice.entry(slice).or_insert_with(|| Histogram::new(1).unwrap()).add(other_times);
+            self.servicetime_hist.add(other_times);
+        }
+        for (k, v) in other.by_status_timeslice.iter().filter(|&(k, _)| keep(k.timeslice) ) {
+            if !self.statuses.contains(&k.http_status) {
+                self.statuses.insert(k.http_status.clone());
+            }
+            let key = KeyStatusTimeslice {
+                timeslice: rescale(k.timeslice, width),
+                http_status: k.http_status.clone(),
+            };
+            *self.by_status_timeslice.entry(key).or_insert(0) += *v;
+        }
+        for (k, v) in other.by_uritype_timeslice.iter().filter(|&(k, _)| keep(k.timeslice) ) {
+            if !self.uritypes.contains(&k.uritype) {
+                self.uritypes.insert(k.uritype.clone());
+            }
+            let key = KeyUritypeTimeslice {
+                timeslice: rescale(k.timeslice, width),
+                uritype: k.uritype.clone(),
+            };
+            *self.by_uritype_timeslice.entry(key).or_insert(0) += *v;
+        }
+        for (k, v) in other.by_capture_timeslice.iter().filter(|&(k, _)| keep(k.timeslice) ) {
+            self.captures.entry(k.name.clone()).or_insert_with(HashSet::new).insert(k.value.clone());
+            let key = KeyCaptureTimeslice {
+                timeslice: rescale(k.timeslice, width),
+                name: k.name.clone(),
+                value: k.value.clone(),
+            };
+            *self.by_capture_timeslice.entry(key).or_insert(0) += *v;
+        }
+        if let Some(ref query) = self.config.query {
+            self.query_result.absorb(query, &other.query_result, width, keep);
+        }
+        if let Some(ref top) = self.config.top {
+            self.top_result.absorb(top, &other.top_result, width, keep);
+        }
+        if self.config.unique.is_some() {
+            self.unique_result.absorb(&(), &other.unique_result, width, keep);
         }
-        writeln!(out, "")?;
+        if let Some(ref breakdown) = self.config.breakdown {
+            self.breakdown_result.absorb(breakdown, &other.breakdown_result, width, keep);
+        }
+        self.bytes_result.absorb(&(), &other.bytes_result, width, keep);
+        self.cache_result.absorb(&(), &other.cache_result, width, keep);
+    }
+
+    /// The timeslices to report on, in order.  With `zero_fill`, timeslices in which nothing
+    /// happened are included too, so that there are no gaps between the first and the last.
+    fn sorted_timeslices(&self, zero_fill: bool) -> Vec<i64> {
         let mut timeslices = self.timeslices.iter().map(|ts| *ts ).collect::<Vec<i64>>();
         timeslices.sort();
-        for ts in timeslices.iter() {
-            write!(out, "{}\t", ts)?;
+        if zero_fill && timeslices.len() > 1 {
+            let first = timeslices[0];
+            let last = timeslices[timeslices.len() - 1];
+            let count = (last - first) / self.slice_seconds + 1;
+            timeslices = (0..count).map(|i| first + i * self.slice_seconds ).collect();
+        }
+        timeslices
+    }
+
+    /// Requests per status in each timeslice, in order of status.  With `status_classes`,
+    /// statuses are grouped into classes such as `5xx`.
+    pub fn status_table(&self, options: &ReportOptions) -> Table {
+        let label = |status: &str| if options.status_classes { status_class(status) } else { status.to_string() };
+        let mut counts = HashMap::new();
+        for (k, v) in self.by_status_timeslice.iter() {
+            *counts.entry((k.timeslice, label(&k.http_status))).or_insert(0) += *v;
+        }
+        let mut cols = self.statuses.iter().map(|s| label(s) ).collect::<Vec<String>>();
+        cols.sort();
+        cols.dedup();
+        let rows = self.sorted_timeslices(options.zero_fill).into_iter().map(|ts| {
+            let mut row = vec![Cell::Time(ts)];
+            for c in &cols {
+                row.push(Cell::Int(*counts.get(&(ts, c.clone())).unwrap_or(&0) as i64));
+            }
+            row
+        }).collect();
+        let table = Table {
+            name: "by_status_timeslice".to_string(),
+            keys: vec!["timeslice".to_string()],
+            columns: cols,
+            pivot: Some("http_status".to_string()),
+            rows: rows,
+        };
+        self.finish_table(table, options)
+    }
+
+    /// The proportion of requests in each timeslice that got a 4xx or a 5xx status
+    pub fn error_rate_table(&self, options: &ReportOptions) -> Table {
+        // requests, client errors and server errors
+        let mut counts: HashMap<i64, (u64, u64, u64)> = HashMap::new();
+        for (k, v) in self.by_status_timeslice.iter() {
+            let c = counts.entry(k.timeslice).or_insert((0, 0, 0));
+            c.0 += *v;
+            match &status_class(&k.http_status)[..] {
+                "4xx" => c.1 += *v,
+                "5xx" => c.2 += *v,
+                _ => (),
+            }
+        }
+        let rows = self.sorted_timeslices(options.zero_fill).into_iter().map(|ts| {
+            let (requests, client, server) = *counts.get(&ts).unwrap_or(&(0, 0, 0));
+            let rate = |n: u64| if requests > 0 { Cell::Float(n as f64 / requests as f64) } else { Cell::Empty };
+            vec![Cell::Time(ts), Cell::Int(requests as i64), Cell::Int(client as i64), Cell::Int(server as i64), rate(client), rate(server)]
+        }).collect();
+        let table = Table {
+            name: "error_rate_by_timeslice".to_string(),
+            keys: vec!["timeslice".to_string()],
+            columns: vec!["requests", "client_errors", "server_errors", "client_error_rate", "server_error_rate"].into_iter().map(|c| c.to_string() ).collect(),
+            pivot: None,
+            rows: rows,
+        };
+        self.finish_table(table, options)
+    }
+
+    /// Requests per uritype in each timeslice, in the order of the classifier's rules, followed
+    /// by the unmatched label and then any others, e.g. from a snapshot, by name
+    pub fn uritype_table(&self, options: &ReportOptions) -> Table {
+        let order = self.config.classifier.labels();
+        let rank = |label: &String| order.iter().position(|l| *l == &label[..] ).unwrap_or(order.len());
+        let mut cols = self.uritypes.iter().collect::<Vec<&String>>();
+        cols.sort_by(|a, b| (rank(a), a).cmp(&(rank(b), b)) );
+        let rows = self.sorted_timeslices(options.zero_fill).into_iter().map(|ts| {
+            let mut row = vec![Cell::Time(ts)];
             for c in &cols {
                 let key = KeyUritypeTimeslice {
-                    timeslice: *ts,
+                    timeslice: ts,
                     uritype: (*c).clone(),
                 };
-                let def = 0;
-                let val = self.by_uritype_timeslice.get(&key).unwrap_or(&def);
-                write!(out, "{}\t", val)?;
+                row.push(Cell::Int(*self.by_uritype_timeslice.get(&key).unwrap_or(&0) as i64));
             }
-            writeln!(out, "")?;
+            row
+        }).collect();
+        let table = Table {
+            name: "by_uritype_timeslice".to_string(),
+            keys: vec!["timeslice".to_string()],
+            columns: cols.into_iter().cloned().collect(),
+            pivot: Some("uritype".to_string()),
+            rows: rows,
+        };
+        self.finish_table(table, options)
+    }
+
+    /// The names of the capture groups for which `capture_table()` has values
+    pub fn capture_names(&self) -> Vec<&String> {
+        let mut names = self.captures.keys().collect::<Vec<&String>>();
+        names.sort();
+        names
+    }
+
+    pub fn capture_table(&self, name: &str, options: &ReportOptions) -> Table {
+        let mut cols = match self.captures.get(name) {
+            Some(values) => values.iter().collect::<Vec<&String>>(),
+            None => Vec::new(),
+        };
+        cols.sort();
+        let rows = self.sorted_timeslices(options.zero_fill).into_iter().map(|ts| {
+            let mut row = vec![Cell::Time(ts)];
+            for c in &cols {
+                let key = KeyCaptureTimeslice {
+                    timeslice: ts,
+                    name: name.to_string(),
+                    value: (*c).clone(),
+                };
+                row.push(Cell::Int(*self.by_capture_timeslice.get(&key).unwrap_or(&0) as i64));
+            }
+            row
+        }).collect();
+        let table = Table {
+            name: format!("by_{}_timeslice", name),
+            keys: vec!["timeslice".to_string()],
+            columns: cols.into_iter().cloned().collect(),
+            pivot: Some(name.to_string()),
+            rows: rows,
+        };
+        self.finish_table(table, options)
+    }
+
+    fn finish_table(&self, mut table: Table, options: &ReportOptions) -> Table {
+        if options.slice_end {
+            table.add_slice_end(self.slice_seconds);
         }
-        Ok(())
+        table
     }
 
-    pub fn dump_servicetimes_by_timeslice(&self, out: &mut Write) -> Result<(),Error>{
-        let cols = self.servicetime_hist.iter_recorded().map(|v| v.value()).collect::<Vec<u64>>();
-        write!(out, "timeslice")?;
-        for c in &cols {
-            write!(out, "\t{:?}", c)?;
+    /// The results of the `--group-by`/`--agg` query, if there is one.  Timeslices are not
+    /// zero-filled, as there is no way to know which combinations of the other fields to fill.
+    pub fn query_table(&self, options: &ReportOptions) -> Option<Table> {
+        self.config.query.as_ref().map(|query| self.finish_table(self.query_result.table(query), options) )
+    }
+
+    /// The most frequent values of each of the `--top-fields`, per timeslice and overall
+    pub fn top_tables(&self, options: &ReportOptions) -> Vec<Table> {
+        match self.config.top {
+            Some(ref top) => self.top_result.tables(top).into_iter().map(|t| self.finish_table(t, options) ).collect(),
+            None => Vec::new(),
         }
-        writeln!(out, "")?;
-        let mut timeslices = self.timeslices.iter().map(|ts| *ts ).collect::<Vec<i64>>();
-        timeslices.sort();
-        for ts in timeslices.iter() {
-            write!(out, "{}\t", ts)?;
-            let hist = self.servicetime_hist_by_timeslice.get(ts).unwrap();
-            for c in &cols {
-                write!(out, "{}\t", hist.count_at(*c).unwrap())?;
+    }
+
+    /// The estimated number of distinct clients in each timeslice
+    pub fn unique_clients_table(&self, options: &ReportOptions) -> Table {
+        let rows = self.sorted_timeslices(options.zero_fill).into_iter()
+            .map(|ts| vec![Cell::Time(ts), Cell::Int(self.unique_result.estimate(ts) as i64)] )
+            .collect();
+        let table = Table {
+            name: "unique_clients_by_timeslice".to_string(),
+            keys: vec!["timeslice".to_string()],
+            columns: vec!["unique_clients".to_string()],
+            pivot: None,
+            rows: rows,
+        };
+        self.finish_table(table, options)
+    }
+
+    /// The estimated number of distinct clients over the whole period
+    pub fn unique_clients_total_table(&self) -> Table {
+        Table {
+            name: "unique_clients".to_string(),
+            keys: Vec::new(),
+            columns: vec!["unique_clients".to_string()],
+            pivot: None,
+            rows: vec![vec![Cell::Int(self.unique_result.total_estimate() as i64)]],
+        }
+    }
+
+    /// The bytes served in each timeslice, overall and by URI type, status and cache status
+    pub fn bytes_tables(&self, options: &ReportOptions) -> Vec<Table> {
+        let timeslices = self.sorted_timeslices(options.zero_fill);
+        self.bytes_result.tables(&timeslices, self.slice_seconds).into_iter()
+            .map(|t| self.finish_table(t, options) )
+            .collect()
+    }
+
+    /// The chosen percentiles of the per-request transfer rate in each timeslice
+    pub fn transfer_rate_table(&self, options: &ReportOptions) -> Table {
+        let timeslices = self.sorted_timeslices(options.zero_fill);
+        let table = self.bytes_result.rate_table(&timeslices, &options.percentiles);
+        self.finish_table(table, options)
+    }
+
+    /// The number of requests with each cache status in each timeslice
+    pub fn cache_status_table(&self, options: &ReportOptions) -> Table {
+        let table = self.cache_result.status_table(&self.sorted_timeslices(options.zero_fill));
+        self.finish_table(table, options)
+    }
+
+    /// Cache hits, misses and hit ratio in each timeslice, overall and for each URI type
+    pub fn cache_hit_ratio_tables(&self, options: &ReportOptions) -> Vec<Table> {
+        vec![
+            self.finish_table(self.cache_result.hit_ratio_table(&self.sorted_timeslices(options.zero_fill)), options),
+            self.finish_table(self.cache_result.uritype_hit_ratio_table(), options),
+        ]
+    }
+
+    /// Service time histograms for the requests with one cache outcome, named e.g.
+    /// `servicetime_hit_by_timeslice`.  Unless told otherwise these have log buckets, since the
+    /// overall `servicetime_by_timeslice` already has a column for every value recorded.
+    fn outcome_histograms<'a>(&self, outcome: &str, histograms: &'a OutcomeHistograms, options: &ReportOptions) -> HistogramSeries<'a> {
+        HistogramSeries {
+            name: format!("servicetime_{}_by_timeslice", outcome),
+            unit: "response_time_micros".to_string(),
+            slices: self.sorted_timeslices(options.zero_fill).into_iter()
+                .map(|ts| (ts, histograms.by_timeslice.get(&ts)) )
+                .collect(),
+            total: &histograms.total,
+            slice_end: if options.slice_end { Some(self.slice_seconds) } else { None },
+            buckets: options.buckets.unwrap_or_else(Buckets::default_log),
+        }
+    }
+
+    /// Service time histograms and percentiles for cache hits and cache misses
+    pub fn cache_servicetime_reports(&self, options: &ReportOptions) -> Vec<Report> {
+        let mut reports = Vec::new();
+        for &(outcome, histograms) in &[("hit", &self.cache_result.hit_servicetimes), ("miss", &self.cache_result.miss_servicetimes)] {
+            let series = self.outcome_histograms(outcome, histograms, options);
+            let table = percentile_table(&format!("servicetime_{}_percentiles_by_timeslice", outcome), &series.slices, &options.percentiles);
+            reports.push(Report::Table(self.finish_table(table, options)));
+            reports.push(Report::Histograms(series));
+        }
+        reports
+    }
+
+    /// Requests, statuses and service time percentiles for each value of each `--breakdown`
+    /// field
+    pub fn breakdown_tables(&self, options: &ReportOptions) -> Vec<Table> {
+        match self.config.breakdown {
+            Some(ref breakdown) => {
+                let timeslices = self.sorted_timeslices(options.zero_fill);
+                self.breakdown_result.tables(breakdown, &timeslices, &options.percentiles).into_iter()
+                    .map(|t| self.finish_table(t, options) )
+                    .collect()
+            },
+            None => Vec::new(),
+        }
+    }
+
+    /// Timeslices in which the request count, error rates or service time percentiles were
+    /// unusual compared with the baseline
+    pub fn anomaly_table(&self, config: &AnomalyConfig, options: &ReportOptions) -> Table {
+        // the tables are only read from, so don't need a timeslice_end column
+        let plain = ReportOptions { slice_end: false, ..options.clone() };
+        let errors = self.error_rate_table(&plain);
+        let servicetimes = self.servicetime_percentile_table(&plain);
+        let mut metrics = vec![
+            ("requests".to_string(), &errors, "requests".to_string()),
+            ("client_error_rate".to_string(), &errors, "client_error_rate".to_string()),
+            ("server_error_rate".to_string(), &errors, "server_error_rate".to_string()),
+        ];
+        for p in options.percentiles.iter() {
+            metrics.push((format!("servicetime_p{}", p), &servicetimes, format!("p{}", p)));
+        }
+        let mut anomalies = Vec::new();
+        for (metric, table, column) in metrics {
+            anomalies.extend(anomaly::detect(config, &metric, &anomaly::column_series(table, &column)));
+        }
+        self.finish_table(anomaly::table(&anomalies), options)
+    }
+
+    pub fn servicetime_histograms(&self, options: &ReportOptions) -> HistogramSeries {
+        HistogramSeries {
+            name: "servicetime_by_timeslice".to_string(),
+            unit: "response_time_micros".to_string(),
+            slices: self.sorted_timeslices(options.zero_fill).into_iter()
+                .map(|ts| (ts, self.servicetime_hist_by_timeslice.get(&ts)) )
+                .collect(),
+            total: &self.servicetime_hist,
+            slice_end: if options.slice_end { Some(self.slice_seconds) } else { None },
+            buckets: options.buckets.unwrap_or(Buckets::Recorded),
+        }
+    }
+
+    /// The chosen percentiles of the service time in each timeslice, along with the maximum,
+    /// mean and count
+    pub fn servicetime_percentile_table(&self, options: &ReportOptions) -> Table {
+        let slices = self.sorted_timeslices(options.zero_fill).into_iter()
+            .map(|ts| (ts, self.servicetime_hist_by_timeslice.get(&ts)) )
+            .collect::<Vec<(i64, Option<&Histogram<u64>>)>>();
+        let table = percentile_table("servicetime_percentiles_by_timeslice", &slices, &options.percentiles);
+        self.finish_table(table, options)
+    }
+
+    /// Everything there is to write out from this consumer
+    pub fn reports(&self, options: &ReportOptions) -> Vec<Report> {
+        let mut reports = vec![
+            Report::Table(self.status_table(options)),
+            Report::Table(self.error_rate_table(options)),
+            Report::Table(self.uritype_table(options)),
+        ];
+        for name in self.capture_names() {
+            reports.push(Report::Table(self.capture_table(name, options)));
+        }
+        reports.push(Report::Histograms(self.servicetime_histograms(options)));
+        reports.push(Report::Table(self.servicetime_percentile_table(options)));
+        for table in self.bytes_tables(options) {
+            reports.push(Report::Table(table));
+        }
+        reports.push(Report::Table(self.transfer_rate_table(options)));
+        // most log formats have no cache status, so would only give empty cache reports
+        if self.cache_result.has_statuses() {
+            reports.push(Report::Table(self.cache_status_table(options)));
+            for table in self.cache_hit_ratio_tables(options) {
+                reports.push(Report::Table(table));
             }
-            writeln!(out, "")?;
+            reports.extend(self.cache_servicetime_reports(options));
         }
-        Ok(())
+        if let Some(table) = self.query_table(options) {
+            reports.push(Report::Table(table));
+        }
+        for table in self.top_tables(options) {
+            reports.push(Report::Table(table));
+        }
+        if self.config.unique.is_some() {
+            reports.push(Report::Table(self.unique_clients_table(options)));
+            reports.push(Report::Table(self.unique_clients_total_table()));
+        }
+        for table in self.breakdown_tables(options) {
+            reports.push(Report::Table(table));
+        }
+        if let Some(ref anomalies) = options.anomalies {
+            reports.push(Report::Table(self.anomaly_table(anomalies, options)));
+        }
+        reports
+    }
+}
+
+/// The class of an HTTP status, such as `5xx` for `503`.  Anything that isn't a three-digit
+/// status from 100 to 599 is left as it is.
+pub fn status_class(status: &str) -> String {
+    let b = status.as_bytes();
+    if b.len() == 3 && b[0] >= b'1' && b[0] <= b'5' && b[1..].iter().all(|c| *c >= b'0' && *c <= b'9' ) {
+        format!("{}xx", b[0] as char)
+    } else {
+        status.to_string()
     }
 }
 
 fn timeslice(t: Timespec, seconds: i64) -> i64 {
-    (t.sec / seconds) * seconds
+    rescale(t.sec, seconds)
+}
+
+/// The start of the `seconds`-wide timeslice containing the given time (or the given start of
+/// a narrower timeslice)
+pub fn rescale(t: i64, seconds: i64) -> i64 {
+    (t / seconds) * seconds
 }
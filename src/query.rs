@@ -0,0 +1,545 @@
+use std::collections::HashMap;
+use std::fmt;
+use hdrsample::Histogram;
+use parse_access_log::Record;
+use output::{Cell, Table};
+use snapshot::{SnapshotWriter, SnapshotReader, invalid_data};
+use process::{SliceResult, rescale};
+use std::io::Error;
+
+/// A field of `Record`, named as in the struct definition
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum RecordField {
+    Timestamp,
+    RemoteHost,
+    RemoteLogname,
+    RemoteUser,
+    RequestMethod,
+    RequestUri,
+    RequestProto,
+    ResponseStatus,
+    ResponseBytes,
+    RequestReferer,
+    RequestUseragent,
+    RequestHost,
+    ResponseTimeMicros,
+    RequestForwardedFor,
+    RequestLocalPort,
+    ResponseCacheStatus,
+    RequestHandler,
+}
+
+const RECORD_FIELDS: &'static [(&'static str, RecordField)] = &[
+    ("timestamp", RecordField::Timestamp),
+    ("remote_host", RecordField::RemoteHost),
+    ("remote_logname", RecordField::RemoteLogname),
+    ("remote_user", RecordField::RemoteUser),
+    ("request_method", RecordField::RequestMethod),
+    ("request_uri", RecordField::RequestUri),
+    ("request_proto", RecordField::RequestProto),
+    ("response_status", RecordField::ResponseStatus),
+    ("response_bytes", RecordField::ResponseBytes),
+    ("request_referer", RecordField::RequestReferer),
+    ("request_useragent", RecordField::RequestUseragent),
+    ("request_host", RecordField::RequestHost),
+    ("response_time_micros", RecordField::ResponseTimeMicros),
+    ("request_forwarded_for", RecordField::RequestForwardedFor),
+    ("request_local_port", RecordField::RequestLocalPort),
+    ("response_cache_status", RecordField::ResponseCacheStatus),
+    ("request_handler", RecordField::RequestHandler),
+];
+
+impl RecordField {
+    pub fn parse(name: &str) -> Option<RecordField> {
+        RECORD_FIELDS.iter().find(|&&(n, _)| n == name ).map(|&(_, f)| f )
+    }
+
+    pub fn name(&self) -> &'static str {
+        RECORD_FIELDS.iter().find(|&&(_, f)| f == *self ).map(|&(n, _)| n ).unwrap()
+    }
+
+    pub fn value(&self, r: &Record) -> Value {
+        match *self {
+            RecordField::Timestamp => Value::Num(r.timestamp.sec),
+            RecordField::ResponseBytes => Value::Num(r.response_bytes.unwrap_or(0) as i64),
+            RecordField::ResponseTimeMicros => Value::Num(r.response_time_micros as i64),
+            RecordField::RequestLocalPort => Value::Num(r.request_local_port as i64),
+            _ => Value::Str(self.text(r).unwrap().to_string()),
+        }
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        match *self {
+            RecordField::Timestamp
+            | RecordField::ResponseBytes
+            | RecordField::ResponseTimeMicros
+            | RecordField::RequestLocalPort => true,
+            _ => false,
+        }
+    }
+
+    /// The value of a textual field, without copying it, or `None` for numeric fields
+    pub fn text<'a>(&self, r: &'a Record) -> Option<&'a str> {
+        match *self {
+            RecordField::RemoteHost => Some(&r.remote_host),
+            RecordField::RemoteLogname => Some(&r.remote_logname),
+            RecordField::RemoteUser => Some(&r.remote_user),
+            RecordField::RequestMethod => Some(&r.request_method),
+            RecordField::RequestUri => Some(&r.request_uri),
+            RecordField::RequestProto => Some(&r.request_proto),
+            RecordField::ResponseStatus => Some(&r.response_status),
+            RecordField::RequestReferer => Some(&r.request_referer),
+            RecordField::RequestUseragent => Some(&r.request_useragent),
+            RecordField::RequestHost => Some(&r.request_host),
+            RecordField::RequestForwardedFor => Some(&r.request_forwarded_for),
+            RecordField::ResponseCacheStatus => Some(&r.response_cache_status),
+            RecordField::RequestHandler => Some(&r.request_handler),
+            RecordField::Timestamp
+            | RecordField::ResponseBytes
+            | RecordField::ResponseTimeMicros
+            | RecordField::RequestLocalPort => None,
+        }
+    }
+
+    /// The field's value as a number, for use by aggregates such as `sum()`.  Textual fields
+    /// give `None` unless they happen to hold a number (e.g. `response_status`).
+    pub fn number(&self, r: &Record) -> Option<u64> {
+        match self.value(r) {
+            Value::Num(n) if n >= 0 => Some(n as u64),
+            Value::Num(_) => None,
+            Value::Str(s) => s.parse().ok(),
+        }
+    }
+}
+
+/// The value of one of the fields a query groups by
+#[derive(Debug,Clone,Hash,Eq,PartialEq,Ord,PartialOrd)]
+pub enum Value {
+    Num(i64),
+    Str(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Num(n) => write!(f, "{}", n),
+            Value::Str(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Something to group records by; either a `Record` field, or a value derived by the `Consumer`
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum GroupField {
+    /// the start of the record's timeslice
+    Timeslice,
+    /// the label given by the URI classifier
+    Uritype,
+    Field(RecordField),
+}
+
+impl GroupField {
+    fn parse(name: &str) -> Result<GroupField, String> {
+        match name {
+            "timeslice" => Ok(GroupField::Timeslice),
+            "uritype" => Ok(GroupField::Uritype),
+            _ => RecordField::parse(name)
+                .map(GroupField::Field)
+                .ok_or_else(|| format!("unknown field {:?}", name)),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            GroupField::Timeslice => "timeslice",
+            GroupField::Uritype => "uritype",
+            GroupField::Field(ref f) => f.name(),
+        }
+    }
+}
+
+/// A function computed over the records in each group
+#[derive(Debug,Clone,PartialEq)]
+pub enum Aggregate {
+    Count,
+    Sum(RecordField),
+    Min(RecordField),
+    Max(RecordField),
+    Mean(RecordField),
+    /// e.g. `p99(response_time_micros)`
+    Percentile(f64, RecordField),
+}
+
+impl Aggregate {
+    fn parse(text: &str) -> Result<Aggregate, String> {
+        if text == "count" {
+            return Ok(Aggregate::Count);
+        }
+        let open = text.find('(').ok_or_else(|| format!("expected count or FUNCTION(FIELD), got {:?}", text))?;
+        if !text.ends_with(')') {
+            return Err(format!("missing ')' in {:?}", text));
+        }
+        let func = &text[..open];
+        let name = &text[open+1..text.len()-1];
+        let field = RecordField::parse(name).ok_or_else(|| format!("unknown field {:?} in {:?}", name, text))?;
+        match func {
+            "sum" => Ok(Aggregate::Sum(field)),
+            "min" => Ok(Aggregate::Min(field)),
+            "max" => Ok(Aggregate::Max(field)),
+            "mean" => Ok(Aggregate::Mean(field)),
+            _ if func.starts_with('p') => {
+                let pct: f64 = func[1..].parse().map_err(|_| format!("bad percentile in {:?}", text))?;
+                if pct < 0.0 || pct > 100.0 {
+                    return Err(format!("percentile out of range in {:?}", text));
+                }
+                Ok(Aggregate::Percentile(pct, field))
+            },
+            _ => Err(format!("unknown aggregate function {:?}", func)),
+        }
+    }
+
+    fn field(&self) -> Option<RecordField> {
+        match *self {
+            Aggregate::Count => None,
+            Aggregate::Sum(f) | Aggregate::Min(f) | Aggregate::Max(f) | Aggregate::Mean(f) | Aggregate::Percentile(_, f) => Some(f),
+        }
+    }
+
+    fn accumulator(&self) -> Accumulator {
+        match *self {
+            Aggregate::Count => Accumulator::Count(0),
+            Aggregate::Sum(_) => Accumulator::Sum(0),
+            Aggregate::Min(_) => Accumulator::Min(None),
+            Aggregate::Max(_) => Accumulator::Max(None),
+            Aggregate::Mean(_) => Accumulator::Mean { sum: 0, count: 0 },
+            Aggregate::Percentile(..) => Accumulator::Histogram(Histogram::new(2).unwrap()),
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Aggregate::Count => write!(f, "count"),
+            Aggregate::Sum(ref field) => write!(f, "sum({})", field.name()),
+            Aggregate::Min(ref field) => write!(f, "min({})", field.name()),
+            Aggregate::Max(ref field) => write!(f, "max({})", field.name()),
+            Aggregate::Mean(ref field) => write!(f, "mean({})", field.name()),
+            Aggregate::Percentile(pct, ref field) => write!(f, "p{}({})", pct, field.name()),
+        }
+    }
+}
+
+/// The running state of one `Aggregate` within one group
+enum Accumulator {
+    Count(u64),
+    Sum(u64),
+    Min(Option<u64>),
+    Max(Option<u64>),
+    Mean { sum: u64, count: u64 },
+    Histogram(Histogram<u64>),
+}
+
+impl Accumulator {
+    fn write_snapshot(&self, w: &mut SnapshotWriter) -> Result<(), Error> {
+        match *self {
+            Accumulator::Count(n) => { w.write_u64(0)?; w.write_u64(n) },
+            Accumulator::Sum(n) => { w.write_u64(1)?; w.write_u64(n) },
+            Accumulator::Min(m) => { w.write_u64(2)?; write_option(w, m) },
+            Accumulator::Max(m) => { w.write_u64(3)?; write_option(w, m) },
+            Accumulator::Mean { sum, count } => {
+                w.write_u64(4)?;
+                w.write_u64(sum)?;
+                w.write_u64(count)
+            },
+            Accumulator::Histogram(ref h) => { w.write_u64(5)?; w.write_histogram(h) },
+        }
+    }
+
+    fn read_snapshot(r: &mut SnapshotReader) -> Result<Accumulator, Error> {
+        match r.read_u64()? {
+            0 => Ok(Accumulator::Count(r.read_u64()?)),
+            1 => Ok(Accumulator::Sum(r.read_u64()?)),
+            2 => Ok(Accumulator::Min(read_option(r)?)),
+            3 => Ok(Accumulator::Max(read_option(r)?)),
+            4 => {
+                let sum = r.read_u64()?;
+                Ok(Accumulator::Mean { sum: sum, count: r.read_u64()? })
+            },
+            5 => Ok(Accumulator::Histogram(r.read_histogram()?)),
+            other => Err(invalid_data(&format!("unknown aggregate type {}", other))),
+        }
+    }
+
+    fn record(&mut self, value: Option<u64>) {
+        match *self {
+            Accumulator::Count(ref mut n) => *n += 1,
+            Accumulator::Sum(ref mut sum) => *sum += value.unwrap_or(0),
+            Accumulator::Min(ref mut min) => if let Some(v) = value {
+                *min = Some(min.map(|m| m.min(v) ).unwrap_or(v));
+            },
+            Accumulator::Max(ref mut max) => if let Some(v) = value {
+                *max = Some(max.map(|m| m.max(v) ).unwrap_or(v));
+            },
+            Accumulator::Mean { ref mut sum, ref mut count } => if let Some(v) = value {
+                *sum += v;
+                *count += 1;
+            },
+            Accumulator::Histogram(ref mut hist) => if let Some(v) = value {
+                let _ = hist.record(v);
+            },
+        }
+    }
+
+    fn merge(&mut self, other: &Accumulator) {
+        match (self, other) {
+            (&mut Accumulator::Count(ref mut n), &Accumulator::Count(o)) => *n += o,
+            (&mut Accumulator::Sum(ref mut n), &Accumulator::Sum(o)) => *n += o,
+            (&mut Accumulator::Min(ref mut m), &Accumulator::Min(o)) => {
+                *m = match (*m, o) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+            },
+            (&mut Accumulator::Max(ref mut m), &Accumulator::Max(o)) => {
+                *m = match (*m, o) {
+                    (Some(a), Some(b)) => Some(a.max(b)),
+                    (a, b) => a.or(b),
+                };
+            },
+            (&mut Accumulator::Mean { ref mut sum, ref mut count }, &Accumulator::Mean { sum: osum, count: ocount }) => {
+                *sum += osum;
+                *count += ocount;
+            },
+            (&mut Accumulator::Histogram(ref mut h), &Accumulator::Histogram(ref o)) => {
+                let _ = h.add(o);
+            },
+            _ => panic!("merging results of different queries"),
+        }
+    }
+
+    fn cell(&self, agg: &Aggregate) -> Cell {
+        match *self {
+            Accumulator::Count(n) | Accumulator::Sum(n) => Cell::Int(n as i64),
+            Accumulator::Min(m) | Accumulator::Max(m) => match m {
+                Some(v) => Cell::Int(v as i64),
+                None => Cell::Empty,
+            },
+            Accumulator::Mean { sum, count } => if count > 0 {
+                Cell::Float(sum as f64 / count as f64)
+            } else {
+                Cell::Empty
+            },
+            Accumulator::Histogram(ref h) => match *agg {
+                Aggregate::Percentile(pct, _) if h.count() > 0 => Cell::Int(h.value_at_percentile(pct) as i64),
+                _ => Cell::Empty,
+            },
+        }
+    }
+}
+
+fn write_option(w: &mut SnapshotWriter, v: Option<u64>) -> Result<(), Error> {
+    match v {
+        Some(v) => { w.write_u64(1)?; w.write_u64(v) },
+        None => w.write_u64(0),
+    }
+}
+
+fn read_option(r: &mut SnapshotReader) -> Result<Option<u64>, Error> {
+    match r.read_u64()? {
+        0 => Ok(None),
+        _ => Ok(Some(r.read_u64()?)),
+    }
+}
+
+/// A `--group-by`/`--agg` query, e.g. grouping by `timeslice,request_host` and computing
+/// `count,p99(response_time_micros)` for each group
+#[derive(Debug,Clone)]
+pub struct Query {
+    group_by: Vec<GroupField>,
+    aggregates: Vec<Aggregate>,
+}
+
+impl Query {
+    /// Parse comma-separated lists of fields to group by, and of aggregates to compute
+    pub fn parse(group_by: &str, aggregates: &str) -> Result<Query, String> {
+        let group_by = group_by.split(',')
+            .map(|f| f.trim() )
+            .filter(|f| !f.is_empty() )
+            .map(GroupField::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        let aggregates = aggregates.split(',')
+            .map(|a| a.trim() )
+            .filter(|a| !a.is_empty() )
+            .map(Aggregate::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if aggregates.is_empty() {
+            return Err("at least one aggregate must be given".to_string());
+        }
+        Ok(Query {
+            group_by: group_by,
+            aggregates: aggregates,
+        })
+    }
+
+    pub fn group_by(&self) -> &[GroupField] {
+        &self.group_by
+    }
+}
+
+impl fmt::Display for Query {
+    /// In the form of the `--group-by` and `--agg` arguments
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let group_by = self.group_by.iter().map(|g| g.name() ).collect::<Vec<&str>>();
+        let aggregates = self.aggregates.iter().map(|a| a.to_string() ).collect::<Vec<String>>();
+        write!(f, "--group-by {} --agg {}", group_by.join(","), aggregates.join(","))
+    }
+}
+
+/// The groups found so far by a `Query`, and the aggregate values for each
+pub struct QueryResult {
+    groups: HashMap<Vec<Value>, Vec<Accumulator>>,
+}
+
+impl QueryResult {
+    pub fn new() -> QueryResult {
+        QueryResult {
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Add a record to its group, where `slice` and `uritype` are the values the consumer has
+    /// already worked out for it
+    pub fn record(&mut self, query: &Query, r: &Record, slice: i64, uritype: &str) {
+        let key = query.group_by.iter().map(|g| match *g {
+            GroupField::Timeslice => Value::Num(slice),
+            GroupField::Uritype => Value::Str(uritype.to_string()),
+            GroupField::Field(ref f) => f.value(r),
+        }).collect::<Vec<Value>>();
+        let accumulators = self.groups.entry(key)
+            .or_insert_with(|| query.aggregates.iter().map(|a| a.accumulator() ).collect() );
+        for (acc, agg) in accumulators.iter_mut().zip(query.aggregates.iter()) {
+            acc.record(agg.field().and_then(|f| f.number(r) ));
+        }
+    }
+
+    pub fn table(&self, query: &Query) -> Table {
+        let mut keys = self.groups.keys().collect::<Vec<&Vec<Value>>>();
+        keys.sort();
+        let rows = keys.into_iter().map(|key| {
+            let mut row = key.iter().zip(query.group_by.iter()).map(|(v, g)| match (*g, v) {
+                (GroupField::Timeslice, &Value::Num(ts)) => Cell::Time(ts),
+                (_, &Value::Num(n)) => Cell::Int(n),
+                (_, &Value::Str(ref s)) => Cell::Str(s.clone()),
+            }).collect::<Vec<Cell>>();
+            row.extend(self.groups[key].iter().zip(query.aggregates.iter()).map(|(acc, agg)| acc.cell(agg) ));
+            row
+        }).collect();
+        Table {
+            name: "query".to_string(),
+            keys: query.group_by.iter().map(|g| g.name().to_string() ).collect(),
+            columns: query.aggregates.iter().map(|a| a.to_string() ).collect(),
+            pivot: None,
+            rows: rows,
+        }
+    }
+}
+
+impl SliceResult for QueryResult {
+    type Config = Query;
+
+    fn absorb(&mut self, query: &Query, other: &QueryResult, slice_seconds: i64, keep: &Fn(i64) -> bool) {
+        for (key, accumulators) in other.groups.iter() {
+            let kept = key.iter().zip(query.group_by.iter()).all(|(v, g)| match (*g, v) {
+                (GroupField::Timeslice, &Value::Num(ts)) => keep(ts),
+                _ => true,
+            });
+            if !kept {
+                continue;
+            }
+            let key = key.iter().zip(query.group_by.iter()).map(|(v, g)| match (*g, v) {
+                (GroupField::Timeslice, &Value::Num(ts)) => Value::Num(rescale(ts, slice_seconds)),
+                _ => v.clone(),
+            }).collect::<Vec<Value>>();
+            let mine = self.groups.entry(key)
+                .or_insert_with(|| query.aggregates.iter().map(|a| a.accumulator() ).collect() );
+            for (acc, o) in mine.iter_mut().zip(accumulators.iter()) {
+                acc.merge(o);
+            }
+        }
+    }
+
+    fn write_snapshot(&self, w: &mut SnapshotWriter) -> Result<(), Error> {
+        w.write_u64(self.groups.len() as u64)?;
+        for (key, accumulators) in self.groups.iter() {
+            w.write_u64(key.len() as u64)?;
+            for v in key.iter() {
+                match *v {
+                    Value::Num(n) => { w.write_u64(0)?; w.write_i64(n)?; },
+                    Value::Str(ref s) => { w.write_u64(1)?; w.write_str(s)?; },
+                }
+            }
+            w.write_u64(accumulators.len() as u64)?;
+            for acc in accumulators.iter() {
+                acc.write_snapshot(w)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_snapshot(r: &mut SnapshotReader) -> Result<QueryResult, Error> {
+        let mut result = QueryResult::new();
+        for _ in 0..r.read_len()? {
+            let mut key = Vec::new();
+            for _ in 0..r.read_len()? {
+                key.push(match r.read_u64()? {
+                    0 => Value::Num(r.read_i64()?),
+                    1 => Value::Str(r.read_string()?),
+                    other => return Err(invalid_data(&format!("unknown value type {}", other))),
+                });
+            }
+            let mut accumulators = Vec::new();
+            for _ in 0..r.read_len()? {
+                accumulators.push(Accumulator::read_snapshot(r)?);
+            }
+            result.groups.insert(key, accumulators);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let q = Query::parse("timeslice,request_host", "count,sum(response_bytes),p99.9(response_time_micros)").unwrap();
+        assert_eq!(vec![GroupField::Timeslice, GroupField::Field(RecordField::RequestHost)], q.group_by);
+        assert_eq!(Aggregate::Percentile(99.9, RecordField::ResponseTimeMicros), q.aggregates[2]);
+        assert!(Query::parse("no_such_field", "count").is_err());
+        assert!(Query::parse("request_host", "sum(request_host").is_err());
+        assert!(Query::parse("request_host", "").is_err());
+    }
+
+    #[test]
+    fn group_and_merge() {
+        let q = Query::parse("timeslice,request_host", "count,sum(response_bytes),max(response_time_micros)").unwrap();
+        let mut a = QueryResult::new();
+        a.record(&q, &Record { request_host: "a.example.com".to_string(), response_status: "200".to_string(), response_time_micros: 10, response_bytes: Some(100), ..Record::default() }, 0, "x");
+        a.record(&q, &Record { request_host: "b.example.com".to_string(), response_status: "200".to_string(), response_time_micros: 20, response_bytes: Some(100), ..Record::default() }, 60, "x");
+        let mut b = QueryResult::new();
+        b.record(&q, &Record { request_host: "a.example.com".to_string(), response_status: "404".to_string(), response_time_micros: 30, response_bytes: Some(100), ..Record::default() }, 60, "x");
+        let mut total = QueryResult::new();
+        total.absorb(&q, &a, 120, &|_| true );
+        total.absorb(&q, &b, 120, &|_| true );
+        let table = total.table(&q);
+        assert_eq!(vec!["timeslice", "request_host"], table.keys);
+        assert_eq!(vec!["count", "sum(response_bytes)", "max(response_time_micros)"], table.columns);
+        assert_eq!(vec![
+                       vec![Cell::Time(0), Cell::Str("a.example.com".to_string()), Cell::Int(2), Cell::Int(200), Cell::Int(30)],
+                       vec![Cell::Time(0), Cell::Str("b.example.com".to_string()), Cell::Int(1), Cell::Int(100), Cell::Int(20)],
+                   ],
+                   table.rows);
+    }
+}
@@ -0,0 +1,200 @@
+use std::i64;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use chan;
+use futures::future;
+use futures::future::FutureResult;
+use hyper;
+use hyper::{Method, StatusCode};
+use hyper::header::{ContentLength, ContentType};
+use hyper::server::{Http, Request, Response, Service};
+use serde_json;
+use serde_json::Value;
+use follow::completed_until;
+use output::{OutputWriter, PrometheusWriter, Report, ReportOptions, TimeFormat, table_json};
+use process::Consumer;
+
+/// `PrometheusWriter` writes the OpenMetrics text format, with timestamps in seconds and a closing
+/// `# EOF`, which a scraper taking it for the older Prometheus format would misread
+const OPENMETRICS: &'static str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Answers HTTP requests with the current contents of a `Consumer` which is still being added
+/// to.  `/metrics` gives the most recent complete timeslice in the OpenMetrics text format, and
+/// `/<table>.json` gives any of the tables that would otherwise be written to files, including
+/// the timeslice still in progress.  `/` lists the tables.
+#[derive(Clone)]
+pub struct StatsService {
+    live: Arc<Mutex<Consumer>>,
+    options: ReportOptions,
+    rollup: Option<i64>,
+    times: TimeFormat,
+}
+
+impl StatsService {
+    pub fn new(live: Arc<Mutex<Consumer>>, options: ReportOptions, rollup: Option<i64>, times: TimeFormat) -> StatsService {
+        StatsService {
+            live: live,
+            options: options,
+            rollup: rollup,
+            times: times,
+        }
+    }
+
+    /// A copy of the live results, rolled up if required, so that the lock isn't held while
+    /// they're written out
+    fn current(&self, latest_only: bool) -> Result<Consumer, Error> {
+        let mut consumer = {
+            let live = self.live.lock().unwrap();
+            match self.rollup {
+                Some(seconds) => live.rollup(seconds)?,
+                None => live.between(i64::MIN, i64::MAX),
+            }
+        };
+        if latest_only {
+            let width = consumer.slice_seconds();
+            let until = completed_until(width);
+            consumer = consumer.between(until - width, until);
+        }
+        Ok(consumer)
+    }
+
+    fn respond(&self, path: &str) -> Result<Option<Response>, Error> {
+        if path == "/metrics" {
+            let consumer = self.current(true)?;
+            let mut prometheus = PrometheusWriter::to(Vec::new());
+            for report in consumer.reports(&self.options) {
+                prometheus.write(&report)?;
+            }
+            prometheus.finish()?;
+            let content_type = OPENMETRICS.parse().map_err(|_| Error::new(ErrorKind::Other, "bad content type") )?;
+            return Ok(Some(body_response(ContentType(content_type), prometheus.into_inner())));
+        }
+        let consumer = self.current(false)?;
+        let reports = consumer.reports(&self.options);
+        let value = if path == "/" {
+            Value::Array(reports.iter().map(|r| Value::from(format!("/{}.json", report_name(r))) ).collect())
+        } else {
+            let report = reports.iter().find(|r| path == format!("/{}.json", report_name(r)) );
+            match report {
+                Some(&Report::Table(ref t)) => table_json(t, &self.times),
+                Some(&Report::Histograms(ref h)) => table_json(&h.to_table(), &self.times),
+                None => return Ok(None),
+            }
+        };
+        let body = serde_json::to_vec(&value).map_err(|e| Error::new(ErrorKind::Other, e))?;
+        Ok(Some(body_response(ContentType::json(), body)))
+    }
+}
+
+fn report_name<'a>(report: &'a Report) -> &'a str {
+    match *report {
+        Report::Table(ref t) => &t.name,
+        Report::Histograms(ref h) => &h.name,
+    }
+}
+
+fn body_response(content_type: ContentType, body: Vec<u8>) -> Response {
+    Response::new()
+        .with_header(content_type)
+        .with_header(ContentLength(body.len() as u64))
+        .with_body(body)
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    body_response(ContentType::plaintext(), format!("{}\n", message).into_bytes())
+        .with_status(status)
+}
+
+impl Service for StatsService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = FutureResult<Response, hyper::Error>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let resp = if *req.method() != Method::Get {
+            error_response(StatusCode::MethodNotAllowed, "only GET is supported")
+        } else {
+            match self.respond(req.path()) {
+                Ok(Some(resp)) => resp,
+                Ok(None) => error_response(StatusCode::NotFound, "no such table; see / for the list"),
+                Err(e) => error_response(StatusCode::InternalServerError, &e.to_string()),
+            }
+        };
+        future::ok(resp)
+    }
+}
+
+/// Start serving on the given address from a new thread, returning once the listener is bound
+pub fn spawn(addr: SocketAddr, service: StatsService) -> Result<(), Error> {
+    let (bound_send, bound_recv) = chan::sync(1);
+    thread::spawn(move || {
+        // the server's event loop can't be moved between threads, so it's created on this one
+        let server = match Http::new().bind(&addr, move || Ok(service.clone()) ) {
+            Ok(server) => server,
+            Err(e) => {
+                bound_send.send(Err(e.to_string()));
+                return;
+            },
+        };
+        bound_send.send(Ok(()));
+        if let Err(e) = server.run() {
+            println!("HTTP server stopped: {}", e);
+        }
+    });
+    match bound_recv.recv() {
+        Some(Ok(())) => {
+            println!("serving results on http://{}/", addr);
+            Ok(())
+        },
+        Some(Err(e)) => Err(Error::new(ErrorKind::Other, format!("couldn't listen on {}: {}", addr, e))),
+        None => Err(Error::new(ErrorKind::Other, "HTTP server thread stopped")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, Stream};
+    use classify::Classifier;
+    use parse_access_log::Record;
+    use process::ConsumerConfig;
+    use time::Timespec;
+
+    #[test]
+    fn metrics() {
+        let mut live = Consumer::new(Arc::new(ConsumerConfig {
+            classifier: Classifier::default_rules(),
+            slice_seconds: 60,
+            query: None,
+            top: None,
+            unique: None,
+            breakdown: None,
+        }));
+        let slice = completed_until(60) - 60;
+        let mut r = Record::default();
+        r.timestamp = Timespec::new(slice, 0);
+        r.response_status = "200".to_string();
+        live.handle(r);
+        let options = ReportOptions {
+            zero_fill: false,
+            slice_end: false,
+            percentiles: vec![50.0],
+            buckets: None,
+            status_classes: false,
+            anomalies: None,
+        };
+        let service = StatsService::new(Arc::new(Mutex::new(live)), options, None, TimeFormat::epoch());
+        let resp = service.respond("/metrics").unwrap().unwrap();
+        assert_eq!(Some(&ContentType(OPENMETRICS.parse().unwrap())), resp.headers().get::<ContentType>());
+        let body = resp.body().fold(Vec::new(), |mut body, chunk| {
+            body.extend_from_slice(&chunk);
+            Ok::<Vec<u8>, hyper::Error>(body)
+        }).wait().unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains(&format!("whatf_by_status_timeslice{{http_status=\"200\"}} 1 {}\n", slice)));
+        assert!(body.ends_with("# EOF\n"));
+    }
+}
@@ -0,0 +1,187 @@
+use std::io::{Error, ErrorKind, Read, Write};
+use hdrsample::Histogram;
+use hdrsample::serialization::{V2Serializer, Deserializer};
+
+/// Identifies a file as a saved `Consumer`
+const MAGIC: &'static [u8] = b"WHATFSNP";
+/// Incremented whenever the layout of a snapshot changes incompatibly
+const VERSION: u64 = 6;
+
+/// Writes the primitive values a snapshot is made of.  Integers are variable-length encoded,
+/// since most are small counts, and histograms use the standard HdrHistogram V2 encoding.
+pub struct SnapshotWriter<'a> {
+    out: &'a mut Write,
+    serializer: V2Serializer,
+    buf: Vec<u8>,
+}
+
+impl<'a> SnapshotWriter<'a> {
+    pub fn new(out: &'a mut Write) -> Result<SnapshotWriter<'a>, Error> {
+        out.write_all(MAGIC)?;
+        let mut w = SnapshotWriter {
+            out: out,
+            serializer: V2Serializer::new(),
+            buf: Vec::new(),
+        };
+        w.write_u64(VERSION)?;
+        Ok(w)
+    }
+
+    pub fn write_u64(&mut self, mut v: u64) -> Result<(), Error> {
+        loop {
+            let byte = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                return self.out.write_all(&[byte]);
+            }
+            self.out.write_all(&[byte | 0x80])?;
+        }
+    }
+
+    pub fn write_i64(&mut self, v: i64) -> Result<(), Error> {
+        // zig-zag encoding keeps small negative numbers short
+        self.write_u64(((v << 1) ^ (v >> 63)) as u64)
+    }
+
+    pub fn write_f64(&mut self, v: f64) -> Result<(), Error> {
+        self.write_u64(v.to_bits())
+    }
+
+    pub fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        self.write_bytes(s.as_bytes())
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.write_u64(bytes.len() as u64)?;
+        self.out.write_all(bytes)
+    }
+
+    pub fn write_histogram(&mut self, h: &Histogram<u64>) -> Result<(), Error> {
+        self.buf.clear();
+        self.serializer.serialize(h, &mut self.buf)
+            .map_err(|e| Error::new(ErrorKind::Other, format!("couldn't serialise histogram: {:?}", e)))?;
+        let len = self.buf.len() as u64;
+        self.write_u64(len)?;
+        self.out.write_all(&self.buf)
+    }
+}
+
+/// Reads back the values written by a `SnapshotWriter`
+pub struct SnapshotReader<'a> {
+    input: &'a mut Read,
+    deserializer: Deserializer,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(input: &'a mut Read) -> Result<SnapshotReader<'a>, Error> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic[..] != MAGIC {
+            return Err(invalid_data("not a whatf snapshot"));
+        }
+        let mut r = SnapshotReader {
+            input: input,
+            deserializer: Deserializer::new(),
+        };
+        let version = r.read_u64()?;
+        if version != VERSION {
+            return Err(invalid_data(&format!("snapshot version {} is not supported (expected {})", version, VERSION)));
+        }
+        Ok(r)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        let mut v = 0u64;
+        let mut shift = 0;
+        loop {
+            let mut byte = [0];
+            self.input.read_exact(&mut byte)?;
+            if shift > 63 {
+                return Err(invalid_data("varint too long"));
+            }
+            v |= ((byte[0] & 0x7f) as u64) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(v);
+            }
+            shift += 7;
+        }
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64, Error> {
+        let v = self.read_u64()?;
+        Ok(((v >> 1) as i64) ^ -((v & 1) as i64))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, Error> {
+        self.read_u64().map(f64::from_bits)
+    }
+
+    /// Read a count of items to follow, which is assumed to be corrupt if it's implausibly large
+    pub fn read_len(&mut self) -> Result<usize, Error> {
+        let len = self.read_u64()?;
+        if len > 1 << 32 {
+            return Err(invalid_data("implausible length"));
+        }
+        Ok(len as usize)
+    }
+
+    pub fn read_string(&mut self) -> Result<String, Error> {
+        let buf = self.read_bytes()?;
+        String::from_utf8(buf).map_err(|_| invalid_data("invalid UTF-8 in string"))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.read_len()?;
+        let mut buf = vec![0; len];
+        self.input.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn read_histogram(&mut self) -> Result<Histogram<u64>, Error> {
+        let len = self.read_len()?;
+        let mut buf = vec![0; len];
+        self.input.read_exact(&mut buf)?;
+        self.deserializer.deserialize(&mut &buf[..])
+            .map_err(|e| invalid_data(&format!("couldn't deserialise histogram: {:?}", e)))
+    }
+}
+
+pub fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("bad snapshot: {}", msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut hist = Histogram::<u64>::new(2).unwrap();
+        hist.record(1234).unwrap();
+        hist.record(99).unwrap();
+        let mut buf = Vec::new();
+        {
+            let mut w = SnapshotWriter::new(&mut buf).unwrap();
+            w.write_u64(300).unwrap();
+            w.write_i64(-1200).unwrap();
+            w.write_f64(0.25).unwrap();
+            w.write_str("héllo").unwrap();
+            w.write_histogram(&hist).unwrap();
+        }
+        let mut input = &buf[..];
+        let mut r = SnapshotReader::new(&mut input).unwrap();
+        assert_eq!(300, r.read_u64().unwrap());
+        assert_eq!(-1200, r.read_i64().unwrap());
+        assert_eq!(0.25, r.read_f64().unwrap());
+        assert_eq!("héllo", r.read_string().unwrap());
+        let h = r.read_histogram().unwrap();
+        assert_eq!(2, h.count());
+        assert_eq!(hist.max(), h.max());
+    }
+
+    #[test]
+    fn not_a_snapshot() {
+        let mut input = &b"something else"[..];
+        assert!(SnapshotReader::new(&mut input).is_err());
+    }
+}
@@ -0,0 +1,381 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::io::Error;
+use parse_access_log::Record;
+use query::RecordField;
+use output::{Cell, Table};
+use snapshot::{SnapshotWriter, SnapshotReader, invalid_data};
+use process::{SliceResult, rescale};
+
+/// How many values are tracked for each value reported.  The counts of the reported values are
+/// overestimated by at most the total count divided by the number tracked.
+const CAPACITY_PER_VALUE: usize = 10;
+
+/// The fields whose most frequent values are reported, unless `--top-fields` says otherwise
+pub const DEFAULT_TOP_FIELDS: &'static [RecordField] = &[
+    RecordField::RequestUri,
+    RecordField::RemoteHost,
+    RecordField::RequestForwardedFor,
+    RecordField::RequestUseragent,
+    RecordField::RequestReferer,
+];
+
+/// Which fields to find the most frequent values of, and how many values to report
+#[derive(Debug,Clone,PartialEq)]
+pub struct TopConfig {
+    pub n: usize,
+    pub fields: Vec<RecordField>,
+}
+
+impl TopConfig {
+    /// Parse a `--top` count and an optional comma-separated list of `--top-fields`
+    pub fn parse(n: &str, fields: Option<&str>) -> Result<TopConfig, String> {
+        let n = match n.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => return Err(format!("the number of values to report must be a positive integer, not {:?}", n)),
+        };
+        let fields = match fields {
+            Some(list) => list.split(',').map(|name| {
+                let name = name.trim();
+                match RecordField::parse(name) {
+                    Some(f) if !f.is_numeric() => Ok(f),
+                    Some(_) => Err(format!("{} is numeric, so has no most frequent values to report", name)),
+                    None => Err(format!("unknown field {:?}", name)),
+                }
+            }).collect::<Result<Vec<RecordField>, String>>()?,
+            None => DEFAULT_TOP_FIELDS.to_vec(),
+        };
+        Ok(TopConfig {
+            n: n,
+            fields: fields,
+        })
+    }
+
+    fn capacity(&self) -> usize {
+        self.n * CAPACITY_PER_VALUE
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+struct Counter {
+    count: u64,
+    /// the most by which `count` may exceed the true count
+    error: u64,
+}
+
+/// Approximate counts of the most frequent values seen, kept in bounded memory using the
+/// Space-Saving algorithm: once `capacity` values are being tracked, a new value replaces the
+/// least frequent one, inheriting its count.  Any value occurring more often than the total
+/// count divided by the capacity is guaranteed to be tracked.
+#[derive(Debug,Clone)]
+pub struct TopN {
+    capacity: usize,
+    counters: HashMap<String, Counter>,
+}
+
+impl TopN {
+    pub fn new(capacity: usize) -> TopN {
+        TopN {
+            capacity: capacity,
+            counters: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, value: &str) {
+        if let Some(c) = self.counters.get_mut(value) {
+            c.count += 1;
+            return;
+        }
+        if self.counters.len() < self.capacity {
+            self.counters.insert(value.to_string(), Counter { count: 1, error: 0 });
+            return;
+        }
+        let (evicted, min) = {
+            let (k, c) = self.counters.iter().min_by_key(|&(_, c)| c.count ).unwrap();
+            (k.clone(), c.count)
+        };
+        self.counters.remove(&evicted);
+        self.counters.insert(value.to_string(), Counter { count: min + 1, error: min });
+    }
+
+    /// The smallest count a value could have had without being tracked
+    fn floor(&self) -> u64 {
+        if self.counters.len() < self.capacity {
+            0
+        } else {
+            self.counters.values().map(|c| c.count ).min().unwrap_or(0)
+        }
+    }
+
+    /// Combine with the counts from another summary.  A value missing from one side may still
+    /// have occurred there up to that side's `floor()` times, which is added to its count and
+    /// error, as described in "Mergeable Summaries" (Agarwal et al, 2012).
+    pub fn merge(&mut self, other: &TopN) {
+        let my_floor = self.floor();
+        let their_floor = other.floor();
+        for (value, c) in self.counters.iter_mut() {
+            match other.counters.get(value) {
+                Some(o) => {
+                    c.count += o.count;
+                    c.error += o.error;
+                },
+                None => {
+                    c.count += their_floor;
+                    c.error += their_floor;
+                },
+            }
+        }
+        for (value, o) in other.counters.iter() {
+            if !self.counters.contains_key(value) {
+                self.counters.insert(value.clone(), Counter { count: o.count + my_floor, error: o.error + my_floor });
+            }
+        }
+        self.capacity = cmp::max(self.capacity, other.capacity);
+        if self.counters.len() > self.capacity {
+            let keep = self.sorted().into_iter().take(self.capacity).map(|(v, _)| v.to_string() ).collect::<Vec<String>>();
+            let mut counters = HashMap::new();
+            for value in keep {
+                let c = self.counters[&value];
+                counters.insert(value, c);
+            }
+            self.counters = counters;
+        }
+    }
+
+    /// The tracked values, most frequent first
+    fn sorted(&self) -> Vec<(&str, Counter)> {
+        let mut values = self.counters.iter().map(|(v, c)| (&v[..], *c) ).collect::<Vec<(&str, Counter)>>();
+        values.sort_by(|a, b| b.1.count.cmp(&a.1.count).then(a.0.cmp(b.0)) );
+        values
+    }
+
+    /// The `n` most frequent values, with their counts and the most each count may be
+    /// overestimated by
+    pub fn top(&self, n: usize) -> Vec<(&str, u64, u64)> {
+        self.sorted().into_iter().take(n).map(|(v, c)| (v, c.count, c.error) ).collect()
+    }
+
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) -> Result<(), Error> {
+        w.write_u64(self.capacity as u64)?;
+        w.write_u64(self.counters.len() as u64)?;
+        for (value, c) in self.counters.iter() {
+            w.write_str(value)?;
+            w.write_u64(c.count)?;
+            w.write_u64(c.error)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_snapshot(r: &mut SnapshotReader) -> Result<TopN, Error> {
+        let mut top = TopN::new(r.read_len()?);
+        for _ in 0..r.read_len()? {
+            let value = r.read_string()?;
+            let c = Counter {
+                count: r.read_u64()?,
+                error: r.read_u64()?,
+            };
+            top.counters.insert(value, c);
+        }
+        Ok(top)
+    }
+}
+
+/// The most frequent values of one field, for each timeslice and overall
+struct TopField {
+    field: RecordField,
+    by_timeslice: HashMap<i64, TopN>,
+    total: TopN,
+}
+
+impl TopField {
+    fn new(field: RecordField, capacity: usize) -> TopField {
+        TopField {
+            field: field,
+            by_timeslice: HashMap::new(),
+            total: TopN::new(capacity),
+        }
+    }
+}
+
+/// The heavy hitters for each of the fields in a `TopConfig`
+pub struct TopResult {
+    fields: Vec<TopField>,
+}
+
+impl TopResult {
+    pub fn new() -> TopResult {
+        TopResult {
+            fields: Vec::new(),
+        }
+    }
+
+    fn field_mut(&mut self, field: RecordField, capacity: usize) -> &mut TopField {
+        let i = match self.fields.iter().position(|f| f.field == field ) {
+            Some(i) => i,
+            None => {
+                self.fields.push(TopField::new(field, capacity));
+                self.fields.len() - 1
+            },
+        };
+        &mut self.fields[i]
+    }
+
+    pub fn record(&mut self, config: &TopConfig, r: &Record, slice: i64) {
+        let capacity = config.capacity();
+        for field in config.fields.iter() {
+            let value = field.text(r).unwrap_or("");
+            // nothing to report for fields the log format doesn't provide
+            if value.is_empty() || value == "-" {
+                continue;
+            }
+            let top = self.field_mut(*field, capacity);
+            top.by_timeslice.entry(slice).or_insert_with(|| TopN::new(capacity) ).record(value);
+            top.total.record(value);
+        }
+    }
+
+    /// Whether there are results for every field in the config
+    pub fn covers(&self, config: &TopConfig) -> bool {
+        config.fields.iter().all(|field| self.fields.iter().any(|f| f.field == *field ) )
+    }
+
+    /// For each field, a table of the top values in each timeslice, followed by a table of the
+    /// top values overall
+    pub fn tables(&self, config: &TopConfig) -> Vec<Table> {
+        let mut tables = Vec::new();
+        for field in config.fields.iter() {
+            let f = match self.fields.iter().find(|f| f.field == *field ) {
+                Some(f) => f,
+                None => continue,
+            };
+            let mut timeslices = f.by_timeslice.keys().collect::<Vec<&i64>>();
+            timeslices.sort();
+            let mut rows = Vec::new();
+            for ts in timeslices {
+                for (value, count, error) in f.by_timeslice[ts].top(config.n) {
+                    rows.push(vec![Cell::Time(*ts), Cell::Str(value.to_string()), Cell::Int(count as i64), Cell::Int(error as i64)]);
+                }
+            }
+            tables.push(top_table(format!("top_{}_by_timeslice", field.name()), vec!["timeslice".to_string(), field.name().to_string()], rows));
+            let rows = f.total.top(config.n).into_iter()
+                .map(|(value, count, error)| vec![Cell::Str(value.to_string()), Cell::Int(count as i64), Cell::Int(error as i64)] )
+                .collect();
+            tables.push(top_table(format!("top_{}", field.name()), vec![field.name().to_string()], rows));
+        }
+        tables
+    }
+}
+
+impl SliceResult for TopResult {
+    type Config = TopConfig;
+
+    fn absorb(&mut self, config: &TopConfig, other: &TopResult, slice_seconds: i64, keep: &Fn(i64) -> bool) {
+        let capacity = config.capacity();
+        for theirs in other.fields.iter().filter(|f| config.fields.contains(&f.field) ) {
+            let mine = self.field_mut(theirs.field, capacity);
+            let mut all = true;
+            for (ts, top) in theirs.by_timeslice.iter() {
+                if !keep(*ts) {
+                    all = false;
+                    continue;
+                }
+                let slice = rescale(ts, slice_seconds);
+                mine.by_timeslice.entry(slice).or_insert_with(|| TopN::new(capacity) ).merge(top);
+            }
+            if all {
+                mine.total.merge(&theirs.total);
+            } else {
+                // the overall counts can't be taken apart, so are rebuilt from the timeslices
+                for (_, top) in theirs.by_timeslice.iter().filter(|&(ts, _)| keep(*ts) ) {
+                    mine.total.merge(top);
+                }
+            }
+        }
+    }
+
+    fn write_snapshot(&self, w: &mut SnapshotWriter) -> Result<(), Error> {
+        w.write_u64(self.fields.len() as u64)?;
+        for f in self.fields.iter() {
+            w.write_str(f.field.name())?;
+            f.total.write_snapshot(w)?;
+            w.write_u64(f.by_timeslice.len() as u64)?;
+            for (ts, top) in f.by_timeslice.iter() {
+                w.write_i64(*ts)?;
+                top.write_snapshot(w)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_snapshot(r: &mut SnapshotReader) -> Result<TopResult, Error> {
+        let mut result = TopResult::new();
+        for _ in 0..r.read_len()? {
+            let name = r.read_string()?;
+            let field = RecordField::parse(&name).ok_or_else(|| invalid_data(&format!("unknown field {:?}", name)) )?;
+            let mut f = TopField::new(field, 0);
+            f.total = TopN::read_snapshot(r)?;
+            for _ in 0..r.read_len()? {
+                let ts = r.read_i64()?;
+                f.by_timeslice.insert(ts, TopN::read_snapshot(r)?);
+            }
+            result.fields.push(f);
+        }
+        Ok(result)
+    }
+}
+
+fn top_table(name: String, keys: Vec<String>, rows: Vec<Vec<Cell>>) -> Table {
+    Table {
+        name: name,
+        keys: keys,
+        columns: vec!["count".to_string(), "max_overcount".to_string()],
+        pivot: None,
+        rows: rows,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(capacity: usize, values: &[&str]) -> TopN {
+        let mut top = TopN::new(capacity);
+        for v in values {
+            top.record(v);
+        }
+        top
+    }
+
+    #[test]
+    fn exact_within_capacity() {
+        let top = summary(10, &["/a", "/b", "/a", "/c", "/a", "/b"]);
+        assert_eq!(vec![("/a", 3, 0), ("/b", 2, 0)], top.top(2));
+    }
+
+    #[test]
+    fn heavy_hitter_survives_eviction() {
+        let mut values = Vec::new();
+        for i in 0..100 {
+            values.push("/hot");
+            values.push(if i % 2 == 0 { "/x" } else { "/y" });
+            values.push(["/1", "/2", "/3", "/4", "/5", "/6", "/7"][i % 7]);
+        }
+        let top = summary(4, &values);
+        let (value, count, error) = top.top(1)[0];
+        assert_eq!("/hot", value);
+        assert!(count >= 100 && count - error <= 100);
+    }
+
+    #[test]
+    fn merge() {
+        let mut a = summary(3, &["/a", "/a", "/a", "/b", "/b"]);
+        let b = summary(3, &["/a", "/c"]);
+        a.merge(&b);
+        assert_eq!(vec![("/a", 4, 0), ("/b", 2, 0), ("/c", 1, 0)], a.top(3));
+        // once full, each side may have missed up to its smallest count of any value
+        let mut c = summary(2, &["/a", "/a", "/a", "/b", "/b"]);
+        let d = summary(2, &["/a", "/c", "/c", "/c", "/c"]);
+        c.merge(&d);
+        assert_eq!(vec![("/c", 6, 2), ("/a", 4, 0)], c.top(3));
+    }
+}
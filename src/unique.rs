@@ -0,0 +1,322 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Error;
+use parse_access_log::Record;
+use query::RecordField;
+use cidr::Cidr;
+use snapshot::{SnapshotWriter, SnapshotReader, invalid_data};
+use process::{SliceResult, rescale};
+
+/// The number of bits of each hash used to choose a register.  2^14 registers give a standard
+/// error of about 0.8%, in 16KiB.
+const PRECISION: u32 = 14;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// An estimate of the number of distinct values seen, in fixed memory, using the HyperLogLog
+/// algorithm.  Each value's hash picks a register, which remembers the longest run of leading
+/// zeros seen in the rest of the hash; merging two estimates keeps the larger of each register,
+/// giving exactly the estimate of the combined values.
+#[derive(Clone)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; REGISTERS],
+        }
+    }
+
+    pub fn insert_hash(&mut self, hash: u64) {
+        let index = (hash >> (64 - PRECISION)) as usize;
+        let rank = (cmp::min((hash << PRECISION).leading_zeros(), 64 - PRECISION) + 1) as u8;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (mine, theirs) in self.registers.iter_mut().zip(other.registers.iter()) {
+            if *theirs > *mine {
+                *mine = *theirs;
+            }
+        }
+    }
+
+    pub fn estimate(&self) -> f64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self.registers.iter().map(|r| 2f64.powi(-(*r as i32)) ).sum::<f64>();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0 ).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            // linear counting is more accurate while many registers are still empty
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+
+    /// Registers are written sparsely while most are empty, as they are for a timeslice with
+    /// little traffic
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) -> Result<(), Error> {
+        let used = self.registers.iter().filter(|r| **r != 0 ).count();
+        if used * 4 < REGISTERS {
+            w.write_u64(0)?;
+            w.write_u64(used as u64)?;
+            for (i, r) in self.registers.iter().enumerate().filter(|&(_, r)| *r != 0 ) {
+                w.write_u64(i as u64)?;
+                w.write_u64(*r as u64)?;
+            }
+            Ok(())
+        } else {
+            w.write_u64(1)?;
+            w.write_bytes(&self.registers)
+        }
+    }
+
+    pub fn read_snapshot(r: &mut SnapshotReader) -> Result<HyperLogLog, Error> {
+        let mut hll = HyperLogLog::new();
+        match r.read_u64()? {
+            0 => {
+                for _ in 0..r.read_len()? {
+                    let i = r.read_u64()? as usize;
+                    let v = r.read_u64()?;
+                    if i >= REGISTERS || v > 64 {
+                        return Err(invalid_data("bad HyperLogLog register"));
+                    }
+                    hll.registers[i] = v as u8;
+                }
+            },
+            1 => {
+                let registers = r.read_bytes()?;
+                if registers.len() != REGISTERS {
+                    return Err(invalid_data("wrong number of HyperLogLog registers"));
+                }
+                hll.registers = registers;
+            },
+            other => return Err(invalid_data(&format!("unknown HyperLogLog encoding {}", other))),
+        }
+        Ok(hll)
+    }
+}
+
+/// 64-bit FNV-1a, followed by the MurmurHash3 finaliser to spread the bits, since HyperLogLog
+/// relies on every bit of the hash being well mixed.  Unlike `DefaultHasher`, the result is
+/// stable between builds, so estimates saved in snapshots can be merged with later ones.
+struct ClientHasher(u64);
+
+impl ClientHasher {
+    fn new() -> ClientHasher {
+        ClientHasher(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        let mut k = self.0;
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xff51afd7ed558ccd);
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xc4ceb9fe1a85ec53);
+        k ^= k >> 33;
+        k
+    }
+}
+
+/// Something that goes towards identifying a client
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ClientField {
+    /// the address of the client, from `request_forwarded_for` where the request came through
+    /// trusted proxies, otherwise `remote_host`
+    ClientIp,
+    Field(RecordField),
+}
+
+/// Which fields, taken together, identify a distinct client
+#[derive(Debug,Clone)]
+pub struct UniqueConfig {
+    pub fields: Vec<ClientField>,
+    /// proxies whose `X-Forwarded-For` entries are believed
+    pub trusted_proxies: Vec<Cidr>,
+}
+
+impl UniqueConfig {
+    /// Parse a comma-separated list of `--unique-clients` fields, and of `--trusted-proxies`
+    /// networks
+    pub fn parse(fields: &str, trusted_proxies: Option<&str>) -> Result<UniqueConfig, String> {
+        let fields = fields.split(',').map(|name| {
+            match name.trim() {
+                "client_ip" => Ok(ClientField::ClientIp),
+                name => RecordField::parse(name).map(ClientField::Field).ok_or_else(|| format!("unknown field {:?}", name) ),
+            }
+        }).collect::<Result<Vec<ClientField>, String>>()?;
+        let trusted_proxies = match trusted_proxies {
+            Some(list) => list.split(',').map(|net| Cidr::parse(net.trim()) ).collect::<Result<Vec<Cidr>, String>>()?,
+            None => Vec::new(),
+        };
+        Ok(UniqueConfig {
+            fields: fields,
+            trusted_proxies: trusted_proxies,
+        })
+    }
+
+    fn client_hash(&self, r: &Record) -> u64 {
+        let mut hasher = ClientHasher::new();
+        for field in self.fields.iter() {
+            match *field {
+                ClientField::ClientIp => hasher.write(client_ip(r, &self.trusted_proxies).as_bytes()),
+                ClientField::Field(f) => hasher.write(f.value(r).to_string().as_bytes()),
+            }
+            // keeps e.g. ("ab", "c") apart from ("a", "bc")
+            hasher.write(&[0xff]);
+        }
+        hasher.finish()
+    }
+}
+
+impl fmt::Display for UniqueConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fields = self.fields.iter().map(|c| match *c {
+            ClientField::ClientIp => "client_ip",
+            ClientField::Field(field) => field.name(),
+        }).collect::<Vec<&str>>();
+        write!(f, "--unique-clients {}", fields.join(","))?;
+        if !self.trusted_proxies.is_empty() {
+            let nets = self.trusted_proxies.iter().map(|n| n.to_string() ).collect::<Vec<String>>();
+            write!(f, " --trusted-proxies {}", nets.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// The address of the client behind any trusted proxies.  The connecting address is followed
+/// back through the `X-Forwarded-For` list, from right to left, for as long as the addresses
+/// belong to trusted proxies, since anything further left may have been made up by the client.
+pub fn client_ip<'a>(r: &'a Record, trusted_proxies: &[Cidr]) -> &'a str {
+    let forwarded = r.request_forwarded_for.split(',')
+        .map(|a| a.trim() )
+        .filter(|a| !a.is_empty() && *a != "-" );
+    let mut client = &r.remote_host[..];
+    let mut chain = forwarded.collect::<Vec<&str>>().into_iter().rev();
+    while trusted_proxies.iter().any(|net| net.contains_str(client) ) {
+        match chain.next() {
+            Some(addr) => client = addr,
+            None => break,
+        }
+    }
+    client
+}
+
+/// Estimates of the number of distinct clients in each timeslice
+pub struct UniqueResult {
+    by_timeslice: HashMap<i64, HyperLogLog>,
+}
+
+impl UniqueResult {
+    pub fn new() -> UniqueResult {
+        UniqueResult {
+            by_timeslice: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, config: &UniqueConfig, r: &Record, slice: i64) {
+        let hash = config.client_hash(r);
+        self.by_timeslice.entry(slice).or_insert_with(HyperLogLog::new).insert_hash(hash);
+    }
+
+    /// The estimated number of distinct clients in the given timeslice
+    pub fn estimate(&self, slice: i64) -> u64 {
+        self.by_timeslice.get(&slice).map(|hll| hll.estimate().round() as u64 ).unwrap_or(0)
+    }
+
+    /// The estimated number of distinct clients across all timeslices.  Since merging estimates
+    /// loses nothing, this is as accurate as if it had been counted separately.
+    pub fn total_estimate(&self) -> u64 {
+        let mut total = HyperLogLog::new();
+        for hll in self.by_timeslice.values() {
+            total.merge(hll);
+        }
+        total.estimate().round() as u64
+    }
+}
+
+impl SliceResult for UniqueResult {
+    type Config = ();
+
+    fn absorb(&mut self, _: &(), other: &UniqueResult, slice_seconds: i64, keep: &Fn(i64) -> bool) {
+        for (ts, hll) in other.by_timeslice.iter().filter(|&(ts, _)| keep(*ts) ) {
+            let slice = rescale(ts, slice_seconds);
+            self.by_timeslice.entry(slice).or_insert_with(HyperLogLog::new).merge(hll);
+        }
+    }
+
+    fn write_snapshot(&self, w: &mut SnapshotWriter) -> Result<(), Error> {
+        w.write_u64(self.by_timeslice.len() as u64)?;
+        for (ts, hll) in self.by_timeslice.iter() {
+            w.write_i64(*ts)?;
+            hll.write_snapshot(w)?;
+        }
+        Ok(())
+    }
+
+    fn read_snapshot(r: &mut SnapshotReader) -> Result<UniqueResult, Error> {
+        let mut result = UniqueResult::new();
+        for _ in 0..r.read_len()? {
+            let ts = r.read_i64()?;
+            result.by_timeslice.insert(ts, HyperLogLog::read_snapshot(r)?);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate() {
+        let config = UniqueConfig::parse("remote_host", None).unwrap();
+        let mut a = UniqueResult::new();
+        let mut b = UniqueResult::new();
+        for i in 0..50000 {
+            a.record(&config, &Record { remote_host: format!("10.0.{}.{}", i / 256, i % 256), ..Record::default() }, 0);
+        }
+        for i in 25000..75000 {
+            b.record(&config, &Record { remote_host: format!("10.0.{}.{}", i / 256, i % 256), ..Record::default() }, 60);
+        }
+        let estimate = a.estimate(0) as f64;
+        assert!((estimate - 50000.0).abs() < 50000.0 * 0.05, "estimate {}", estimate);
+        a.absorb(&(), &b, 120, &|_| true );
+        let total = a.total_estimate() as f64;
+        assert!((total - 75000.0).abs() < 75000.0 * 0.05, "total {}", total);
+    }
+
+    #[test]
+    fn small_counts_are_close() {
+        let config = UniqueConfig::parse("remote_host", None).unwrap();
+        let mut result = UniqueResult::new();
+        for host in &["a", "b", "c", "a", "b"] {
+            result.record(&config, &Record { remote_host: host.to_string(), ..Record::default() }, 0);
+        }
+        assert_eq!(3, result.estimate(0));
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let trusted = vec![Cidr::parse("10.0.0.0/8").unwrap()];
+        // untrusted connections can't vouch for anything
+        assert_eq!("203.0.113.9", client_ip(&Record { remote_host: "203.0.113.9".to_string(), request_forwarded_for: "198.51.100.1".to_string(), ..Record::default() }, &trusted));
+        assert_eq!("198.51.100.1", client_ip(&Record { remote_host: "10.1.1.1".to_string(), request_forwarded_for: "192.0.2.7, 198.51.100.1, 10.2.2.2".to_string(), ..Record::default() }, &trusted));
+        assert_eq!("10.3.3.3", client_ip(&Record { remote_host: "10.1.1.1".to_string(), request_forwarded_for: "10.3.3.3".to_string(), ..Record::default() }, &trusted));
+        assert_eq!("10.1.1.1", client_ip(&Record { remote_host: "10.1.1.1".to_string(), request_forwarded_for: "-".to_string(), ..Record::default() }, &trusted));
+        assert_eq!("10.1.1.1", client_ip(&Record { remote_host: "10.1.1.1".to_string(), request_forwarded_for: "198.51.100.1".to_string(), ..Record::default() }, &[]));
+    }
+}